@@ -0,0 +1,182 @@
+//! Quadtree and R-tree on the stops of `GTFS_BENCH_STOPS` (default
+//! `gtfs/stops.txt`), or on random stops over Wallonia when it is missing.
+//!
+//! cargo bench --bench spatial
+
+use std::{env, fs};
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use tec_gtfs::{
+    quadtree::{Coordinate, Extent, QuadTree},
+    rtree::{RTree, DEFAULT_NODE_SIZE},
+    spatial::SpatialIndex,
+};
+
+/// Stops generated when no feed is around, about the size of the TEC one
+const SYNTHETIC_STOPS: usize = 30_000;
+
+/// Queries per iteration
+const QUERIES: usize = 100;
+
+fn load_stops() -> Vec<(u32, Coordinate)> {
+    let path = env::var("GTFS_BENCH_STOPS").unwrap_or_else(|_| "gtfs/stops.txt".to_string());
+    match fs::read_to_string(&path) {
+        Ok(content) => {
+            let stops = parse_stops(&content);
+            println!("Loaded {} stops from {}", stops.len(), path);
+            stops
+        }
+        Err(_) => {
+            println!(
+                "No stops at {}, using {} random ones",
+                path, SYNTHETIC_STOPS
+            );
+            let mut random = Random(42);
+            (0..SYNTHETIC_STOPS as u32)
+                .map(|i| {
+                    (
+                        i,
+                        Coordinate::new(2.8 + random.next() * 3.6, 49.5 + random.next() * 1.3),
+                    )
+                })
+                .collect()
+        }
+    }
+}
+
+/// `stop_lat` and `stop_lon` of a stops.txt, quoted fields included
+fn parse_stops(content: &str) -> Vec<(u32, Coordinate)> {
+    let mut lines = content.lines();
+    let header = split(
+        lines
+            .next()
+            .unwrap_or_default()
+            .trim_start_matches('\u{feff}'),
+    );
+    let lat = header.iter().position(|h| h == "stop_lat");
+    let lon = header.iter().position(|h| h == "stop_lon");
+    let (lat, lon) = match (lat, lon) {
+        (Some(lat), Some(lon)) => (lat, lon),
+        _ => return Vec::new(),
+    };
+
+    lines
+        .filter_map(|line| {
+            let fields = split(line);
+            let lat = fields.get(lat)?.trim().parse().ok()?;
+            let lon = fields.get(lon)?.trim().parse().ok()?;
+            Some(Coordinate::new(lon, lat)).filter(|c| c.is_valid())
+        })
+        .enumerate()
+        .map(|(i, coord)| (i as u32, coord))
+        .collect()
+}
+
+fn split(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    for c in line.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            _ => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields
+}
+
+/// xorshift, enough to spread query points
+struct Random(u64);
+
+impl Random {
+    fn next(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % 1_000_000) as f64 / 1_000_000.0
+    }
+}
+
+fn build_quadtree(stops: &[(u32, Coordinate)]) -> QuadTree<u32> {
+    let extent = Extent::bounding(stops.iter().map(|(_, c)| c))
+        .unwrap_or_else(|| Extent::new(0.0, 0.0, 0.0, 0.0));
+    let mut tree = QuadTree::new(extent);
+    for (stop, coord) in stops {
+        tree.insert(coord, *stop);
+    }
+    tree
+}
+
+fn build_rtree(stops: &[(u32, Coordinate)]) -> RTree<u32> {
+    RTree::bulk_load(stops.to_vec(), DEFAULT_NODE_SIZE)
+}
+
+/// Query points close to stops, as most API calls are
+fn query_points(stops: &[(u32, Coordinate)]) -> Vec<Coordinate> {
+    let mut random = Random(7);
+    (0..QUERIES)
+        .map(|_| {
+            let (_, stop) = &stops[(random.next() * stops.len() as f64) as usize];
+            Coordinate::new(
+                stop.get_x() + (random.next() - 0.5) * 0.02,
+                stop.get_y() + (random.next() - 0.5) * 0.02,
+            )
+        })
+        .collect()
+}
+
+fn query<I: SpatialIndex<u32>>(c: &mut Criterion, name: &str, tree: &I, points: &[Coordinate]) {
+    let mut group = c.benchmark_group(name);
+
+    group.bench_function(BenchmarkId::new("bbox", "1km"), |b| {
+        b.iter(|| {
+            for point in points {
+                let (x, y) = (point.get_x(), point.get_y());
+                let extent = Extent::new(x - 0.007, y - 0.0045, x + 0.007, y + 0.0045);
+                black_box(tree.find_bbox(&extent));
+            }
+        })
+    });
+
+    for k in [1, 10] {
+        group.bench_function(BenchmarkId::new("nearest", k), |b| {
+            b.iter(|| {
+                for point in points {
+                    black_box(tree.find_nearest(point, k, f64::INFINITY));
+                }
+            })
+        });
+    }
+
+    for radius in [500.0, 2000.0] {
+        group.bench_function(BenchmarkId::new("radius", radius), |b| {
+            b.iter(|| {
+                for point in points {
+                    black_box(tree.find_radius(point, radius));
+                }
+            })
+        });
+    }
+
+    group.finish();
+}
+
+fn benches(c: &mut Criterion) {
+    let stops = load_stops();
+    if stops.is_empty() {
+        return;
+    }
+    let points = query_points(&stops);
+
+    let mut group = c.benchmark_group("build");
+    group.sample_size(20);
+    group.bench_function("quadtree", |b| b.iter(|| build_quadtree(black_box(&stops))));
+    group.bench_function("rtree", |b| b.iter(|| build_rtree(black_box(&stops))));
+    group.finish();
+
+    query(c, "quadtree", &build_quadtree(&stops), &points);
+    query(c, "rtree", &build_rtree(&stops), &points);
+}
+
+criterion_group!(spatial, benches);
+criterion_main!(spatial);
@@ -0,0 +1,324 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use super::{authorize, feed_prefix, AlertQuery};
+use crate::store::{
+    route_agency, Alert, AlertFilter, Feed, FeedSnapshot, InformedEntity, Store, TripScope,
+};
+use axum::{
+    extract::{ConnectInfo, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::json;
+
+#[derive(Deserialize)]
+pub struct AlertKey {
+    key: Option<String>,
+    id: Option<String>,
+}
+
+/// Alerts active at `at` (now by default) about a stop, a route or a trip,
+/// or every active alert
+pub async fn alerts(State(app): State<Arc<Store>>, query: Query<AlertQuery>) -> impl IntoResponse {
+    let snapshot = app.get_feed();
+    feed_prefix(&snapshot, query.feed.as_deref())?;
+    let feed = query.feed.as_deref();
+
+    let filter = match (&query.stop_id, &query.route_id, &query.trip_id) {
+        (None, None, None) => AlertFilter::All,
+        (Some(stop_id), None, None) => {
+            match snapshot
+                .resolve(stop_id, feed)
+                .into_iter()
+                .find(|(f, id)| f.get_gtfs().stops.contains_key(*id))
+            {
+                Some((f, id)) => AlertFilter::Stop(f.namespaced(id)),
+                None => {
+                    return Err((
+                        StatusCode::BAD_REQUEST,
+                        Json(json!({"error": "Stop not found"})),
+                    ))
+                }
+            }
+        }
+        (None, Some(route_id), None) => {
+            match snapshot
+                .resolve(route_id, feed)
+                .into_iter()
+                .find_map(|(f, id)| f.get_gtfs().routes.get(id).map(|route| (f, route)))
+            {
+                Some((f, route)) => AlertFilter::Route {
+                    route_id: f.namespaced(&route.id),
+                    agency_id: route_agency(f, route),
+                },
+                None => {
+                    return Err((
+                        StatusCode::BAD_REQUEST,
+                        Json(json!({"error": "Invalid route_id"})),
+                    ))
+                }
+            }
+        }
+        (None, None, Some(trip_id)) => match snapshot.find_trip(trip_id, feed) {
+            Some((f, trip)) => AlertFilter::Trip(TripScope::new(f, trip)),
+            None => {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({"error": "Invalid trip_id"})),
+                ))
+            }
+        },
+        _ => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Only one of stop_id, route_id or trip_id"})),
+            ))
+        }
+    };
+
+    let at = query.at.unwrap_or_else(|| Utc::now().timestamp() as u64);
+    Ok(Json(app.get_alerts(&filter, at)).into_response())
+}
+
+/// Add an alert from localhost, its entities being looked up like the ids
+/// of any other endpoint
+pub async fn add(
+    State(app): State<Arc<Store>>,
+    ConnectInfo(connect_info): ConnectInfo<SocketAddr>,
+    query: Query<AlertKey>,
+    Json(mut alert): Json<Alert>,
+) -> impl IntoResponse {
+    authorize(&app, "ALERTS", &connect_info, query.key.as_deref())?;
+
+    if alert.header.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "Missing header"})),
+        ));
+    }
+    if alert.informed_entities.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "Missing informed_entities"})),
+        ));
+    }
+
+    let snapshot = app.get_feed();
+    let mut entities = Vec::with_capacity(alert.informed_entities.len());
+    for entity in alert.informed_entities.iter() {
+        match resolve_entity(&snapshot, entity) {
+            Ok(entity) => entities.push(entity),
+            Err(error) => return Err((StatusCode::BAD_REQUEST, Json(json!({"error": error})))),
+        }
+    }
+    alert.informed_entities = entities;
+
+    let id = app.add_alert(alert);
+    Ok(Json(json!({"ok": "added", "id": id})).into_response())
+}
+
+/// Drop an alert added through `add`
+pub async fn remove(
+    State(app): State<Arc<Store>>,
+    ConnectInfo(connect_info): ConnectInfo<SocketAddr>,
+    query: Query<AlertKey>,
+) -> impl IntoResponse {
+    authorize(&app, "ALERTS", &connect_info, query.key.as_deref())?;
+
+    let id = match &query.id {
+        Some(id) => id,
+        None => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Missing id"})),
+            ))
+        }
+    };
+
+    if !app.remove_alert(id) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "Unknown alert"})),
+        ));
+    }
+    Ok(Json(json!({"ok": "removed"})).into_response())
+}
+
+/// Entity with its ids namespaced and the route of its trip filled in, all
+/// of them taken from the same feed
+fn resolve_entity(
+    snapshot: &FeedSnapshot,
+    entity: &InformedEntity,
+) -> Result<InformedEntity, String> {
+    if entity.agency_id.is_none()
+        && entity.route_id.is_none()
+        && entity.trip_id.is_none()
+        && entity.stop_id.is_none()
+    {
+        return Err("Informed entity without agency_id, route_id, trip_id or stop_id".to_string());
+    }
+
+    snapshot
+        .get_feeds()
+        .iter()
+        .find_map(|feed| resolve_in(snapshot, feed, entity))
+        .ok_or_else(|| "Unknown agency_id, route_id, trip_id or stop_id".to_string())
+}
+
+/// `None` when one of the ids is not in `feed`
+fn resolve_in(
+    snapshot: &FeedSnapshot,
+    feed: &Feed,
+    entity: &InformedEntity,
+) -> Option<InformedEntity> {
+    let gtfs = feed.get_gtfs();
+    // Outer `None` when the id is given but not found
+    let local = |id: &Option<String>, exists: &dyn Fn(&str) -> bool| match id {
+        Some(id) => snapshot
+            .resolve(id, Some(feed.get_name()))
+            .into_iter()
+            .map(|(_, local)| local)
+            .find(|local| exists(local))
+            .map(|local| Some(local.to_string())),
+        None => Some(None),
+    };
+
+    let agency_id = local(&entity.agency_id, &|id| {
+        gtfs.agencies
+            .iter()
+            .any(|agency| agency.id.as_deref() == Some(id))
+    })?;
+    let route_id = local(&entity.route_id, &|id| gtfs.routes.contains_key(id))?;
+    let trip_id = local(&entity.trip_id, &|id| gtfs.trips.contains_key(id))?;
+    let stop_id = local(&entity.stop_id, &|id| gtfs.stops.contains_key(id))?;
+    let route_id = route_id.or_else(|| {
+        trip_id
+            .as_ref()
+            .and_then(|id| gtfs.trips.get(id))
+            .map(|trip| trip.route_id.clone())
+    });
+
+    Some(InformedEntity {
+        agency_id: agency_id.map(|id| feed.namespaced(&id)),
+        route_id: route_id.map(|id| feed.namespaced(&id)),
+        trip_id: trip_id.map(|id| feed.namespaced(&id)),
+        stop_id: stop_id.map(|id| feed.namespaced(&id)),
+        direction_id: entity.direction_id,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use gtfs_structures::{Agency, Gtfs, Route, Stop, Trip};
+
+    use super::*;
+    use crate::store::{FeedConfig, FeedSource, ValidationReport};
+
+    /// Feeds `tec` and `stib` both with stop `S1`, `tec` also with agency
+    /// `TEC` and trip `T1` of route `R1`
+    fn snapshot() -> FeedSnapshot {
+        let feeds = ["tec", "stib"]
+            .into_iter()
+            .map(|name| {
+                let mut gtfs = Gtfs::default();
+                gtfs.stops.insert(
+                    "S1".to_string(),
+                    Arc::new(Stop {
+                        id: "S1".to_string(),
+                        ..Default::default()
+                    }),
+                );
+                if name == "tec" {
+                    gtfs.agencies.push(Agency {
+                        id: Some("TEC".to_string()),
+                        ..Default::default()
+                    });
+                    gtfs.routes.insert(
+                        "R1".to_string(),
+                        Route {
+                            id: "R1".to_string(),
+                            ..Default::default()
+                        },
+                    );
+                    gtfs.trips.insert(
+                        "T1".to_string(),
+                        Trip {
+                            id: "T1".to_string(),
+                            route_id: "R1".to_string(),
+                            ..Default::default()
+                        },
+                    );
+                }
+                let config = FeedConfig {
+                    name: name.to_string(),
+                    source: FeedSource::Directory("gtfs".into()),
+                };
+                Arc::new(Feed::from_parts(
+                    &config,
+                    String::new(),
+                    gtfs,
+                    ValidationReport::default(),
+                ))
+            })
+            .collect();
+        FeedSnapshot::build(1, feeds)
+    }
+
+    fn entity(
+        agency_id: Option<&str>,
+        trip_id: Option<&str>,
+        stop_id: Option<&str>,
+    ) -> InformedEntity {
+        InformedEntity {
+            agency_id: agency_id.map(str::to_string),
+            trip_id: trip_id.map(str::to_string),
+            stop_id: stop_id.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn entities_are_resolved_in_one_feed() {
+        let snapshot = snapshot();
+        let resolve = |entity: InformedEntity| resolve_entity(&snapshot, &entity);
+
+        // A bare id is looked up in the first feed having it
+        assert_eq!(
+            resolve(entity(None, None, Some("S1"))),
+            Ok(entity(None, None, Some("tec:S1")))
+        );
+        assert_eq!(
+            resolve(entity(None, None, Some("stib:S1"))),
+            Ok(entity(None, None, Some("stib:S1")))
+        );
+        // The route of a trip is filled in
+        assert_eq!(
+            resolve(entity(Some("TEC"), Some("T1"), Some("S1"))),
+            Ok(InformedEntity {
+                agency_id: Some("tec:TEC".to_string()),
+                route_id: Some("tec:R1".to_string()),
+                trip_id: Some("tec:T1".to_string()),
+                stop_id: Some("tec:S1".to_string()),
+                direction_id: None,
+            })
+        );
+    }
+
+    #[test]
+    fn entities_across_feeds_or_unknown_are_rejected() {
+        let snapshot = snapshot();
+        let resolve = |entity: InformedEntity| resolve_entity(&snapshot, &entity);
+
+        assert!(resolve(entity(None, Some("T1"), Some("stib:S1"))).is_err());
+        assert!(resolve(entity(None, Some("T2"), None)).is_err());
+        assert!(resolve(entity(Some("STIB"), None, None)).is_err());
+        assert!(resolve(InformedEntity {
+            direction_id: Some(0),
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
@@ -0,0 +1,78 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use super::{check_key, gtfs::Key, ApiError, BusBboxQuery, BusQuery};
+use crate::{
+    quadtree::Extent,
+    store::{Bus, Store},
+};
+use axum::{
+    extract::{ConnectInfo, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+
+/// Every bus currently tracked, of `line_id` only when given
+pub async fn buses(State(app): State<Arc<Store>>, query: Query<BusQuery>) -> impl IntoResponse {
+    Json(app.get_buses(query.line_id.as_deref()))
+}
+
+/// Buses currently inside the box
+pub async fn bbox(State(app): State<Arc<Store>>, query: Query<BusBboxQuery>) -> impl IntoResponse {
+    let north = match &query.north {
+        Some(north) => north,
+        None => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Missing north"})),
+            ))
+        }
+    };
+
+    let south = match &query.south {
+        Some(south) => south,
+        None => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Missing south"})),
+            ))
+        }
+    };
+
+    let east = match &query.east {
+        Some(east) => east,
+        None => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Missing east"})),
+            ))
+        }
+    };
+
+    let west = match &query.west {
+        Some(west) => west,
+        None => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Missing west"})),
+            ))
+        }
+    };
+
+    let extent = Extent::new(*west as f64, *south as f64, *east as f64, *north as f64);
+    Ok(Json(app.get_buses_bbox(&extent, query.line_id.as_deref())).into_response())
+}
+
+/// Batch of bus positions pushed by the fetcher, guarded by the secret
+pub async fn ingest(
+    State(app): State<Arc<Store>>,
+    ConnectInfo(connect_info): ConnectInfo<SocketAddr>,
+    query: Query<Key>,
+    Json(buses): Json<Vec<Bus>>,
+) -> Result<Response, ApiError> {
+    check_key(&app, "VEHICLES", &connect_info, query.key.as_deref())?;
+
+    let summary = app.ingest_buses(buses);
+    Ok(Json(json!({"ok": "ingested", "summary": summary})).into_response())
+}
@@ -1,10 +1,11 @@
 use std::{net::SocketAddr, sync::Arc};
 
-use crate::{logger, store::Store};
+use super::{authorize, feed_prefix, ApiError};
+use crate::store::{RefreshError, Store};
 use axum::{
     extract::{ConnectInfo, Query, State},
     http::StatusCode,
-    response::IntoResponse,
+    response::{IntoResponse, Response},
     Json,
 };
 use serde::Deserialize;
@@ -13,6 +14,7 @@ use serde_json::json;
 #[derive(Deserialize)]
 pub struct Key {
     pub key: Option<String>,
+    pub feed: Option<String>,
 }
 
 pub async fn refresh(
@@ -20,35 +22,70 @@ pub async fn refresh(
     ConnectInfo(connect_info): ConnectInfo<SocketAddr>,
     query: Query<Key>,
 ) -> impl IntoResponse {
-    if connect_info.ip().to_string() != "127.0.0.1" {
-        logger::critical(
-            "REFRESH GTFS",
-            &format!("Forbidden access from {}", connect_info.ip().to_string()),
-        );
-        return Err((StatusCode::FORBIDDEN, Json(json!({"error": "Forbidden"}))));
-    }
-
-    let key = match &query.key {
-        Some(key) => key,
-        None => {
-            return Err((
-                StatusCode::BAD_REQUEST,
-                Json(json!({"error": "Missing key"})),
-            ))
-        }
-    };
+    let key = query.key.as_deref();
+    authorize(&app, "REFRESH GTFS", &connect_info, key)?;
 
-    match app.refresh_gtfs(key).await {
-        Ok(_) => Ok((
+    match app
+        .refresh_gtfs(key.unwrap_or_default(), query.feed.as_deref())
+        .await
+    {
+        Ok(version) => Ok((
             StatusCode::OK,
-            Json(json!({"ok": "refreshed"})),
+            Json(json!({"ok": "refreshed", "version": version})),
         )),
-        Err(e) => {
-            logger::critical(
-                "REFRESH GTFS",
-                &format!("Forbidden access from {}", connect_info.ip().to_string()),
-            );
-            Err((StatusCode::INTERNAL_SERVER_ERROR, Json(json!({"error": e}))))
+        Err(RefreshError::Unauthorized) => {
+            Err((StatusCode::FORBIDDEN, Json(json!({"error": "Forbidden"}))))
         }
+        Err(RefreshError::UnknownFeed(_)) => Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "Unknown feed"})),
+        )),
+        Err(RefreshError::Load(e)) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({
+                "error": "Error loading gtfs",
+                "details": e,
+                "version": app.get_feed().get_version(),
+            })),
+        )),
     }
 }
+
+/// Validation report of every loaded feed, or of `feed` only
+pub async fn validation(
+    State(app): State<Arc<Store>>,
+    ConnectInfo(connect_info): ConnectInfo<SocketAddr>,
+    query: Query<Key>,
+) -> Result<Response, ApiError> {
+    authorize(&app, "VALIDATOR", &connect_info, query.key.as_deref())?;
+
+    let snapshot = app.get_feed();
+    feed_prefix(&snapshot, query.feed.as_deref())?;
+
+    let feeds: Vec<_> = snapshot
+        .get_feeds()
+        .iter()
+        .filter(|f| match &query.feed {
+            Some(name) => f.get_name() == name,
+            None => true,
+        })
+        .map(|f| {
+            let report = f.get_validation();
+            json!({
+                "feed": f.get_name(),
+                "checksum": f.get_checksum(),
+                "error_count": report.error_count(),
+                "warning_count": report.warning_count(),
+                "counts": report.counts,
+                "errors": report.errors,
+                "warnings": report.warnings,
+            })
+        })
+        .collect();
+
+    Ok(Json(json!({
+        "version": snapshot.get_version(),
+        "feeds": feeds,
+    }))
+    .into_response())
+}
@@ -0,0 +1,106 @@
+use super::{feed_prefix, ApiError, HistoryQuery};
+use crate::store::{EntityDiff, FeedDiff, Store};
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+fn counts(diff: &EntityDiff) -> Value {
+    json!({
+        "added": diff.added.len(),
+        "removed": diff.removed.len(),
+        "modified": diff.modified.len(),
+    })
+}
+
+fn summary(diff: &FeedDiff) -> Value {
+    let mut stops = counts(&diff.stops.changes);
+    stops["moved"] = json!(diff.stops.moved.len());
+
+    json!({
+        "feed": diff.feed,
+        "previous_checksum": diff.previous_checksum,
+        "checksum": diff.checksum,
+        "routes": counts(&diff.routes),
+        "stops": stops,
+        "trips": counts(&diff.trips),
+        "calendar": counts(&diff.calendar),
+    })
+}
+
+/// Last published versions with the number of changes in each
+pub async fn history(
+    State(app): State<Arc<Store>>,
+    query: Query<HistoryQuery>,
+) -> Result<Response, ApiError> {
+    feed_prefix(&app.get_feed(), query.feed.as_deref())?;
+    let in_filter = |name: &str| match &query.feed {
+        Some(filter) => filter == name,
+        None => true,
+    };
+
+    let versions: Vec<Value> = app
+        .get_history()
+        .iter()
+        .rev()
+        .map(|entry| {
+            let changes: Option<Vec<Value>> = entry.diff.as_ref().map(|diff| {
+                diff.feeds
+                    .iter()
+                    .filter(|f| in_filter(&f.feed))
+                    .map(summary)
+                    .collect()
+            });
+            let feeds: Vec<_> = entry.feeds.iter().filter(|f| in_filter(&f.name)).collect();
+
+            json!({
+                "version": entry.version,
+                "loaded_at": entry.loaded_at,
+                "previous_version": entry.diff.as_ref().map(|d| d.previous_version),
+                "feeds": feeds,
+                "changes": changes,
+            })
+        })
+        .collect();
+
+    Ok(Json(versions).into_response())
+}
+
+/// Every id added, removed or modified by a version, the current one by default
+pub async fn diff(State(app): State<Arc<Store>>, query: Query<HistoryQuery>) -> impl IntoResponse {
+    feed_prefix(&app.get_feed(), query.feed.as_deref())?;
+
+    let entry = match app.get_history_entry(query.version) {
+        Some(entry) => entry,
+        None => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Unknown version"})),
+            ))
+        }
+    };
+
+    let feeds: Vec<&FeedDiff> = match &entry.diff {
+        Some(diff) => diff
+            .feeds
+            .iter()
+            .filter(|f| match &query.feed {
+                Some(filter) => filter == &f.feed,
+                None => true,
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+
+    Ok(Json(json!({
+        "version": entry.version,
+        "loaded_at": entry.loaded_at,
+        "previous_version": entry.diff.as_ref().map(|d| d.previous_version),
+        "feeds": feeds,
+    }))
+    .into_response())
+}
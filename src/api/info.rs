@@ -1,17 +1,17 @@
-use super::TripQuery;
-use crate::store::Store;
+use super::{feed_prefix, TripQuery};
+use crate::store::{AlertFilter, Store, TripScope};
 use axum::{
     extract::{Query, State},
     http::StatusCode,
     response::IntoResponse,
     Json,
 };
+use chrono::Utc;
 use serde_json::json;
 use std::sync::Arc;
 
 pub async fn info(State(app): State<Arc<Store>>, query: Query<TripQuery>) -> impl IntoResponse {
-    let gtfs = app.get_gtfs();
-    let app = gtfs.read().await;
+    let snapshot = app.get_feed();
     let trip_id = match &query.trip_id {
         Some(trip_id) => trip_id,
         None => {
@@ -22,8 +22,10 @@ pub async fn info(State(app): State<Arc<Store>>, query: Query<TripQuery>) -> imp
         }
     };
 
-    let trip = match app.get_trip(trip_id) {
-        Ok(trip) => trip,
+    feed_prefix(&snapshot, query.feed.as_deref())?;
+
+    let (feed, trip) = match snapshot.find_trip(trip_id, query.feed.as_deref()) {
+        Some(found) => found,
         _ => {
             return Err((
                 StatusCode::BAD_REQUEST,
@@ -32,7 +34,7 @@ pub async fn info(State(app): State<Arc<Store>>, query: Query<TripQuery>) -> imp
         }
     };
 
-    let route = match app.get_route(&trip.route_id) {
+    let route = match feed.get_gtfs().get_route(&trip.route_id) {
         Ok(route) => route,
         _ => {
             return Err((
@@ -42,9 +44,16 @@ pub async fn info(State(app): State<Arc<Store>>, query: Query<TripQuery>) -> imp
         }
     };
 
+    let alerts = app.get_alerts(
+        &AlertFilter::Trip(TripScope::new(feed, trip)),
+        Utc::now().timestamp() as u64,
+    );
+
     let json = json!({
+        "feed": feed.get_name(),
         "route_long_name": route.long_name,
-        "route_direction": trip.direction_id
+        "route_direction": trip.direction_id,
+        "alerts": alerts
     });
 
     Ok(Json(json).into_response())
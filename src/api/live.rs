@@ -0,0 +1,557 @@
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    logger,
+    quadtree::Extent,
+    store::{Bus, BusDelta, FeedSnapshot, Store},
+};
+use axum::{
+    extract::{
+        ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        ConnectInfo, State,
+    },
+    response::IntoResponse,
+};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::{
+    sync::broadcast::error::RecvError,
+    time::{interval_at, timeout},
+};
+
+/// Delay between two pings, and without any message from the client after
+/// which it is dropped
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(20);
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A client taking longer to accept a message is dropped
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+const MAX_SUBSCRIPTIONS: usize = 32;
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    Subscribe(Filter),
+    /// Without any field, every subscription is dropped
+    Unsubscribe(Filter),
+}
+
+#[derive(Deserialize)]
+struct Filter {
+    north: Option<f64>,
+    south: Option<f64>,
+    east: Option<f64>,
+    west: Option<f64>,
+    line_id: Option<String>,
+    stop_id: Option<String>,
+    feed: Option<String>,
+}
+
+#[derive(PartialEq)]
+enum Subscription {
+    Bbox(Extent),
+    Line(String),
+    /// Namespaced stop id, and line ids of the routes serving the stop
+    Stop(String, HashSet<String>),
+}
+
+/// Subscriptions of one connection and the buses it was last told about
+#[derive(Default)]
+struct Subscriber {
+    subscriptions: Vec<Subscription>,
+    visible: HashSet<String>,
+}
+
+/// Live bus positions: a snapshot of the matching buses whenever the
+/// subscriptions change, then the buses moving in and out of them
+pub async fn live(
+    ws: WebSocketUpgrade,
+    State(app): State<Arc<Store>>,
+    ConnectInfo(connect_info): ConnectInfo<SocketAddr>,
+) -> impl IntoResponse {
+    ws.on_upgrade(move |socket| stream(socket, app, connect_info))
+}
+
+async fn stream(mut socket: WebSocket, app: Arc<Store>, peer: SocketAddr) {
+    // Subscribed before the first snapshot so no change is missed
+    let mut updates = app.subscribe_buses();
+    let mut subscriber = Subscriber::default();
+    let mut heartbeat = interval_at(
+        tokio::time::Instant::now() + HEARTBEAT_INTERVAL,
+        HEARTBEAT_INTERVAL,
+    );
+    let mut last_seen = Instant::now();
+
+    loop {
+        let reply = tokio::select! {
+            _ = heartbeat.tick() => {
+                if last_seen.elapsed() > HEARTBEAT_TIMEOUT {
+                    logger::warn("WEBSOCKET", &format!("No heartbeat from {}, dropping it", peer));
+                    break;
+                }
+                Message::Ping(Vec::new())
+            }
+            message = socket.recv() => {
+                last_seen = Instant::now();
+                match message {
+                    Some(Ok(Message::Text(text))) => subscriber.handle(&app, &text),
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                }
+            }
+            batch = updates.recv() => match batch {
+                Ok(batch) => match subscriber.delta(&batch) {
+                    Some(reply) => reply,
+                    None => continue,
+                },
+                Err(RecvError::Lagged(skipped)) => {
+                    logger::warn(
+                        "WEBSOCKET",
+                        &format!("{} is {} batches behind, dropping it", peer, skipped),
+                    );
+                    let close = Message::Close(Some(CloseFrame {
+                        code: close_code::AGAIN,
+                        reason: "Too slow".into(),
+                    }));
+                    let _ = timeout(SEND_TIMEOUT, socket.send(close)).await;
+                    break;
+                }
+                Err(RecvError::Closed) => break,
+            }
+        };
+
+        match timeout(SEND_TIMEOUT, socket.send(reply)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(_)) => break,
+            Err(_) => {
+                logger::warn(
+                    "WEBSOCKET",
+                    &format!("{} stopped reading, dropping it", peer),
+                );
+                break;
+            }
+        }
+    }
+}
+
+fn error(message: &str) -> Message {
+    Message::Text(json!({"type": "error", "error": message}).to_string())
+}
+
+impl Subscriber {
+    /// Apply a client message, answering with the buses now matching or
+    /// with an error
+    fn handle(&mut self, app: &Store, text: &str) -> Message {
+        match self.apply(&app.get_feed(), text) {
+            Ok(()) => self.snapshot(app.get_buses(None)),
+            Err(e) => error(&e),
+        }
+    }
+
+    /// Change the subscriptions as the client message asks
+    fn apply(&mut self, snapshot: &FeedSnapshot, text: &str) -> Result<(), String> {
+        let message: ClientMessage =
+            serde_json::from_str(text).map_err(|e| format!("Invalid message: {}", e))?;
+
+        match message {
+            ClientMessage::Subscribe(filter) => {
+                let subscription = match filter.parse(snapshot)? {
+                    Some(subscription) => subscription,
+                    None => return Err("Missing bbox, line_id or stop_id".to_string()),
+                };
+                if self.subscriptions.len() >= MAX_SUBSCRIPTIONS {
+                    return Err("Too many subscriptions".to_string());
+                }
+                if !self.subscriptions.contains(&subscription) {
+                    self.subscriptions.push(subscription);
+                }
+            }
+            ClientMessage::Unsubscribe(filter) => match filter.parse(snapshot)? {
+                Some(Subscription::Stop(stop_id, _)) => self
+                    .subscriptions
+                    .retain(|s| !matches!(s, Subscription::Stop(id, _) if *id == stop_id)),
+                Some(subscription) => self.subscriptions.retain(|s| *s != subscription),
+                None => self.subscriptions.clear(),
+            },
+        }
+        Ok(())
+    }
+
+    /// Buses matching the subscriptions among `buses`, from which deltas
+    /// are then tracked
+    fn snapshot(&mut self, buses: Vec<Bus>) -> Message {
+        let buses: Vec<Bus> = buses.into_iter().filter(|bus| self.matches(bus)).collect();
+        self.visible = buses.iter().map(|bus| bus.get_id().to_string()).collect();
+        Message::Text(json!({"type": "snapshot", "buses": buses}).to_string())
+    }
+
+    fn matches(&self, bus: &Bus) -> bool {
+        self.subscriptions
+            .iter()
+            .any(|subscription| match subscription {
+                Subscription::Bbox(extent) => extent.contains(&bus.get_coordinate()),
+                Subscription::Line(line_id) => bus.get_line_id() == line_id,
+                Subscription::Stop(_, lines) => lines.contains(bus.get_line_id()),
+            })
+    }
+
+    /// Buses of the batch entering or moving within the subscriptions, and
+    /// ids of those leaving them, `None` when nothing concerns the client
+    fn delta(&mut self, batch: &[BusDelta]) -> Option<Message> {
+        let mut moved = Vec::new();
+        let mut removed = Vec::new();
+        for delta in batch {
+            match delta {
+                BusDelta::Moved(bus) if self.matches(bus) => {
+                    self.visible.insert(bus.get_id().to_string());
+                    moved.push(bus);
+                }
+                BusDelta::Moved(bus) | BusDelta::Removed(bus) => {
+                    if self.visible.remove(bus.get_id()) {
+                        removed.push(bus.get_id());
+                    }
+                }
+            }
+        }
+
+        if moved.is_empty() && removed.is_empty() {
+            None
+        } else {
+            Some(Message::Text(
+                json!({"type": "delta", "moved": moved, "removed": removed}).to_string(),
+            ))
+        }
+    }
+}
+
+impl Filter {
+    fn parse(self, snapshot: &FeedSnapshot) -> Result<Option<Subscription>, &'static str> {
+        let bbox = match (self.north, self.south, self.east, self.west) {
+            (Some(north), Some(south), Some(east), Some(west)) => {
+                Some(Extent::new(west, south, east, north))
+            }
+            (None, None, None, None) => None,
+            _ => return Err("Incomplete bbox"),
+        };
+
+        if let Some(name) = &self.feed {
+            if snapshot.get_feed(name).is_none() {
+                return Err("Unknown feed");
+            }
+        }
+
+        match (bbox, self.line_id, self.stop_id) {
+            (Some(extent), None, None) => Ok(Some(Subscription::Bbox(extent))),
+            (None, Some(line_id), None) => {
+                // Buses are stored with namespaced line ids
+                let line_id = snapshot
+                    .find_route_id(&line_id, self.feed.as_deref())
+                    .unwrap_or(line_id);
+                Ok(Some(Subscription::Line(line_id)))
+            }
+            (None, None, Some(stop_id)) => {
+                match lines_at_stop(snapshot, &stop_id, self.feed.as_deref()) {
+                    Some((stop_id, lines)) => Ok(Some(Subscription::Stop(stop_id, lines))),
+                    None => Err("Stop not found"),
+                }
+            }
+            (None, None, None) => Ok(None),
+            _ => Err("Only one of bbox, line_id or stop_id per subscription"),
+        }
+    }
+}
+
+/// Namespaced id of a stop and of the routes serving it
+fn lines_at_stop(
+    snapshot: &FeedSnapshot,
+    stop_id: &str,
+    feed: Option<&str>,
+) -> Option<(String, HashSet<String>)> {
+    let (stop_id, routes) = snapshot
+        .resolve(stop_id, feed)
+        .into_iter()
+        .filter_map(|(feed, id)| snapshot.get_symbol(&feed.namespaced(id)))
+        .find_map(|stop_id| {
+            snapshot
+                .get_reverse_stops()
+                .get(&stop_id)
+                .map(|routes| (stop_id, routes))
+        })?;
+
+    Some((
+        snapshot.resolve_symbol(stop_id).to_string(),
+        routes
+            .iter()
+            .map(|route| snapshot.resolve_symbol(*route).to_string())
+            .collect(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use gtfs_structures::{Gtfs, Route, Stop, StopTime, Trip};
+    use serde_json::Value;
+
+    use super::*;
+    use crate::store::{Feed, FeedConfig, FeedSource, ValidationReport};
+
+    /// Routes `R1` and `R2`, only `R1` serving stop `S1`
+    fn snapshot() -> FeedSnapshot {
+        let mut gtfs = Gtfs::default();
+        let stop = Arc::new(Stop {
+            id: "S1".to_string(),
+            latitude: Some(50.4),
+            longitude: Some(4.4),
+            ..Default::default()
+        });
+        gtfs.stops.insert("S1".to_string(), stop.clone());
+        for route_id in ["R1", "R2"] {
+            gtfs.routes.insert(
+                route_id.to_string(),
+                Route {
+                    id: route_id.to_string(),
+                    ..Default::default()
+                },
+            );
+        }
+        gtfs.trips.insert(
+            "T1".to_string(),
+            Trip {
+                id: "T1".to_string(),
+                route_id: "R1".to_string(),
+                stop_times: vec![StopTime {
+                    stop,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            },
+        );
+
+        let config = FeedConfig {
+            name: "tec".to_string(),
+            source: FeedSource::Directory("gtfs".into()),
+        };
+        let feed = Feed::from_parts(&config, String::new(), gtfs, ValidationReport::default());
+        FeedSnapshot::build(1, vec![Arc::new(feed)])
+    }
+
+    fn bus(id: &str, line_id: &str, longitude: f32) -> Bus {
+        Bus::new(
+            id.to_string(),
+            "1".to_string(),
+            line_id.to_string(),
+            50.5,
+            longitude,
+            0.0,
+            0,
+        )
+    }
+
+    fn json(message: Message) -> Value {
+        match message {
+            Message::Text(text) => serde_json::from_str(&text).unwrap(),
+            _ => panic!("Not a text message"),
+        }
+    }
+
+    fn ids(buses: &Value) -> Vec<&str> {
+        buses
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|bus| {
+                bus.get("id")
+                    .and_then(Value::as_str)
+                    .unwrap_or_else(|| bus.as_str().unwrap())
+            })
+            .collect()
+    }
+
+    /// Moved and removed bus ids of a delta, `None` when nothing is sent
+    fn delta(
+        subscriber: &mut Subscriber,
+        batch: Vec<BusDelta>,
+    ) -> Option<(Vec<String>, Vec<String>)> {
+        let message = json(subscriber.delta(&batch)?);
+        assert_eq!(message["type"], "delta");
+        let owned = |key: &str| ids(&message[key]).into_iter().map(str::to_string).collect();
+        Some((owned("moved"), owned("removed")))
+    }
+
+    const BBOX: &str = r#"{"type": "subscribe", "north": 51, "south": 50, "east": 5, "west": 4}"#;
+
+    #[test]
+    fn buses_enter_move_and_leave_a_bbox() {
+        let mut subscriber = Subscriber::default();
+        subscriber.apply(&snapshot(), BBOX).unwrap();
+        let message = json(subscriber.snapshot(vec![
+            bus("inside", "tec:R1", 4.5),
+            bus("outside", "tec:R1", 6.0),
+        ]));
+        assert_eq!(message["type"], "snapshot");
+        assert_eq!(ids(&message["buses"]), vec!["inside"]);
+
+        let moved = |id: &str, longitude: f32| BusDelta::Moved(bus(id, "tec:R1", longitude));
+        // Moving within, entering, and moving outside without being seen
+        assert_eq!(
+            delta(
+                &mut subscriber,
+                vec![
+                    moved("inside", 4.6),
+                    moved("outside", 4.9),
+                    moved("far", 7.0)
+                ]
+            ),
+            Some((vec!["inside".into(), "outside".into()], vec![]))
+        );
+        assert_eq!(delta(&mut subscriber, vec![moved("far", 8.0)]), None);
+
+        // Leaving, then expiring after having left
+        assert_eq!(
+            delta(&mut subscriber, vec![moved("inside", 6.0)]),
+            Some((vec![], vec!["inside".into()]))
+        );
+        assert_eq!(
+            delta(
+                &mut subscriber,
+                vec![BusDelta::Removed(bus("inside", "tec:R1", 6.0))]
+            ),
+            None
+        );
+        assert_eq!(
+            delta(
+                &mut subscriber,
+                vec![BusDelta::Removed(bus("outside", "tec:R1", 4.9))]
+            ),
+            Some((vec![], vec!["outside".into()]))
+        );
+    }
+
+    #[test]
+    fn lines_and_stops_match_namespaced_line_ids() {
+        let snapshot = snapshot();
+        let buses = || vec![bus("b1", "tec:R1", 4.5), bus("b2", "tec:R2", 4.5)];
+
+        let mut by_line = Subscriber::default();
+        by_line
+            .apply(&snapshot, r#"{"type": "subscribe", "line_id": "R2"}"#)
+            .unwrap();
+        assert_eq!(ids(&json(by_line.snapshot(buses()))["buses"]), vec!["b2"]);
+
+        let mut by_stop = Subscriber::default();
+        by_stop
+            .apply(&snapshot, r#"{"type": "subscribe", "stop_id": "tec:S1"}"#)
+            .unwrap();
+        assert_eq!(ids(&json(by_stop.snapshot(buses()))["buses"]), vec!["b1"]);
+        assert_eq!(
+            delta(
+                &mut by_stop,
+                vec![BusDelta::Moved(bus("b3", "tec:R2", 4.5))]
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn invalid_subscriptions_are_rejected() {
+        let snapshot = snapshot();
+        let mut subscriber = Subscriber::default();
+        let mut apply = |text: &str| subscriber.apply(&snapshot, text).unwrap_err();
+
+        assert_eq!(
+            apply(r#"{"type": "subscribe", "north": 51, "line_id": "R1"}"#),
+            "Incomplete bbox"
+        );
+        assert_eq!(
+            apply(r#"{"type": "subscribe", "line_id": "R1", "stop_id": "S1"}"#),
+            "Only one of bbox, line_id or stop_id per subscription"
+        );
+        assert_eq!(
+            apply(
+                r#"{"type": "subscribe", "north": 51, "south": 50, "east": 5, "west": 4, "line_id": "R1"}"#
+            ),
+            "Only one of bbox, line_id or stop_id per subscription"
+        );
+        assert_eq!(
+            apply(r#"{"type": "subscribe"}"#),
+            "Missing bbox, line_id or stop_id"
+        );
+        assert_eq!(
+            apply(r#"{"type": "subscribe", "stop_id": "S2"}"#),
+            "Stop not found"
+        );
+        assert_eq!(
+            apply(r#"{"type": "subscribe", "line_id": "R1", "feed": "stib"}"#),
+            "Unknown feed"
+        );
+        assert!(apply(r#"{"type": "watch"}"#).starts_with("Invalid message"));
+        assert!(subscriber.subscriptions.is_empty());
+    }
+
+    #[test]
+    fn unsubscribe_one_or_all() {
+        let snapshot = snapshot();
+        let mut subscriber = Subscriber::default();
+        for text in [
+            BBOX,
+            BBOX,
+            r#"{"type": "subscribe", "line_id": "R1"}"#,
+            r#"{"type": "subscribe", "stop_id": "S1"}"#,
+        ] {
+            subscriber.apply(&snapshot, text).unwrap();
+        }
+        // The same subscription is only kept once
+        assert_eq!(subscriber.subscriptions.len(), 3);
+
+        subscriber
+            .apply(&snapshot, r#"{"type": "unsubscribe", "stop_id": "tec:S1"}"#)
+            .unwrap();
+        subscriber
+            .apply(&snapshot, r#"{"type": "unsubscribe", "line_id": "tec:R1"}"#)
+            .unwrap();
+        assert!(
+            subscriber.subscriptions == vec![Subscription::Bbox(Extent::new(4.0, 50.0, 5.0, 51.0))]
+        );
+
+        subscriber.snapshot(vec![bus("b1", "tec:R1", 4.5)]);
+        subscriber
+            .apply(&snapshot, r#"{"type": "unsubscribe"}"#)
+            .unwrap();
+        assert!(subscriber.subscriptions.is_empty());
+        let message = json(subscriber.snapshot(vec![bus("b1", "tec:R1", 4.5)]));
+        assert!(ids(&message["buses"]).is_empty());
+        assert_eq!(
+            delta(
+                &mut subscriber,
+                vec![BusDelta::Moved(bus("b1", "tec:R1", 4.6))]
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn subscriptions_are_capped() {
+        let snapshot = snapshot();
+        let mut subscriber = Subscriber::default();
+        let subscribe = |west: usize| {
+            format!(
+                r#"{{"type": "subscribe", "north": 51, "south": 50, "east": 5, "west": {}}}"#,
+                west
+            )
+        };
+        for west in 0..MAX_SUBSCRIPTIONS {
+            subscriber.apply(&snapshot, &subscribe(west)).unwrap();
+        }
+        assert_eq!(
+            subscriber.apply(&snapshot, &subscribe(MAX_SUBSCRIPTIONS)),
+            Err("Too many subscriptions".to_string())
+        );
+        assert_eq!(subscriber.subscriptions.len(), MAX_SUBSCRIPTIONS);
+    }
+}
@@ -1,13 +1,27 @@
-use crate::{logger, store::Store};
-use axum::{http::Method, routing::get, Router};
+use crate::{
+    logger,
+    store::{FeedSnapshot, Store},
+};
+use axum::{
+    http::{Method, StatusCode},
+    routing::{get, post},
+    Json, Router,
+};
+use serde_json::{json, Value};
 use std::{sync::Arc, net::SocketAddr};
 use tower_http::cors::{Any, CorsLayer};
 
+mod alerts;
+mod buses;
 mod info;
+mod live;
+mod realtime;
+mod routes;
 mod shape;
 mod stops;
 mod theorical;
 mod gtfs;
+mod history;
 
 pub async fn init(store: Arc<Store>) {
     let cors = CorsLayer::new()
@@ -18,11 +32,25 @@ pub async fn init(store: Arc<Store>) {
     let app = Router::new()
         .route("/", get(|| async { "Hello, World!" }))
         .route("/theorical", get(theorical::theorical_schedule))
+        .route("/realtime", get(realtime::realtime_schedule))
         .route("/shape", get(shape::shape))
+        .route("/shape/closest", get(shape::closest))
+        .route("/routes/near", get(routes::near))
         .route("/info", get(info::info))
         .route("/stops", get(stops::stops))
+        .route("/stops/nearest", get(stops::nearest))
+        .route("/stops/around", get(stops::around))
+        .route("/stops/within", post(stops::within))
+        .route("/stops/corridor", post(stops::corridor))
         .route("/bus_from_stop", get(stops::bus_per_stop))
+        .route("/buses", get(buses::buses).post(buses::ingest))
+        .route("/alerts", get(alerts::alerts).post(alerts::add).delete(alerts::remove))
+        .route("/buses/bbox", get(buses::bbox))
+        .route("/buses/live", get(live::live))
         .route("/refresh_gtfs", get(gtfs::refresh))
+        .route("/validation", get(gtfs::validation))
+        .route("/history", get(history::history))
+        .route("/history/diff", get(history::diff))
         .layer(cors)
         .with_state(store);
 
@@ -35,20 +63,160 @@ pub async fn init(store: Arc<Store>) {
     axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
 }
 
+/// Status and JSON body of a failed request
+pub type ApiError = (StatusCode, Json<Value>);
+
+/// Let an admin request through from localhost with the secret
+pub fn authorize(
+    app: &Store,
+    service: &str,
+    connect_info: &SocketAddr,
+    key: Option<&str>,
+) -> Result<(), ApiError> {
+    if connect_info.ip().to_string() != "127.0.0.1" {
+        return Err(forbidden(service, connect_info));
+    }
+    check_key(app, service, connect_info, key)
+}
+
+/// Secret check of [`authorize`] alone, for requests accepted from any host
+pub fn check_key(
+    app: &Store,
+    service: &str,
+    connect_info: &SocketAddr,
+    key: Option<&str>,
+) -> Result<(), ApiError> {
+    match key {
+        Some(key) if app.is_authorized(key) => Ok(()),
+        Some(_) => Err(forbidden(service, connect_info)),
+        None => Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "Missing key"})),
+        )),
+    }
+}
+
+fn forbidden(service: &str, connect_info: &SocketAddr) -> ApiError {
+    logger::critical(
+        service,
+        &format!("Forbidden access from {}", connect_info.ip()),
+    );
+    (StatusCode::FORBIDDEN, Json(json!({"error": "Forbidden"})))
+}
+
+/// Namespace prefix of the ids of the `feed` a request is filtered on, empty
+/// when there is none
+pub fn feed_prefix(snapshot: &FeedSnapshot, feed: Option<&str>) -> Result<String, ApiError> {
+    match feed {
+        Some(name) => match snapshot.get_feed(name) {
+            Some(feed) => Ok(feed.namespaced("")),
+            None => Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Unknown feed"})),
+            )),
+        },
+        None => Ok(String::new()),
+    }
+}
+
 #[derive(serde::Deserialize)]
 pub struct BboxQuery {
     north: Option<f32>,
     east: Option<f32>,
     west: Option<f32>,
     south: Option<f32>,
+    limit: Option<usize>,
+    offset: Option<usize>,
+    zoom: Option<u8>,
+    feed: Option<String>,
+    alerts: Option<bool>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct BusQuery {
+    line_id: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct BusBboxQuery {
+    north: Option<f32>,
+    east: Option<f32>,
+    west: Option<f32>,
+    south: Option<f32>,
+    line_id: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct NearestQuery {
+    lat: Option<f64>,
+    lon: Option<f64>,
+    k: Option<usize>,
+    max_distance: Option<f64>,
+    feed: Option<String>,
+    alerts: Option<bool>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct AroundQuery {
+    lat: Option<f64>,
+    lon: Option<f64>,
+    radius: Option<f64>,
+    feed: Option<String>,
+    alerts: Option<bool>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct CorridorQuery {
+    buffer: Option<f64>,
+    feed: Option<String>,
+    alerts: Option<bool>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct WithinQuery {
+    feed: Option<String>,
+    alerts: Option<bool>,
 }
 
 #[derive(serde::Deserialize)]
 pub struct TripQuery {
     trip_id: Option<String>,
+    feed: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct RealtimeQuery {
+    trip_id: Option<String>,
+    feed: Option<String>,
+    start_date: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct ShapePointQuery {
+    trip_id: Option<String>,
+    lat: Option<f64>,
+    lon: Option<f64>,
+    feed: Option<String>,
 }
 
 #[derive(serde::Deserialize)]
 pub struct StopQuery {
     stop_id: Option<String>,
+    feed: Option<String>,
+    alerts: Option<bool>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct AlertQuery {
+    stop_id: Option<String>,
+    route_id: Option<String>,
+    trip_id: Option<String>,
+    feed: Option<String>,
+    at: Option<u64>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct HistoryQuery {
+    version: Option<u64>,
+    feed: Option<String>,
 }
@@ -0,0 +1,63 @@
+use super::{feed_prefix, RealtimeQuery};
+use crate::store::{service_date, RealtimeTrip, Store};
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use chrono::NaiveDate;
+use serde_json::json;
+use std::sync::Arc;
+
+/// Static schedule of a trip with the delays and cancellations of its
+/// latest GTFS-RT update on `start_date`, today by default
+pub async fn realtime_schedule(
+    State(app): State<Arc<Store>>,
+    query: Query<RealtimeQuery>,
+) -> impl IntoResponse {
+    let snapshot = app.get_feed();
+
+    let trip_id = match &query.trip_id {
+        Some(trip_id) => trip_id,
+        None => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Missing trip_id"})),
+            ))
+        }
+    };
+
+    feed_prefix(&snapshot, query.feed.as_deref())?;
+
+    let (feed, trip) = match snapshot.find_trip(trip_id, query.feed.as_deref()) {
+        Some(found) => found,
+        _ => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Invalid trip_id"})),
+            ))
+        }
+    };
+
+    let start_date = match &query.start_date {
+        Some(date) => match NaiveDate::parse_from_str(date, "%Y%m%d") {
+            Ok(date) => Some(date),
+            Err(_) => {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({"error": "Invalid start_date"})),
+                ))
+            }
+        },
+        None => service_date(feed, trip),
+    };
+
+    // Updates without a start date are about the current service day
+    let trip_id = feed.namespaced(&trip.id);
+    let mut update = app.get_trip_update(&trip_id, start_date);
+    if update.is_none() && start_date == service_date(feed, trip) {
+        update = app.get_trip_update(&trip_id, None);
+    }
+    Ok(Json(RealtimeTrip::build(feed, trip, update.as_ref())).into_response())
+}
@@ -0,0 +1,80 @@
+use super::{feed_prefix, AroundQuery};
+use crate::{quadtree::Coordinate, store::Store};
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use serde_json::json;
+use std::{collections::HashSet, sync::Arc};
+
+/// Largest radius accepted by `/routes/near`, in meters
+const MAX_RADIUS: f64 = 2_000.0;
+
+/// Routes and directions whose shapes pass within `radius` meters, with the
+/// closest point of the closest shape, sorted by distance
+pub async fn near(State(app): State<Arc<Store>>, query: Query<AroundQuery>) -> impl IntoResponse {
+    let lat = match query.lat {
+        Some(lat) if lat.is_finite() => lat,
+        _ => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Missing lat"})),
+            ))
+        }
+    };
+
+    let lon = match query.lon {
+        Some(lon) if lon.is_finite() => lon,
+        _ => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Missing lon"})),
+            ))
+        }
+    };
+
+    let radius = match query.radius {
+        Some(radius) if (0.0..=MAX_RADIUS).contains(&radius) => radius,
+        Some(_) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Invalid radius"})),
+            ))
+        }
+        None => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Missing radius"})),
+            ))
+        }
+    };
+
+    let snapshot = app.get_feed();
+    let prefix = feed_prefix(&snapshot, query.feed.as_deref())?;
+
+    // Shapes come closest first, so the first one seen for a route and
+    // direction is its closest
+    let shapes = snapshot.get_shapes();
+    let mut seen = HashSet::new();
+    let mut routes = Vec::new();
+    for found in shapes.find_near(&Coordinate::new(lon, lat), radius) {
+        for (route, direction) in shapes.get_routes(found.shape) {
+            let route_id = snapshot.resolve_symbol(*route);
+            if !route_id.starts_with(&prefix) || !seen.insert((*route, *direction)) {
+                continue;
+            }
+            routes.push(json!({
+                "route_id": route_id,
+                "direction_id": direction,
+                "shape_id": snapshot.resolve_symbol(found.shape),
+                "distance": found.distance,
+                "closest": found.closest,
+                "along": found.along,
+            }));
+        }
+    }
+
+    Ok(Json(routes).into_response())
+}
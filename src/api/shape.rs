@@ -1,5 +1,5 @@
-use super::TripQuery;
-use crate::store::Store;
+use super::{feed_prefix, ShapePointQuery, TripQuery};
+use crate::{quadtree::Coordinate, store::Store};
 use axum::{
     extract::{Query, State},
     http::StatusCode,
@@ -11,8 +11,7 @@ use serde_json::json;
 use std::sync::Arc;
 
 pub async fn shape(State(app): State<Arc<Store>>, query: Query<TripQuery>) -> impl IntoResponse {
-    let gtfs = app.get_gtfs();
-    let app = gtfs.read().await;
+    let snapshot = app.get_feed();
 
     let trip_id = match &query.trip_id {
         Some(trip_id) => trip_id,
@@ -24,8 +23,10 @@ pub async fn shape(State(app): State<Arc<Store>>, query: Query<TripQuery>) -> im
         }
     };
 
-    let trip = match app.get_trip(trip_id) {
-        Ok(trip) => trip,
+    feed_prefix(&snapshot, query.feed.as_deref())?;
+
+    let (feed, trip) = match snapshot.find_trip(trip_id, query.feed.as_deref()) {
+        Some(found) => found,
         _ => {
             return Err((
                 StatusCode::BAD_REQUEST,
@@ -39,17 +40,17 @@ pub async fn shape(State(app): State<Arc<Store>>, query: Query<TripQuery>) -> im
         None => return Err((StatusCode::BAD_REQUEST, Json(json!({"error": "no shape"})))),
     };
 
-    let shape: Vec<Shape> = match app.get_shape(&shape_id) {
+    let shape: Vec<Shape> = match feed.get_gtfs().get_shape(&shape_id) {
         Ok(shape) => {
             let mut vec = Vec::with_capacity(shape.len());
             for s in shape {
-                let mut def = Shape::default();
-                def.id = s.id.clone();
-                def.latitude = s.latitude.clone();
-                def.longitude = s.longitude.clone();
-                def.sequence = s.sequence.clone();
-                def.dist_traveled = s.dist_traveled.clone();
-                vec.push(def);
+                vec.push(Shape {
+                    id: feed.namespaced(&s.id),
+                    latitude: s.latitude,
+                    longitude: s.longitude,
+                    sequence: s.sequence,
+                    dist_traveled: s.dist_traveled,
+                });
             }
             vec
         }
@@ -63,3 +64,76 @@ pub async fn shape(State(app): State<Arc<Store>>, query: Query<TripQuery>) -> im
 
     Ok(Json(shape).into_response())
 }
+
+/// Closest point of the shape of a trip to a location, with its distance
+/// from the start of the shape
+pub async fn closest(
+    State(app): State<Arc<Store>>,
+    query: Query<ShapePointQuery>,
+) -> impl IntoResponse {
+    let snapshot = app.get_feed();
+
+    let trip_id = match &query.trip_id {
+        Some(trip_id) => trip_id,
+        None => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Missing trip_id"})),
+            ))
+        }
+    };
+
+    let lat = match query.lat {
+        Some(lat) if lat.is_finite() => lat,
+        _ => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Missing lat"})),
+            ))
+        }
+    };
+
+    let lon = match query.lon {
+        Some(lon) if lon.is_finite() => lon,
+        _ => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Missing lon"})),
+            ))
+        }
+    };
+
+    feed_prefix(&snapshot, query.feed.as_deref())?;
+
+    let (feed, trip) = match snapshot.find_trip(trip_id, query.feed.as_deref()) {
+        Some(found) => found,
+        _ => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Invalid trip_id"})),
+            ))
+        }
+    };
+
+    let shape_id = match &trip.shape_id {
+        Some(shape_id) => feed.namespaced(shape_id),
+        None => return Err((StatusCode::BAD_REQUEST, Json(json!({"error": "no shape"})))),
+    };
+
+    let found = snapshot
+        .get_symbol(&shape_id)
+        .and_then(|shape| snapshot.get_shapes().find_closest(shape, &Coordinate::new(lon, lat)));
+    match found {
+        Some(found) => Ok(Json(json!({
+            "shape_id": shape_id,
+            "closest": found.closest,
+            "distance": found.distance,
+            "along": found.along,
+        }))
+        .into_response()),
+        None => Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "Invalid shape_id"})),
+        )),
+    }
+}
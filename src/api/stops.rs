@@ -1,14 +1,34 @@
-use super::{BboxQuery, StopQuery};
-use crate::{quadtree::Extent, store::Store};
+use super::{
+    feed_prefix, AroundQuery, BboxQuery, CorridorQuery, NearestQuery, StopQuery, WithinQuery,
+};
+use crate::{
+    quadtree::{Coordinate, Extent, Polygon},
+    spatial::SpatialIndex,
+    store::{AlertFilter, FeedSnapshot, Store},
+};
 use axum::{
     extract::{Query, State},
     http::StatusCode,
-    response::IntoResponse,
+    response::{IntoResponse, Response},
     Json,
 };
-use serde_json::json;
+use chrono::Utc;
+use geojson::{GeoJson, Position};
+use serde::Serialize;
+use serde_json::{json, Value};
 use std::sync::Arc;
 
+/// Largest page of `/stops`
+const MAX_STOPS_PAGE: usize = 5000;
+
+/// Width in pixels of a cluster on a 256 pixels map tile, and the zoom level
+/// from which `/stops` stops clustering
+const CLUSTER_SIZE: f64 = 64.0;
+const MAX_CLUSTER_ZOOM: u8 = 16;
+
+/// Stop ids listed in a cluster
+const CLUSTER_REPRESENTATIVES: usize = 3;
+
 pub async fn stops(State(app): State<Arc<Store>>, query: Query<BboxQuery>) -> impl IntoResponse {
     let north = match &query.north {
         Some(north) => north,
@@ -50,12 +70,370 @@ pub async fn stops(State(app): State<Arc<Store>>, query: Query<BboxQuery>) -> im
         }
     };
 
-    let stops = app.get_stops();
-    let app = stops.read().await;
+    let snapshot = app.get_feed();
+    let prefix = feed_prefix(&snapshot, query.feed.as_deref())?;
+
     let extent = Extent::new(*west as f64, *south as f64, *east as f64, *north as f64);
-    let stops = app.find_bbox(&extent);
+    if let Some(zoom) = query.zoom {
+        if query.limit.is_some() || query.offset.is_some() {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Paging is not supported with zoom"})),
+            ));
+        }
+        let app = Some(app.as_ref()).filter(|_| query.alerts.unwrap_or(false));
+        return Ok(clusters(&snapshot, &extent, zoom, &prefix, app).into_response());
+    }
+
+    let limit = query.limit.map(|limit| limit.min(MAX_STOPS_PAGE));
+    let page =
+        snapshot
+            .get_stops()
+            .find_bbox_page(&extent, query.offset.unwrap_or(0), limit, |stop_id| {
+                snapshot.resolve_symbol(*stop_id).starts_with(&prefix)
+            });
+    let stops: Vec<_> = page
+        .values
+        .into_iter()
+        .map(|(stop_id, coord)| (snapshot.resolve_symbol(stop_id), coord))
+        .collect();
+
+    // Without paging parameters, keep the plain list older clients expect
+    if query.limit.is_none() && query.offset.is_none() {
+        return Ok(with_alerts(&app, &stops, query.alerts, |stop| stop.0));
+    }
+
+    let alerts = stop_alerts(&app, stops.iter().map(|(stop_id, _)| *stop_id));
+    Ok(Json(json!({
+        "stops": stops,
+        "total": page.total,
+        "truncated": page.truncated,
+        "alerts": alerts,
+    }))
+    .into_response())
+}
+
+/// Active alerts of the stops, by stop id, the stops without any being left
+/// out
+fn stop_alerts<'a>(
+    app: &Store,
+    stop_ids: impl Iterator<Item = &'a str>,
+) -> serde_json::Map<String, Value> {
+    app.get_stop_alerts(stop_ids, Utc::now().timestamp() as u64)
+        .into_iter()
+        .map(|(stop_id, alerts)| (stop_id.to_string(), json!(alerts)))
+        .collect()
+}
+
+/// Stops as the plain list older clients expect, or as
+/// `{"stops": [...], "alerts": {...}}` when `alerts` is asked for
+fn with_alerts<'a, T: Serialize>(
+    app: &Store,
+    stops: &'a [T],
+    alerts: Option<bool>,
+    stop_id: impl Fn(&'a T) -> &'a str,
+) -> Response {
+    if !alerts.unwrap_or(false) {
+        return Json(stops).into_response();
+    }
+    let alerts = stop_alerts(app, stops.iter().map(stop_id));
+    Json(json!({"stops": stops, "alerts": alerts})).into_response()
+}
+
+/// Stops of `/stops` grouped by quadtree cells about `CLUSTER_SIZE` pixels
+/// wide at `zoom`, lone stops being returned apart, with their alerts when
+/// `app` is given
+fn clusters(
+    snapshot: &FeedSnapshot,
+    extent: &Extent,
+    zoom: u8,
+    prefix: &str,
+    app: Option<&Store>,
+) -> Json<Value> {
+    // Web mercator: a tile spans 360 / 2^zoom degrees of longitude, and
+    // fewer degrees of latitude away from the equator
+    let (width, height) = if zoom < MAX_CLUSTER_ZOOM {
+        let width = 360.0 / 2f64.powi(zoom as i32) * CLUSTER_SIZE / 256.0;
+        let latitude = (extent.y_low + extent.y_high) / 2.0;
+        (width, width * latitude.to_radians().cos())
+    } else {
+        (0.0, 0.0)
+    };
+
+    let mut stops = Vec::new();
+    let mut clusters = Vec::new();
+    for cluster in snapshot.get_stops().find_clusters(
+        extent,
+        width,
+        height,
+        CLUSTER_REPRESENTATIVES,
+        |stop_id| snapshot.resolve_symbol(*stop_id).starts_with(prefix),
+    ) {
+        if cluster.count == 1 {
+            let (stop_id, coord) = cluster.values.into_iter().next().unwrap();
+            stops.push((snapshot.resolve_symbol(stop_id), coord));
+            continue;
+        }
+
+        let stop_ids: Vec<&str> = cluster
+            .values
+            .iter()
+            .map(|(stop_id, _)| snapshot.resolve_symbol(*stop_id))
+            .collect();
+        clusters.push(json!({
+            "centroid": cluster.centroid,
+            "count": cluster.count,
+            "extent": cluster.extent,
+            "stops": stop_ids,
+        }));
+    }
+    stops.sort_by(|a, b| a.0.cmp(b.0));
+
+    let mut response = json!({
+        "clusters": clusters,
+        "stops": stops,
+    });
+    if let Some(app) = app {
+        response["alerts"] = Value::Object(stop_alerts(app, stops.iter().map(|stop| stop.0)));
+    }
+    Json(response)
+}
+
+/// Default and maximum number of stops returned by `/stops/nearest`
+const DEFAULT_NEAREST: usize = 5;
+const MAX_NEAREST: usize = 100;
+
+/// Closest stops to a point, sorted by distance in meters
+pub async fn nearest(
+    State(app): State<Arc<Store>>,
+    query: Query<NearestQuery>,
+) -> impl IntoResponse {
+    let lat = match query.lat {
+        Some(lat) if lat.is_finite() => lat,
+        _ => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Missing lat"})),
+            ))
+        }
+    };
+
+    let lon = match query.lon {
+        Some(lon) if lon.is_finite() => lon,
+        _ => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Missing lon"})),
+            ))
+        }
+    };
+
+    let max_distance = match query.max_distance {
+        Some(max_distance) if max_distance.is_finite() && max_distance >= 0.0 => max_distance,
+        Some(_) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Invalid max_distance"})),
+            ))
+        }
+        None => f64::INFINITY,
+    };
+
+    let snapshot = app.get_feed();
+    let prefix = feed_prefix(&snapshot, query.feed.as_deref())?;
+
+    let k = query.k.unwrap_or(DEFAULT_NEAREST).min(MAX_NEAREST);
+    let stops: Vec<_> = snapshot
+        .get_stops()
+        .find_nearest_by(&Coordinate::new(lon, lat), k, max_distance, |stop_id| {
+            snapshot.resolve_symbol(*stop_id).starts_with(&prefix)
+        })
+        .into_iter()
+        .map(|(stop_id, coord, distance)| (snapshot.resolve_symbol(stop_id), coord, distance))
+        .collect();
+
+    Ok(with_alerts(&app, &stops, query.alerts, |stop| stop.0))
+}
+
+/// Largest radius accepted by `/stops/around`, in meters
+const MAX_RADIUS: f64 = 10_000.0;
+
+/// Stops within a walking radius, sorted by distance in meters
+pub async fn around(State(app): State<Arc<Store>>, query: Query<AroundQuery>) -> impl IntoResponse {
+    let lat = match query.lat {
+        Some(lat) if lat.is_finite() => lat,
+        _ => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Missing lat"})),
+            ))
+        }
+    };
+
+    let lon = match query.lon {
+        Some(lon) if lon.is_finite() => lon,
+        _ => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Missing lon"})),
+            ))
+        }
+    };
+
+    let radius = match query.radius {
+        Some(radius) if (0.0..=MAX_RADIUS).contains(&radius) => radius,
+        Some(_) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Invalid radius"})),
+            ))
+        }
+        None => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Missing radius"})),
+            ))
+        }
+    };
 
-    Ok(Json(stops).into_response())
+    let snapshot = app.get_feed();
+    let prefix = feed_prefix(&snapshot, query.feed.as_deref())?;
+
+    let stops: Vec<_> = snapshot
+        .get_stops()
+        .find_radius(&Coordinate::new(lon, lat), radius)
+        .into_iter()
+        .map(|(stop_id, coord, distance)| (snapshot.resolve_symbol(stop_id), coord, distance))
+        .filter(|(stop_id, _, _)| stop_id.starts_with(&prefix))
+        .collect();
+
+    Ok(with_alerts(&app, &stops, query.alerts, |stop| stop.0))
+}
+
+/// Stops inside a GeoJSON Polygon or MultiPolygon, ordered by `stop_id`
+pub async fn within(
+    State(app): State<Arc<Store>>,
+    query: Query<WithinQuery>,
+    body: String,
+) -> impl IntoResponse {
+    let rings = match parse_geometry(&body) {
+        Ok(geojson::Value::Polygon(rings)) => vec![rings],
+        Ok(geojson::Value::MultiPolygon(polygons)) => polygons,
+        Ok(_) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Expected a Polygon or MultiPolygon"})),
+            ))
+        }
+        Err(error) => return Err((StatusCode::BAD_REQUEST, Json(json!({"error": error})))),
+    };
+
+    let rings = match rings.iter().flatten().map(|ring| coordinates(ring)).collect() {
+        Some(rings) => rings,
+        None => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Invalid coordinates"})),
+            ))
+        }
+    };
+
+    let snapshot = app.get_feed();
+    let prefix = feed_prefix(&snapshot, query.feed.as_deref())?;
+
+    let mut stops: Vec<_> = snapshot
+        .get_stops()
+        .find_polygon(&Polygon::new(rings))
+        .into_iter()
+        .map(|(stop_id, coord)| (snapshot.resolve_symbol(stop_id), coord))
+        .filter(|(stop_id, _)| stop_id.starts_with(&prefix))
+        .collect();
+    stops.sort_by(|a, b| a.0.cmp(b.0));
+
+    Ok(with_alerts(&app, &stops, query.alerts, |stop| stop.0))
+}
+
+/// Stops within `buffer` meters of a GeoJSON LineString or MultiLineString,
+/// sorted by distance in meters
+pub async fn corridor(
+    State(app): State<Arc<Store>>,
+    query: Query<CorridorQuery>,
+    body: String,
+) -> impl IntoResponse {
+    let buffer = match query.buffer {
+        Some(buffer) if (0.0..=MAX_RADIUS).contains(&buffer) => buffer,
+        Some(_) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Invalid buffer"})),
+            ))
+        }
+        None => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Missing buffer"})),
+            ))
+        }
+    };
+
+    let lines = match parse_geometry(&body) {
+        Ok(geojson::Value::LineString(line)) => vec![line],
+        Ok(geojson::Value::MultiLineString(lines)) => lines,
+        Ok(_) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Expected a LineString or MultiLineString"})),
+            ))
+        }
+        Err(error) => return Err((StatusCode::BAD_REQUEST, Json(json!({"error": error})))),
+    };
+
+    let lines: Vec<Vec<Coordinate>> = match lines.iter().map(|line| coordinates(line)).collect() {
+        Some(lines) => lines,
+        None => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "Invalid coordinates"})),
+            ))
+        }
+    };
+
+    let snapshot = app.get_feed();
+    let prefix = feed_prefix(&snapshot, query.feed.as_deref())?;
+
+    let stops: Vec<_> = snapshot
+        .get_stops()
+        .find_corridor(&lines, buffer)
+        .into_iter()
+        .map(|(stop_id, coord, distance)| (snapshot.resolve_symbol(stop_id), coord, distance))
+        .filter(|(stop_id, _, _)| stop_id.starts_with(&prefix))
+        .collect();
+
+    Ok(with_alerts(&app, &stops, query.alerts, |stop| stop.0))
+}
+
+/// Geometry of a GeoJSON body, given alone or as a feature
+fn parse_geometry(body: &str) -> Result<geojson::Value, &'static str> {
+    match body.parse::<GeoJson>() {
+        Ok(GeoJson::Geometry(geometry)) => Ok(geometry.value),
+        Ok(GeoJson::Feature(feature)) => match feature.geometry {
+            Some(geometry) => Ok(geometry.value),
+            None => Err("Missing geometry"),
+        },
+        Ok(GeoJson::FeatureCollection(_)) => Err("Expected a Geometry or a Feature"),
+        Err(_) => Err("Invalid GeoJSON"),
+    }
+}
+
+/// Positions as coordinates, `None` if one is not a valid location
+fn coordinates(positions: &[Position]) -> Option<Vec<Coordinate>> {
+    positions
+        .iter()
+        .map(|position| match position.as_slice() {
+            [lon, lat, ..] => Some(Coordinate::new(*lon, *lat)).filter(|c| c.is_valid()),
+            _ => None,
+        })
+        .collect()
 }
 
 pub async fn bus_per_stop(
@@ -72,10 +450,37 @@ pub async fn bus_per_stop(
         }
     };
 
-    let rs = app.get_reverse_stops();
-    let app = rs.read().await;
-    match app.get(stop_id) {
-        Some(stops) => Ok(Json(stops).into_response()),
+    let snapshot = app.get_feed();
+    feed_prefix(&snapshot, query.feed.as_deref())?;
+
+    let routes = snapshot
+        .resolve(stop_id, query.feed.as_deref())
+        .into_iter()
+        .filter_map(|(feed, id)| snapshot.get_symbol(&feed.namespaced(id)))
+        .find_map(|stop_id| {
+            snapshot
+                .get_reverse_stops()
+                .get(&stop_id)
+                .map(|routes| (stop_id, routes))
+        });
+
+    match routes {
+        Some((stop_id, routes)) => {
+            let routes: Vec<&str> = routes
+                .iter()
+                .map(|route_id| snapshot.resolve_symbol(*route_id))
+                .collect();
+
+            // The plain list stays the default for older clients
+            if !query.alerts.unwrap_or(false) {
+                return Ok(Json(routes).into_response());
+            }
+            let alerts = app.get_alerts(
+                &AlertFilter::Stop(snapshot.resolve_symbol(stop_id).to_string()),
+                Utc::now().timestamp() as u64,
+            );
+            Ok(Json(json!({"routes": routes, "alerts": alerts})).into_response())
+        }
         None => Err((
             StatusCode::BAD_REQUEST,
             Json(json!({"error": "Stop not found"})),
@@ -1,4 +1,4 @@
-use super::TripQuery;
+use super::{feed_prefix, TripQuery};
 use crate::store::Store;
 use axum::{
     extract::{Query, State},
@@ -13,8 +13,7 @@ pub async fn theorical_schedule(
     State(app): State<Arc<Store>>,
     query: Query<TripQuery>,
 ) -> impl IntoResponse {
-    let gtfs = app.get_gtfs();
-    let app = gtfs.read().await;
+    let snapshot = app.get_feed();
 
     let trip_id = match &query.trip_id {
         Some(trip_id) => trip_id,
@@ -26,8 +25,10 @@ pub async fn theorical_schedule(
         }
     };
 
-    let trip = match app.get_trip(trip_id) {
-        Ok(trip) => trip.clone(),
+    feed_prefix(&snapshot, query.feed.as_deref())?;
+
+    let (feed, trip) = match snapshot.find_trip(trip_id, query.feed.as_deref()) {
+        Some(found) => found,
         _ => {
             return Err((
                 StatusCode::BAD_REQUEST,
@@ -36,5 +37,17 @@ pub async fn theorical_schedule(
         }
     };
 
+    // Ids are namespaced like in every other endpoint
+    let mut trip = trip.clone();
+    trip.id = feed.namespaced(&trip.id);
+    trip.route_id = feed.namespaced(&trip.route_id);
+    trip.service_id = feed.namespaced(&trip.service_id);
+    trip.shape_id = trip.shape_id.map(|shape_id| feed.namespaced(&shape_id));
+    for st in trip.stop_times.iter_mut() {
+        let mut stop = st.stop.as_ref().clone();
+        stop.id = feed.namespaced(&stop.id);
+        st.stop = Arc::new(stop);
+    }
+
     Ok(Json(trip).into_response())
 }
@@ -0,0 +1,5 @@
+//! Spatial indexes of the server, in a library so benches can use them
+
+pub mod quadtree;
+pub mod rtree;
+pub mod spatial;
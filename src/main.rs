@@ -1,9 +1,10 @@
 use dotenv::dotenv;
 use std::{sync::Arc, env};
 
+use tec_gtfs::{quadtree, rtree, spatial};
+
 mod api;
 pub mod logger;
-pub mod quadtree;
 pub mod store;
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 10)]
@@ -15,7 +16,29 @@ async fn main() {
         Err(_) => panic!("No SECRET found in .env"),
     };
 
-    let store = Arc::new(store::Store::new(&secret));
+    let feeds = match env::var("GTFS_FEEDS") {
+        Ok(feeds) => match store::FeedConfig::parse_list(&feeds) {
+            Ok(feeds) => feeds,
+            Err(e) => panic!("Invalid GTFS_FEEDS: {}", e),
+        },
+        Err(_) => vec![store::FeedConfig {
+            name: "tec".to_string(),
+            source: store::FeedSource::parse(
+                &env::var("GTFS_SOURCE").unwrap_or_else(|_| "gtfs".to_string()),
+            ),
+        }],
+    };
+
+    let store = match store::Store::new(&secret, feeds).await {
+        Ok(store) => Arc::new(store),
+        Err(e) => {
+            logger::critical("FETCHER", &format!("Error loading GTFS: {}", e));
+            std::process::exit(1);
+        }
+    };
     logger::fine("FETCHER", "Loaded GTFS");
+    store::poller::spawn(store.clone(), store::poller::PollerConfig::from_env());
+    store::realtime::spawn(store.clone(), store::realtime::RealtimeConfig::from_env());
+    store::spawn_bus_expiry(store.clone());
     api::init(store).await;
 }
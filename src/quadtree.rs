@@ -1,8 +1,13 @@
-use std::{collections::VecDeque, fmt::Debug};
+use std::{
+    collections::{BinaryHeap, VecDeque},
+    fmt::Debug,
+};
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Copy)]
+use crate::spatial::{Candidate, Cluster, Item, SpatialIndex};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct Extent {
     pub x_low: f64,
     pub x_high: f64,
@@ -10,26 +15,170 @@ pub struct Extent {
     pub y_high: f64,
 }
 
+/// Point quadtree whose leaves hold up to `capacity` distinct coordinates
+/// before splitting, and never split past `max_depth`
+#[derive(Serialize, Deserialize)]
 pub struct QuadTree<T: Clone + Debug> {
-    bot_left: Option<Box<QuadTree<T>>>,
-    bot_right: Option<Box<QuadTree<T>>>,
-    top_left: Option<Box<QuadTree<T>>>,
-    top_right: Option<Box<QuadTree<T>>>,
+    root: Node<T>,
+    capacity: usize,
+    max_depth: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Node<T: Clone + Debug> {
+    bot_left: Option<Box<Node<T>>>,
+    bot_right: Option<Box<Node<T>>>,
+    top_left: Option<Box<Node<T>>>,
+    top_right: Option<Box<Node<T>>>,
     has_children: bool,
-    value: Option<(VecDeque<T>, Coordinate)>,
+    /// Values of a leaf, grouped by coordinate
+    points: Vec<(VecDeque<T>, Coordinate)>,
     extent: Extent,
 }
 
+/// Shape of a [`QuadTree`], to tune its capacity and maximum depth
 #[derive(Debug, Clone, Serialize)]
+pub struct QuadTreeStats {
+    pub capacity: usize,
+    pub max_depth: usize,
+    pub nodes: usize,
+    pub leaves: usize,
+    /// Distinct coordinates
+    pub points: usize,
+    pub values: usize,
+    /// Number of nodes at each depth, the root being at depth 0
+    pub depths: Vec<usize>,
+    /// Number of leaves holding each number of distinct coordinates
+    pub points_per_leaf: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Coordinate {
-    x: f64,
-    y: f64,
+    pub(crate) x: f64,
+    pub(crate) y: f64,
 }
 
+/// Mean earth radius in meters
+pub const EARTH_RADIUS: f64 = 6_371_008.8;
+
+/// Distinct coordinates a leaf holds before splitting
+pub const DEFAULT_CAPACITY: usize = 8;
+
+/// Depth past which leaves keep growing instead of splitting, cells are
+/// then under a meter wide on a country-sized tree
+pub const DEFAULT_MAX_DEPTH: usize = 24;
+
+/// Smallest side of an extent in degrees, about 10 m, so two points never
+/// end up in a degenerate extent
+const MIN_EXTENT_SIZE: f64 = 1e-4;
+
 impl Coordinate {
     pub fn new(x: f64, y: f64) -> Self {
         Self { x, y }
     }
+
+    /// Longitude
+    pub fn get_x(&self) -> f64 {
+        self.x
+    }
+
+    /// Latitude
+    pub fn get_y(&self) -> f64 {
+        self.y
+    }
+
+    /// Whether this can be a location: finite, within longitude and latitude
+    /// ranges, and not 0/0 (null island, an unset position in most feeds)
+    pub fn is_valid(&self) -> bool {
+        self.x.is_finite()
+            && self.y.is_finite()
+            && (-180.0..=180.0).contains(&self.x)
+            && (-90.0..=90.0).contains(&self.y)
+            && !(self.x == 0.0 && self.y == 0.0)
+    }
+
+    /// Great-circle distance in meters, x being the longitude and y the
+    /// latitude in degrees
+    pub fn distance(&self, other: &Coordinate) -> f64 {
+        let (lat1, lat2) = (self.y.to_radians(), other.y.to_radians());
+        let dlat = lat2 - lat1;
+        let dlon = (other.x - self.x).to_radians();
+
+        let a = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS * a.sqrt().min(1.0).asin()
+    }
+
+    /// Closest point of the segment `a`-`b` and its distance in meters. The
+    /// segment is projected on a plane tangent at this coordinate, which is
+    /// accurate for segments of a few kilometers.
+    pub fn closest_on_segment(&self, a: &Coordinate, b: &Coordinate) -> (Coordinate, f64) {
+        let scale = self.y.to_radians().cos().max(1e-9);
+        let (ax, ay) = ((a.x - self.x) * scale, a.y - self.y);
+        let (bx, by) = ((b.x - self.x) * scale, b.y - self.y);
+        let (dx, dy) = (bx - ax, by - ay);
+
+        let length = dx * dx + dy * dy;
+        let t = if length > 0.0 {
+            (-(ax * dx + ay * dy) / length).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        let closest = Coordinate::new(self.x + (ax + t * dx) / scale, self.y + ay + t * dy);
+        let distance = self.distance(&closest);
+        (closest, distance)
+    }
+}
+
+/// Meters in a degree of latitude
+const METERS_PER_DEGREE: f64 = EARTH_RADIUS * std::f64::consts::PI / 180.0;
+
+/// Area made of closed rings, a coordinate being inside when it is inside
+/// an odd number of them: holes and the parts of a multipolygon need no
+/// special treatment
+#[derive(Debug, Clone)]
+pub struct Polygon {
+    rings: Vec<Vec<Coordinate>>,
+    extent: Option<Extent>,
+}
+
+impl Polygon {
+    /// Rings of less than three coordinates are ignored, closing a ring by
+    /// repeating its first coordinate is optional
+    pub fn new(rings: Vec<Vec<Coordinate>>) -> Self {
+        let rings: Vec<Vec<Coordinate>> =
+            rings.into_iter().filter(|ring| ring.len() >= 3).collect();
+        let extent = Extent::bounding(rings.iter().flatten());
+        Self { rings, extent }
+    }
+
+    /// Bounding box of the rings, `None` without any
+    pub fn extent(&self) -> Option<Extent> {
+        self.extent
+    }
+
+    pub fn contains(&self, coord: &Coordinate) -> bool {
+        match &self.extent {
+            Some(extent) if extent.contains(coord) => {}
+            _ => return false,
+        }
+
+        // Even-odd ray casting towards the east
+        let mut inside = false;
+        for ring in &self.rings {
+            let mut previous = &ring[ring.len() - 1];
+            for point in ring {
+                if (point.y > coord.y) != (previous.y > coord.y)
+                    && coord.x
+                        < (previous.x - point.x) * (coord.y - point.y) / (previous.y - point.y)
+                            + point.x
+                {
+                    inside = !inside;
+                }
+                previous = point;
+            }
+        }
+        inside
+    }
 }
 
 impl Extent {
@@ -42,6 +191,50 @@ impl Extent {
         }
     }
 
+    /// Smallest extent holding every coordinate, `None` without any
+    pub fn bounding<'a>(coords: impl IntoIterator<Item = &'a Coordinate>) -> Option<Self> {
+        let mut extent: Option<Extent> = None;
+        for coord in coords {
+            extent = Some(match extent {
+                Some(extent) => extent.expand(coord),
+                None => Extent::new(coord.x, coord.y, coord.x, coord.y),
+            });
+        }
+        extent
+    }
+
+    /// Extent grown to hold `coord`
+    pub fn expand(&self, coord: &Coordinate) -> Self {
+        Self {
+            x_low: self.x_low.min(coord.x),
+            y_low: self.y_low.min(coord.y),
+            x_high: self.x_high.max(coord.x),
+            y_high: self.y_high.max(coord.y),
+        }
+    }
+
+    /// Area in square degrees
+    pub fn area(&self) -> f64 {
+        (self.x_high - self.x_low) * (self.y_high - self.y_low)
+    }
+
+    pub fn center(&self) -> (f64, f64) {
+        (
+            (self.x_low + self.x_high) / 2.0,
+            (self.y_low + self.y_high) / 2.0,
+        )
+    }
+
+    /// Smallest extent holding both
+    pub fn union(&self, other: &Extent) -> Self {
+        Self {
+            x_low: self.x_low.min(other.x_low),
+            y_low: self.y_low.min(other.y_low),
+            x_high: self.x_high.max(other.x_high),
+            y_high: self.y_high.max(other.y_high),
+        }
+    }
+
     pub fn contains(&self, coord: &Coordinate) -> bool {
         let x = coord.x;
         let y = coord.y;
@@ -55,14 +248,51 @@ impl Extent {
             && self.y_high >= other.y_low
     }
 
+    /// Great-circle distance in meters from `coord` to the closest point of
+    /// the extent, 0 when inside
+    pub fn distance(&self, coord: &Coordinate) -> f64 {
+        let x = coord.x.clamp(self.x_low, self.x_high);
+        if x == coord.x {
+            // Straight north or south along the meridian
+            return coord.distance(&Coordinate::new(x, coord.y.clamp(self.y_low, self.y_high)));
+        }
+
+        // Closest point of the meridian of the nearest side: its latitude
+        // drifts towards the pole the further away the meridian is
+        let lat = coord.y.to_radians();
+        let dlon = (x - coord.x).to_radians();
+        let foot = (lat.tan() / dlon.cos()).atan().to_degrees();
+        coord.distance(&Coordinate::new(x, foot.clamp(self.y_low, self.y_high)))
+    }
 
+    /// Extent grown by at least `meters` on every side
+    pub fn buffer(&self, meters: f64) -> Self {
+        let dlat = meters / METERS_PER_DEGREE;
+        let y_low = (self.y_low - dlat).max(-90.0);
+        let y_high = (self.y_high + dlat).min(90.0);
+
+        // A degree of longitude is shortest on the side closest to a pole
+        let cos = y_low.abs().max(y_high.abs()).to_radians().cos();
+        let dlon = if cos > 1e-9 {
+            (meters / (METERS_PER_DEGREE * cos) * 1.01).min(360.0)
+        } else {
+            360.0
+        };
+
+        Self {
+            x_low: self.x_low - dlon,
+            x_high: self.x_high + dlon,
+            y_low,
+            y_high,
+        }
+    }
+
+    /// Quadrant of the extent holding `coord`. A coordinate outside goes
+    /// to the closest quadrant, which only happens by rounding when the
+    /// root grows.
     pub fn quadrant(&self, coord: &Coordinate) -> usize {
         let x = coord.x;
         let y = coord.y;
-        //outside
-        if !self.contains(coord) {
-            panic!("Coordinate not in extent");
-        }
         if x <= (self.x_low + self.x_high) / 2.0 {
             if y <= (self.y_low + self.y_high) / 2.0 {
                 0 // bot left
@@ -79,34 +309,508 @@ impl Extent {
 
 impl<T: Clone + Debug> QuadTree<T> {
     pub fn new(extent: Extent) -> Self {
+        Self::with_limits(extent, DEFAULT_CAPACITY, DEFAULT_MAX_DEPTH)
+    }
+
+    /// Tree whose leaves split past `capacity` distinct coordinates, down to
+    /// `max_depth`
+    pub fn with_limits(extent: Extent, capacity: usize, max_depth: usize) -> Self {
+        Self {
+            root: Node::new(extent),
+            capacity: capacity.max(1),
+            max_depth,
+        }
+    }
+
+    /// Remove the values at `coord` accepted by `predicate` and return them.
+    /// Nodes whose children end up holding no more than `capacity`
+    /// coordinates are collapsed back into a leaf.
+    pub fn remove(&mut self, coord: &Coordinate, predicate: impl Fn(&T) -> bool) -> Vec<T> {
+        let mut removed = Vec::new();
+        let mut path = Vec::new();
+        let mut node = &mut self.root;
+
+        while node.has_children {
+            let quadrant = node.extent.quadrant(coord);
+            path.push(quadrant);
+            node = node.child_mut(quadrant);
+        }
+
+        if let Some(index) = node
+            .points
+            .iter()
+            .position(|(_, point)| point.x == coord.x && point.y == coord.y)
+        {
+            let data = &mut node.points[index].0;
+            let mut kept = VecDeque::with_capacity(data.len());
+            for value in data.drain(..) {
+                if predicate(&value) {
+                    removed.push(value);
+                } else {
+                    kept.push_back(value);
+                }
+            }
+            *data = kept;
+            if data.is_empty() {
+                node.points.swap_remove(index);
+            }
+        }
+
+        if removed.is_empty() {
+            return removed;
+        }
+
+        // Collapse from the parent of the leaf up to the root, stopping at
+        // the first node that keeps its children
+        for depth in (0..path.len()).rev() {
+            let mut node = &mut self.root;
+            for quadrant in &path[..depth] {
+                node = node.child_mut(*quadrant);
+            }
+            if !node.collapse(self.capacity) {
+                break;
+            }
+        }
+
+        removed
+    }
+
+    /// Move `value` from `old` to `new`, inserting it when it was not at
+    /// `old`. Returns false and leaves the tree untouched when `new` is not a
+    /// location, see [`Coordinate::is_valid`].
+    pub fn update(&mut self, old: &Coordinate, new: &Coordinate, value: T) -> bool
+    where
+        T: PartialEq,
+    {
+        if !new.is_valid() {
+            return false;
+        }
+        self.remove(old, |v| *v == value);
+        self.insert(new, value)
+    }
+
+    /// Make the root contain `coord`. A leaf root is simply resized, otherwise
+    /// the root doubles towards `coord`, the previous root becoming one of
+    /// the quadrants of the new one.
+    fn grow(&mut self, coord: &Coordinate) {
+        let root = &mut self.root;
+        if root.extent.contains(coord) {
+            return;
+        }
+
+        if !root.has_children {
+            let extent = Extent::bounding(root.points.iter().map(|(_, point)| point))
+                .unwrap_or_else(|| Extent::new(coord.x, coord.y, coord.x, coord.y))
+                .expand(coord);
+            root.extent = Extent::new(
+                extent.x_low,
+                extent.y_low,
+                extent.x_high.max(extent.x_low + MIN_EXTENT_SIZE),
+                extent.y_high.max(extent.y_low + MIN_EXTENT_SIZE),
+            );
+            return;
+        }
+
+        while !self.root.extent.contains(coord) {
+            let extent = self.root.extent;
+            // A root split on a line has no width or height to double
+            let width = (extent.x_high - extent.x_low).max(MIN_EXTENT_SIZE);
+            let height = (extent.y_high - extent.y_low).max(MIN_EXTENT_SIZE);
+            let left = coord.x < extent.x_low;
+            let down = coord.y < extent.y_low;
+
+            let grown = Extent {
+                x_low: if left {
+                    extent.x_low - width
+                } else {
+                    extent.x_low
+                },
+                x_high: if left {
+                    extent.x_high
+                } else {
+                    extent.x_high + width
+                },
+                y_low: if down {
+                    extent.y_low - height
+                } else {
+                    extent.y_low
+                },
+                y_high: if down {
+                    extent.y_high
+                } else {
+                    extent.y_high + height
+                },
+            };
+
+            let old = std::mem::replace(&mut self.root, Node::new(grown));
+            self.root.divide();
+            let slot = match (left, down) {
+                (false, false) => &mut self.root.bot_left,
+                (true, false) => &mut self.root.bot_right,
+                (false, true) => &mut self.root.top_left,
+                (true, true) => &mut self.root.top_right,
+            };
+            *slot = Some(Box::new(old));
+        }
+    }
+
+    /// Node count, depth histogram and points per leaf
+    pub fn stats(&self) -> QuadTreeStats {
+        let mut stats = QuadTreeStats {
+            capacity: self.capacity,
+            max_depth: self.max_depth,
+            nodes: 0,
+            leaves: 0,
+            points: 0,
+            values: 0,
+            depths: Vec::new(),
+            points_per_leaf: Vec::new(),
+        };
+        let mut stack = vec![(&self.root, 0)];
+
+        while let Some((node, depth)) = stack.pop() {
+            stats.nodes += 1;
+            if stats.depths.len() <= depth {
+                stats.depths.resize(depth + 1, 0);
+            }
+            stats.depths[depth] += 1;
+
+            if node.has_children {
+                stack.extend(node.children().map(|child| (child, depth + 1)));
+                continue;
+            }
+
+            stats.leaves += 1;
+            stats.points += node.points.len();
+            stats.values += node
+                .points
+                .iter()
+                .map(|(data, _)| data.len())
+                .sum::<usize>();
+            if stats.points_per_leaf.len() <= node.points.len() {
+                stats.points_per_leaf.resize(node.points.len() + 1, 0);
+            }
+            stats.points_per_leaf[node.points.len()] += 1;
+        }
+
+        stats
+    }
+
+    pub fn print(&self) {
+        self.root.print();
+    }
+}
+
+impl<T: Clone + Debug> SpatialIndex<T> for QuadTree<T> {
+    /// Grows the root when `coord` is outside of it
+    fn insert(&mut self, coord: &Coordinate, new_value: T) -> bool {
+        if !coord.is_valid() {
+            return false;
+        }
+        self.grow(coord);
+
+        let mut node = &mut self.root;
+        let mut depth = 0;
+
+        //Prevent recursion for performance
+        loop {
+            if node.has_children {
+                let quadrant = node.extent.quadrant(coord);
+                node = node.child_mut(quadrant);
+                depth += 1;
+                continue;
+            }
+
+            if let Some((data, _)) = node
+                .points
+                .iter_mut()
+                .find(|(_, point)| point.x == coord.x && point.y == coord.y)
+            {
+                data.push_back(new_value);
+                break;
+            }
+
+            if node.points.len() < self.capacity || depth >= self.max_depth {
+                node.points
+                    .push((VecDeque::from([new_value]), coord.clone()));
+                break;
+            }
+
+            // Full leaf: spread its points over four children and retry
+            node.divide();
+        }
+
+        true
+    }
+
+    fn find_bbox(&self, extent: &Extent) -> Vec<(T, Coordinate)> {
+        let mut result = Vec::new();
+        let mut stack = Vec::new();
+        stack.push(&self.root);
+
+        while let Some(node) = stack.pop() {
+            if node.extent.intersects(extent) {
+                // A node straddling the border may hold points outside
+                for (data, coord) in node
+                    .points
+                    .iter()
+                    .filter(|(_, coord)| extent.contains(coord))
+                {
+                    result.extend(data.iter().map(|x| (x.to_owned(), coord.to_owned())));
+                }
+
+                if node.has_children {
+                    stack.extend(node.children());
+                }
+            }
+        }
+
+        result
+    }
+
+    fn memory_usage(&self, value_size: impl Fn(&T) -> usize) -> usize {
+        let mut size = 0;
+        let mut stack = vec![&self.root];
+
+        while let Some(node) = stack.pop() {
+            size += std::mem::size_of::<Node<T>>();
+            size += node.points.capacity() * std::mem::size_of::<(VecDeque<T>, Coordinate)>();
+            for (data, _) in &node.points {
+                size += (data.capacity() - data.len()) * std::mem::size_of::<T>();
+                size += data.iter().map(&value_size).sum::<usize>();
+            }
+
+            stack.extend(node.children());
+        }
+
+        size
+    }
+
+    /// Coordinates of leaves larger than a cell are returned one by one
+    fn find_clusters(
+        &self,
+        extent: &Extent,
+        width: f64,
+        height: f64,
+        representatives: usize,
+        filter: impl Fn(&T) -> bool,
+    ) -> Vec<Cluster<T>> {
+        let mut clusters = Vec::new();
+        let mut stack = vec![&self.root];
+
+        while let Some(node) = stack.pop() {
+            if !node.extent.intersects(extent) {
+                continue;
+            }
+
+            let fits = node.extent.x_high - node.extent.x_low <= width
+                && node.extent.y_high - node.extent.y_low <= height;
+            if fits {
+                let mut values = Vec::new();
+                let mut subtree = vec![node];
+                while let Some(node) = subtree.pop() {
+                    for (data, point) in node.points.iter().filter(|(_, p)| extent.contains(p)) {
+                        values.extend(data.iter().filter(|v| filter(v)).map(|v| (v, point)));
+                    }
+                    subtree.extend(node.children());
+                }
+                clusters.extend(Cluster::build(values, representatives));
+                continue;
+            }
+
+            for (data, point) in node.points.iter().filter(|(_, p)| extent.contains(p)) {
+                let values = data
+                    .iter()
+                    .filter(|v| filter(v))
+                    .map(|v| (v, point))
+                    .collect();
+                clusters.extend(Cluster::build(values, representatives));
+            }
+
+            if node.has_children {
+                stack.extend(node.children());
+            }
+        }
+
+        clusters
+    }
+
+    fn find_polygon(&self, polygon: &Polygon) -> Vec<(T, Coordinate)> {
+        let mut result = Vec::new();
+        let extent = match polygon.extent() {
+            Some(extent) => extent,
+            None => return result,
+        };
+        let mut stack = vec![&self.root];
+
+        while let Some(node) = stack.pop() {
+            if !node.extent.intersects(&extent) {
+                continue;
+            }
+
+            for (data, point) in node
+                .points
+                .iter()
+                .filter(|(_, point)| polygon.contains(point))
+            {
+                result.extend(data.iter().map(|v| (v.clone(), point.clone())));
+            }
+
+            if node.has_children {
+                stack.extend(node.children());
+            }
+        }
+
+        result
+    }
+
+    fn find_corridor(&self, lines: &[Vec<Coordinate>], buffer: f64) -> Vec<(T, Coordinate, f64)> {
+        // Every segment with its bounding box grown by the buffer, a lone
+        // coordinate being a segment of zero length
+        let segments: Vec<(&Coordinate, &Coordinate, Extent)> = lines
+            .iter()
+            .flat_map(|line| match line.len() {
+                1 => vec![(&line[0], &line[0])],
+                _ => line.windows(2).map(|w| (&w[0], &w[1])).collect(),
+            })
+            .map(|(a, b)| {
+                let extent = Extent::new(a.x, a.y, a.x, a.y).expand(b).buffer(buffer);
+                (a, b, extent)
+            })
+            .collect();
+
+        let mut result = Vec::new();
+        let mut stack = vec![&self.root];
+
+        while let Some(node) = stack.pop() {
+            if !segments
+                .iter()
+                .any(|(_, _, extent)| extent.intersects(&node.extent))
+            {
+                continue;
+            }
+
+            for (data, point) in &node.points {
+                let distance = segments
+                    .iter()
+                    .filter(|(_, _, extent)| extent.contains(point))
+                    .map(|(a, b, _)| point.closest_on_segment(a, b).1)
+                    .fold(f64::INFINITY, f64::min);
+                if distance <= buffer {
+                    result.extend(data.iter().map(|v| (v.clone(), point.clone(), distance)));
+                }
+            }
+
+            if node.has_children {
+                stack.extend(node.children());
+            }
+        }
+
+        result.sort_by(|a, b| a.2.total_cmp(&b.2));
+        result
+    }
+
+    fn find_radius(&self, coord: &Coordinate, radius: f64) -> Vec<(T, Coordinate, f64)> {
+        let mut result = Vec::new();
+        let mut stack = vec![&self.root];
+
+        while let Some(node) = stack.pop() {
+            if node.extent.distance(coord) > radius {
+                continue;
+            }
+
+            for (data, point) in &node.points {
+                let distance = coord.distance(point);
+                if distance <= radius {
+                    result.extend(data.iter().map(|v| (v.clone(), point.clone(), distance)));
+                }
+            }
+
+            if node.has_children {
+                stack.extend(node.children());
+            }
+        }
+
+        result.sort_by(|a, b| a.2.total_cmp(&b.2));
+        result
+    }
+
+    fn find_nearest_by(
+        &self,
+        coord: &Coordinate,
+        k: usize,
+        max_distance: f64,
+        filter: impl Fn(&T) -> bool,
+    ) -> Vec<(T, Coordinate, f64)> {
+        let mut result = Vec::new();
+        if k == 0 {
+            return result;
+        }
+
+        // Best-first: nodes are queued by their distance lower bound and
+        // points by their exact distance, so a point popped before any
+        // node is closer than everything left in the tree
+        let mut queue = BinaryHeap::new();
+        queue.push(Candidate {
+            distance: self.root.extent.distance(coord),
+            item: Item::Node(&self.root),
+        });
+
+        while let Some(Candidate { distance, item }) = queue.pop() {
+            if distance > max_distance {
+                break;
+            }
+
+            match item {
+                Item::Point((value, point)) => {
+                    result.push((T::clone(value), Coordinate::clone(point), distance));
+                    if result.len() == k {
+                        break;
+                    }
+                }
+                Item::Node(node) => {
+                    for (data, point) in &node.points {
+                        let distance = coord.distance(point);
+                        for value in data.iter().filter(|v| filter(v)) {
+                            queue.push(Candidate {
+                                distance,
+                                item: Item::Point((value, point)),
+                            });
+                        }
+                    }
+
+                    if node.has_children {
+                        for child in node.children() {
+                            queue.push(Candidate {
+                                distance: child.extent.distance(coord),
+                                item: Item::Node(child),
+                            });
+                        }
+                    }
+                }
+            }
+        }
+
+        result
+    }
+}
+
+impl<T: Clone + Debug> Node<T> {
+    fn new(extent: Extent) -> Self {
         Self {
             bot_left: None,
             bot_right: None,
             top_left: None,
             top_right: None,
-            value: None,
+            points: Vec::new(),
             extent,
             has_children: false,
         }
     }
 
-    pub fn set_value(&mut self, value: T, coordinate: &Coordinate) -> bool {
-        if let Some((data, coord)) = &mut self.value {
-            if coord.x != coordinate.x || coord.y != coordinate.y {
-                return false;
-            }
-            data.push_back(value);
-        } else {
-            let mut data = VecDeque::new();
-            data.push_back(value);
-            self.value = Some((data, coordinate.clone()));
-        }
-
-        self.has_children = false;
-        return true;
-    }
-
-    pub fn divide(&mut self) {
+    /// Split the extent in four and move the points of the node to the
+    /// children holding them
+    fn divide(&mut self) {
         let extent = &self.extent;
 
         let nextent = Extent {
@@ -115,7 +819,7 @@ impl<T: Clone + Debug> QuadTree<T> {
             x_high: (extent.x_low + extent.x_high) / 2.0,
             y_high: extent.y_high,
         };
-        self.top_left = Some(Box::new(QuadTree::new(nextent)));
+        self.top_left = Some(Box::new(Node::new(nextent)));
 
         let nextent = Extent {
             x_low: (extent.x_low + extent.x_high) / 2.0,
@@ -123,7 +827,7 @@ impl<T: Clone + Debug> QuadTree<T> {
             x_high: extent.x_high,
             y_high: extent.y_high,
         };
-        self.top_right = Some(Box::new(QuadTree::new(nextent)));
+        self.top_right = Some(Box::new(Node::new(nextent)));
 
         let nextent = Extent {
             x_low: extent.x_low,
@@ -131,7 +835,7 @@ impl<T: Clone + Debug> QuadTree<T> {
             x_high: (extent.x_low + extent.x_high) / 2.0,
             y_high: (extent.y_high + extent.y_low) / 2.0,
         };
-        self.bot_left = Some(Box::new(QuadTree::new(nextent)));
+        self.bot_left = Some(Box::new(Node::new(nextent)));
 
         let nextent = Extent {
             x_low: (extent.x_low + extent.x_high) / 2.0,
@@ -139,110 +843,443 @@ impl<T: Clone + Debug> QuadTree<T> {
             x_high: extent.x_high,
             y_high: (extent.y_high + extent.y_low) / 2.0,
         };
-        self.bot_right = Some(Box::new(QuadTree::new(nextent)));
+        self.bot_right = Some(Box::new(Node::new(nextent)));
 
         self.has_children = true;
+
+        for (data, coord) in std::mem::take(&mut self.points) {
+            let quadrant = self.extent.quadrant(&coord);
+            self.child_mut(quadrant).points.push((data, coord));
+        }
     }
 
-    pub fn insert(&mut self, coord: &Coordinate, new_value: T) -> bool {
-        let mut node = self;
+    /// Turn the node back into a leaf when its children are leaves holding
+    /// no more than `capacity` coordinates between them
+    fn collapse(&mut self, capacity: usize) -> bool {
+        if !self.has_children {
+            return true;
+        }
+        if self.children().any(|child| child.has_children)
+            || self
+                .children()
+                .map(|child| child.points.len())
+                .sum::<usize>()
+                > capacity
+        {
+            return false;
+        }
 
-        //Prevent recursion for performance
-        loop {
-            //If there's no value but there are children, go to the children
-            if node.has_children {
-                let quadrant = node.extent.quadrant(coord);
-                let new_node = match quadrant {
-                    0 => node.bot_left.as_mut().unwrap(),
-                    1 => node.bot_right.as_mut().unwrap(),
-                    2 => node.top_left.as_mut().unwrap(),
-                    3 => node.top_right.as_mut().unwrap(),
-                    _ => panic!("Invalid quadrant"), //can't happen
-                };
-                node = new_node;
-            } else {
-                let (o_value, o_coord) = match node.value.clone() {
-                    Some(value) => value,
-                    None => {
-                        node.set_value(new_value.clone(), coord);
-                        break;
-                    }
-                };
+        for child in [
+            &mut self.bot_left,
+            &mut self.bot_right,
+            &mut self.top_left,
+            &mut self.top_right,
+        ] {
+            if let Some(child) = child.take() {
+                self.points.extend(child.points);
+            }
+        }
+        self.has_children = false;
+        true
+    }
 
-                if node.set_value(new_value.clone(), coord) {
-                    break;
-                }
+    fn child_mut(&mut self, quadrant: usize) -> &mut Node<T> {
+        match quadrant {
+            0 => self.bot_left.as_mut().unwrap(),
+            1 => self.bot_right.as_mut().unwrap(),
+            2 => self.top_left.as_mut().unwrap(),
+            3 => self.top_right.as_mut().unwrap(),
+            _ => panic!("Invalid quadrant"), //can't happen
+        }
+    }
 
-                node.divide();
-                let new_quadrant = node.extent.quadrant(&o_coord);
-                let new_node = match new_quadrant {
-                    0 => node.bot_left.as_mut().unwrap(),
-                    1 => node.bot_right.as_mut().unwrap(),
-                    2 => node.top_left.as_mut().unwrap(),
-                    3 => node.top_right.as_mut().unwrap(),
-                    _ => panic!("Invalid quadrant"), //can't happen
-                };
+    fn children(&self) -> impl Iterator<Item = &Node<T>> {
+        [
+            &self.bot_left,
+            &self.bot_right,
+            &self.top_left,
+            &self.top_right,
+        ]
+        .into_iter()
+        .flatten()
+        .map(|child| child.as_ref())
+    }
 
-                new_node.value = Some((o_value.to_owned(), o_coord.to_owned()));
-                node.value = None;
-            }
+    fn print(&self) {
+        println!("{:?}", self.extent);
+        for child in self.children() {
+            child.print();
         }
 
-        return true;
+        println!("{:?}", self.points);
+        println!();
     }
+}
 
-    pub fn find_bbox(&self, extent: &Extent) -> VecDeque<(T, Coordinate)> {
-        let mut result = VecDeque::new();
-        let mut stack = Vec::new();
-        stack.push(self);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        while let Some(node) = stack.pop() {
-            if node.extent.intersects(extent) {
-                if let Some(data) = &node.value {
-                    let (other, coord) = data.clone();
-                    let mut new_data = other.iter().map(|x| (x.to_owned(), coord.to_owned())).collect();
-                    result.append(&mut new_data);
-                }
+    fn coords(tree: &QuadTree<u32>) -> Vec<u32> {
+        let mut ids: Vec<u32> = tree
+            .find_bbox(&Extent::new(-180., -90., 180., 90.))
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        ids.sort_unstable();
+        ids
+    }
 
-                if node.has_children {
-                    if let Some(child) = &node.bot_left {
-                        stack.push(child);
-                    }
-                    if let Some(child) = &node.bot_right {
-                        stack.push(child);
-                    }
-                    if let Some(child) = &node.top_left {
-                        stack.push(child);
-                    }
-                    if let Some(child) = &node.top_right {
-                        stack.push(child);
-                    }
-                }
-            }
+    #[test]
+    fn insert_rejects_invalid_coordinates() {
+        let mut tree = QuadTree::new(Extent::new(0., 0., 0., 0.));
+        assert!(!tree.insert(&Coordinate::new(f64::NAN, 50.4), 0));
+        assert!(!tree.insert(&Coordinate::new(0., 0.), 1));
+        assert!(!tree.insert(&Coordinate::new(4.4, 91.), 2));
+        assert!(tree.insert(&Coordinate::new(4.4, 50.4), 3));
+        assert_eq!(coords(&tree), vec![3]);
+    }
+
+    #[test]
+    fn grow_resizes_a_leaf_root() {
+        let mut tree = QuadTree::new(Extent::new(0., 0., 0., 0.));
+        tree.insert(&Coordinate::new(4.4, 50.4), 0);
+        assert_eq!(
+            tree.root.extent,
+            Extent::new(4.4, 50.4, 4.4 + MIN_EXTENT_SIZE, 50.4 + MIN_EXTENT_SIZE)
+        );
+
+        tree.insert(&Coordinate::new(5.9, 49.5), 1);
+        assert_eq!(tree.root.extent, Extent::new(4.4, 49.5, 5.9, 50.4));
+        assert_eq!(tree.stats().nodes, 1);
+        assert_eq!(coords(&tree), vec![0, 1]);
+    }
+
+    #[test]
+    fn grow_doubles_a_split_root() {
+        let mut tree = QuadTree::with_limits(Extent::new(4., 50., 5., 51.), 1, DEFAULT_MAX_DEPTH);
+        tree.insert(&Coordinate::new(4.2, 50.2), 0);
+        tree.insert(&Coordinate::new(4.8, 50.8), 1);
+        assert!(tree.root.has_children);
+
+        // Twice the size towards the south west, the previous root being
+        // the north east quadrant
+        tree.insert(&Coordinate::new(3.5, 49.5), 2);
+        assert_eq!(tree.root.extent, Extent::new(3., 49., 5., 51.));
+        assert_eq!(
+            tree.root.top_right.as_ref().unwrap().extent,
+            Extent::new(4., 50., 5., 51.)
+        );
+
+        // Several doublings at once
+        tree.insert(&Coordinate::new(12., 55.), 3);
+        assert!(tree.root.extent.contains(&Coordinate::new(12., 55.)));
+        assert_eq!(coords(&tree), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn grow_a_root_split_on_a_line() {
+        let mut tree = QuadTree::with_limits(Extent::new(4., 50., 4., 51.), 1, DEFAULT_MAX_DEPTH);
+        tree.insert(&Coordinate::new(4., 50.2), 0);
+        tree.insert(&Coordinate::new(4., 50.7), 1);
+        assert!(tree.root.has_children);
+
+        tree.insert(&Coordinate::new(4.5, 50.5), 2);
+        assert!(tree.root.extent.contains(&Coordinate::new(4.5, 50.5)));
+        assert_eq!(coords(&tree), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn leaves_split_past_capacity() {
+        let mut tree = QuadTree::new(Extent::new(4., 50., 5., 51.));
+        for i in 0..DEFAULT_CAPACITY as u32 {
+            tree.insert(&Coordinate::new(4.1 + i as f64 * 0.1, 50.5), i);
         }
+        // Values sharing a coordinate take no room
+        tree.insert(&Coordinate::new(4.1, 50.5), 100);
+        assert_eq!(tree.stats().nodes, 1);
 
-        result
+        tree.insert(&Coordinate::new(4.05, 50.05), 200);
+        let stats = tree.stats();
+        assert_eq!(stats.nodes, 5);
+        assert_eq!(stats.points, DEFAULT_CAPACITY + 1);
+        assert_eq!(stats.values, DEFAULT_CAPACITY + 2);
     }
 
-    pub fn print(&self) {
-        println!("{:?}", self.extent);
-        if let Some(child) = &self.bot_left {
-            child.as_ref().print();
+    #[test]
+    fn leaves_past_max_depth_keep_growing() {
+        let mut tree = QuadTree::with_limits(Extent::new(4., 50., 5., 51.), 1, 3);
+        for i in 0..20 {
+            tree.insert(&Coordinate::new(4.6 + i as f64 * 1e-6, 50.6), i);
         }
 
-        if let Some(child) = &self.bot_right {
-            child.as_ref().print();
+        let stats = tree.stats();
+        assert_eq!(stats.depths.len(), 4);
+        assert_eq!(stats.points, 20);
+        assert_eq!(stats.points_per_leaf.len(), 21);
+        assert_eq!(stats.points_per_leaf[20], 1);
+        assert_eq!(coords(&tree), (0..20).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn remove_collapses_emptied_nodes() {
+        let mut tree = QuadTree::with_limits(Extent::new(4., 50., 5., 51.), 2, DEFAULT_MAX_DEPTH);
+        let points: Vec<Coordinate> = (0..6)
+            .map(|i| Coordinate::new(4.05 + i as f64 * 0.15, 50.05 + i as f64 * 0.15))
+            .collect();
+        for (i, coord) in points.iter().enumerate() {
+            tree.insert(coord, i as u32);
         }
+        tree.insert(&points[0], 10);
+        assert!(tree.stats().nodes > 1);
+
+        // Only the values accepted by the predicate
+        assert_eq!(tree.remove(&points[0], |v| *v == 10), vec![10]);
+        assert!(tree.remove(&points[0], |v| *v == 10).is_empty());
+        assert!(tree
+            .remove(&Coordinate::new(4.9, 50.1), |_| true)
+            .is_empty());
 
-        if let Some(child) = &self.top_left {
-            child.as_ref().print();
+        for (i, coord) in points.iter().enumerate().skip(2) {
+            assert_eq!(tree.remove(coord, |_| true), vec![i as u32]);
         }
+        let stats = tree.stats();
+        assert_eq!(stats.nodes, 1);
+        assert_eq!(stats.points, 2);
+        assert_eq!(coords(&tree), vec![0, 1]);
 
-        if let Some(child) = &self.top_right {
-            child.as_ref().print();
+        assert_eq!(tree.remove(&points[0], |_| true), vec![0]);
+        assert_eq!(tree.remove(&points[1], |_| true), vec![1]);
+        assert_eq!(tree.stats().nodes, 1);
+        assert!(coords(&tree).is_empty());
+    }
+
+    #[test]
+    fn update_moves_a_value() {
+        let mut tree = QuadTree::new(Extent::new(4., 50., 5., 51.));
+        let (old, new) = (Coordinate::new(4.2, 50.2), Coordinate::new(4.7, 50.7));
+        tree.insert(&old, 0);
+        tree.insert(&old, 1);
+
+        assert!(tree.update(&old, &new, 0));
+        assert_eq!(tree.find_bbox(&Extent::new(4.7, 50.7, 4.7, 50.7)).len(), 1);
+        assert_eq!(tree.find_bbox(&Extent::new(4.2, 50.2, 4.2, 50.2))[0].0, 1);
+
+        // Inserted when not at the old coordinate, untouched when the new
+        // one is invalid
+        assert!(tree.update(&old, &new, 2));
+        assert!(!tree.update(&new, &Coordinate::new(0., 0.), 2));
+        assert_eq!(coords(&tree), vec![0, 1, 2]);
+    }
+
+    const ORIGIN: (f64, f64) = (4.4, 50.4);
+
+    /// Values 0 to 9 every 0.01 degree north from `ORIGIN` on its meridian,
+    /// 10 to 19 every 0.01 degree east of it, in leaves small enough to split
+    fn cross() -> QuadTree<u32> {
+        let mut tree = QuadTree::with_limits(Extent::new(4.3, 50.3, 4.6, 50.6), 2, 8);
+        for i in 0..10 {
+            let step = i as f64 * 0.01;
+            tree.insert(&Coordinate::new(ORIGIN.0, ORIGIN.1 + step), i);
+            tree.insert(&Coordinate::new(ORIGIN.0 + step + 0.01, ORIGIN.1), 10 + i);
         }
+        tree
+    }
+
+    fn ids<P>(values: Vec<(u32, P)>) -> Vec<u32> {
+        values.into_iter().map(|(id, _)| id).collect()
+    }
+
+    fn nearest_ids(values: Vec<(u32, Coordinate, f64)>) -> Vec<u32> {
+        values.into_iter().map(|(id, _, _)| id).collect()
+    }
+
+    fn sorted(mut ids: Vec<u32>) -> Vec<u32> {
+        ids.sort_unstable();
+        ids
+    }
+
+    fn empty() -> QuadTree<u32> {
+        QuadTree::new(Extent::new(0., 0., 0., 0.))
+    }
+
+    #[test]
+    fn distances_along_a_meridian() {
+        let origin = Coordinate::new(ORIGIN.0, ORIGIN.1);
+        let north = Coordinate::new(ORIGIN.0, ORIGIN.1 + 0.01);
+        assert!((origin.distance(&north) - 0.01 * METERS_PER_DEGREE).abs() < 1e-6);
+
+        let extent = Extent::new(4.5, 50.5, 4.6, 50.6);
+        assert_eq!(extent.distance(&Coordinate::new(4.55, 50.55)), 0.);
+        assert_eq!(extent.distance(&Coordinate::new(4.5, 50.5)), 0.);
+        assert!(
+            (extent.distance(&Coordinate::new(4.55, 50.4)) - 0.1 * METERS_PER_DEGREE).abs() < 1e-6
+        );
+    }
+
+    #[test]
+    fn extent_distance_is_a_lower_bound() {
+        let extent = Extent::new(4.5, 50.3, 4.6, 50.5);
+        for coord in [
+            Coordinate::new(4.2, 50.4),
+            Coordinate::new(4.9, 50.1),
+            Coordinate::new(3.0, 55.0),
+            Coordinate::new(4.55, 50.9),
+        ] {
+            // Closest of a dense sampling of the border
+            let closest = (0..=1000)
+                .flat_map(|i| {
+                    let t = i as f64 / 1000.;
+                    let x = extent.x_low + t * (extent.x_high - extent.x_low);
+                    let y = extent.y_low + t * (extent.y_high - extent.y_low);
+                    [
+                        Coordinate::new(x, extent.y_low),
+                        Coordinate::new(x, extent.y_high),
+                        Coordinate::new(extent.x_low, y),
+                        Coordinate::new(extent.x_high, y),
+                    ]
+                })
+                .map(|point| coord.distance(&point))
+                .fold(f64::INFINITY, f64::min);
+            let distance = extent.distance(&coord);
+            assert!(distance <= closest + 1e-6, "{:?}", coord);
+            assert!(closest - distance < 5., "{:?}", coord);
+        }
+    }
+
+    #[test]
+    fn nearest_closest_first() {
+        let tree = cross();
+        let origin = Coordinate::new(ORIGIN.0, ORIGIN.1);
+
+        let found = tree.find_nearest(&origin, 3, f64::INFINITY);
+        assert_eq!(found[0].0, 0);
+        assert_eq!(found[0].2, 0.);
+        // A degree of longitude is shorter than a degree of latitude
+        assert_eq!(nearest_ids(found), vec![0, 10, 1]);
+
+        let found = tree.find_nearest(&origin, 100, f64::INFINITY);
+        assert_eq!(found.len(), 20);
+        assert!(found.windows(2).all(|w| w[0].2 <= w[1].2));
+
+        // Borders are included
+        let north = 0.03 * METERS_PER_DEGREE;
+        let found = tree.find_nearest_by(&origin, 100, north + 1e-6, |id| *id < 10);
+        assert_eq!(nearest_ids(found), vec![0, 1, 2, 3]);
+
+        assert!(tree.find_nearest(&origin, 0, f64::INFINITY).is_empty());
+        assert!(empty().find_nearest(&origin, 5, f64::INFINITY).is_empty());
+    }
+
+    #[test]
+    fn radius_includes_its_border() {
+        let tree = cross();
+        let origin = Coordinate::new(ORIGIN.0, ORIGIN.1);
+        let radius = origin.distance(&Coordinate::new(ORIGIN.0, ORIGIN.1 + 0.02));
+
+        let found = tree.find_radius(&origin, radius);
+        assert!(found.windows(2).all(|w| w[0].2 <= w[1].2));
+        let expected: Vec<u32> = vec![0, 1, 2, 10, 11, 12];
+        assert_eq!(sorted(nearest_ids(found)), expected);
+
+        assert_eq!(nearest_ids(tree.find_radius(&origin, 0.)), vec![0]);
+        assert!(empty().find_radius(&origin, 1_000.).is_empty());
+    }
+
+    #[test]
+    fn bbox_includes_its_border() {
+        let tree = cross();
+        let extent = Extent::new(ORIGIN.0, ORIGIN.1, ORIGIN.0 + 0.02, ORIGIN.1 + 0.01);
+        assert_eq!(sorted(ids(tree.find_bbox(&extent))), vec![0, 1, 10, 11]);
+        assert!(empty().find_bbox(&extent).is_empty());
+    }
+
+    #[test]
+    fn polygon_with_a_hole() {
+        let tree = cross();
+        let rectangle = |x: f64, y: f64, width: f64, height: f64| {
+            vec![
+                Coordinate::new(x, y),
+                Coordinate::new(x + width, y),
+                Coordinate::new(x + width, y + height),
+                Coordinate::new(x, y + height),
+            ]
+        };
+        let polygon = Polygon::new(vec![
+            rectangle(4.395, 50.395, 0.07, 0.07),
+            rectangle(4.395, 50.425, 0.01, 0.01),
+        ]);
+        assert_eq!(
+            sorted(ids(tree.find_polygon(&polygon))),
+            vec![0, 1, 2, 4, 5, 6, 10, 11, 12, 13, 14, 15]
+        );
+
+        // A point on the border shared by two polygons is in exactly one
+        let west = Polygon::new(vec![rectangle(4.3, 50.35, 0.1, 0.2)]);
+        let east = Polygon::new(vec![rectangle(4.4, 50.35, 0.005, 0.2)]);
+        let on_border = sorted(
+            [west, east]
+                .iter()
+                .flat_map(|polygon| ids(tree.find_polygon(polygon)))
+                .collect(),
+        );
+        assert_eq!(on_border, (0..10).collect::<Vec<_>>());
+
+        assert!(tree.find_polygon(&Polygon::new(Vec::new())).is_empty());
+        assert!(empty().find_polygon(&polygon).is_empty());
+    }
+
+    #[test]
+    fn corridor_along_lines() {
+        let tree = cross();
+        // Parallel to the eastern branch, 0.005 degree north of it
+        let mut lines = vec![vec![
+            Coordinate::new(4.425, ORIGIN.1 + 0.005),
+            Coordinate::new(4.455, ORIGIN.1 + 0.005),
+        ]];
+        let gap = 0.005 * METERS_PER_DEGREE;
+
+        let found = tree.find_corridor(&lines, gap + 1.);
+        assert!(found.windows(2).all(|w| w[0].2 <= w[1].2));
+        assert!(found
+            .iter()
+            .all(|(_, _, distance)| (distance - gap).abs() < 1.));
+        assert_eq!(sorted(nearest_ids(found)), vec![12, 13, 14]);
+        assert!(tree.find_corridor(&lines, gap - 1.).is_empty());
+
+        // A lone coordinate is a point to be near of
+        lines.push(vec![Coordinate::new(ORIGIN.0, ORIGIN.1 + 0.05)]);
+        let found = tree.find_corridor(&lines, 1.);
+        assert_eq!(nearest_ids(found), vec![5]);
+
+        assert!(tree.find_corridor(&[], 1_000.).is_empty());
+        let everywhere = vec![Coordinate::new(4.4, 50.4), Coordinate::new(4.5, 50.5)];
+        assert!(empty().find_corridor(&[everywhere], 1_000.).is_empty());
+    }
+
+    #[test]
+    fn clusters_by_cell() {
+        let tree = cross();
+        let extent = Extent::new(4.3, 50.3, 4.6, 50.6);
+
+        // Cells larger than the tree: one cluster of everything
+        let clusters = tree.find_clusters(&extent, 1., 1., 2, |_| true);
+        assert_eq!(clusters.len(), 1);
+        assert_eq!(clusters[0].count, 20);
+        assert_eq!(clusters[0].values.len(), 2);
+        assert_eq!(clusters[0].extent, Extent::new(4.4, 50.4, 4.5, 50.49));
+
+        // Cells smaller than any leaf: one cluster per coordinate
+        let clusters = tree.find_clusters(&extent, 1e-9, 1e-9, 2, |_| true);
+        assert_eq!(clusters.len(), 20);
+        assert!(clusters.iter().all(|cluster| cluster.count == 1));
 
-        println!("{:?}", self.value);
-        println!("");
+        let clusters = tree.find_clusters(&extent, 1e-9, 1e-9, 2, |id| *id >= 10);
+        assert_eq!(clusters.len(), 10);
+        let outside = Extent::new(5., 51., 6., 52.);
+        assert!(tree.find_clusters(&outside, 1., 1., 2, |_| true).is_empty());
+        assert!(empty()
+            .find_clusters(&extent, 1., 1., 2, |_| true)
+            .is_empty());
     }
 }
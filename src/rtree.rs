@@ -0,0 +1,678 @@
+use std::{collections::BinaryHeap, mem::size_of};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    quadtree::{Coordinate, Extent, Polygon},
+    spatial::{Candidate, Cluster, Item, SpatialIndex},
+};
+
+/// Entries a node holds before splitting
+pub const DEFAULT_NODE_SIZE: usize = 16;
+
+/// Point R-tree, packed with Sort-Tile-Recursive when bulk loaded so nodes
+/// are full and barely overlap. Later inserts go down the child growing the
+/// least and split full nodes in two along their longest side.
+#[derive(Serialize, Deserialize)]
+pub struct RTree<T> {
+    root: Node<T>,
+    node_size: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Node<T> {
+    /// Bounding box of the entries, meaningless for an empty root
+    extent: Extent,
+    entries: Entries<T>,
+}
+
+#[derive(Serialize, Deserialize)]
+enum Entries<T> {
+    Leaf(Vec<(T, Coordinate)>),
+    Branch(Vec<Node<T>>),
+}
+
+impl<T: Clone> RTree<T> {
+    pub fn new(node_size: usize) -> Self {
+        Self {
+            root: Node::leaf(Vec::new()),
+            node_size: node_size.max(2),
+        }
+    }
+
+    /// Tree packing `values`, skipping those whose coordinate is not a
+    /// location, see [`Coordinate::is_valid`]
+    pub fn bulk_load(values: Vec<(T, Coordinate)>, node_size: usize) -> Self {
+        let node_size = node_size.max(2);
+        let values: Vec<_> = values.into_iter().filter(|(_, c)| c.is_valid()).collect();
+
+        let mut level: Vec<Node<T>> = pack(values, node_size, |(_, c)| (c.x, c.y))
+            .into_iter()
+            .map(Node::leaf)
+            .collect();
+        while level.len() > 1 {
+            level = pack(level, node_size, |node| node.extent.center())
+                .into_iter()
+                .map(Node::branch)
+                .collect();
+        }
+
+        Self {
+            root: level.pop().unwrap_or_else(|| Node::leaf(Vec::new())),
+            node_size,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        let mut len = 0;
+        let mut stack = vec![&self.root];
+        while let Some(node) = stack.pop() {
+            match &node.entries {
+                Entries::Leaf(values) => len += values.len(),
+                Entries::Branch(children) => stack.extend(children),
+            }
+        }
+        len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        matches!(&self.root.entries, Entries::Leaf(values) if values.is_empty())
+    }
+
+    /// Number of levels, a lone leaf being 1
+    pub fn height(&self) -> usize {
+        let mut height = 1;
+        let mut node = &self.root;
+        while let Entries::Branch(children) = &node.entries {
+            node = &children[0];
+            height += 1;
+        }
+        height
+    }
+}
+
+impl<T: Clone> SpatialIndex<T> for RTree<T> {
+    fn insert(&mut self, coord: &Coordinate, value: T) -> bool {
+        if !coord.is_valid() {
+            return false;
+        }
+        if self.is_empty() {
+            self.root.extent = Extent::new(coord.x, coord.y, coord.x, coord.y);
+        }
+
+        if let Some(sibling) = self.root.insert(coord, value, self.node_size) {
+            let root = std::mem::replace(&mut self.root, Node::leaf(Vec::new()));
+            self.root = Node::branch(vec![root, sibling]);
+        }
+        true
+    }
+
+    fn find_bbox(&self, extent: &Extent) -> Vec<(T, Coordinate)> {
+        let mut result = Vec::new();
+        let mut stack = vec![&self.root];
+
+        while let Some(node) = stack.pop() {
+            if !node.extent.intersects(extent) {
+                continue;
+            }
+            match &node.entries {
+                Entries::Leaf(values) => result.extend(
+                    values
+                        .iter()
+                        .filter(|(_, point)| extent.contains(point))
+                        .cloned(),
+                ),
+                Entries::Branch(children) => stack.extend(children),
+            }
+        }
+
+        result
+    }
+
+    fn find_radius(&self, coord: &Coordinate, radius: f64) -> Vec<(T, Coordinate, f64)> {
+        let mut result = Vec::new();
+        let mut stack = vec![&self.root];
+
+        while let Some(node) = stack.pop() {
+            if node.extent.distance(coord) > radius {
+                continue;
+            }
+            match &node.entries {
+                Entries::Leaf(values) => {
+                    for (value, point) in values {
+                        let distance = coord.distance(point);
+                        if distance <= radius {
+                            result.push((value.clone(), point.clone(), distance));
+                        }
+                    }
+                }
+                Entries::Branch(children) => stack.extend(children),
+            }
+        }
+
+        result.sort_by(|a, b| a.2.total_cmp(&b.2));
+        result
+    }
+
+    fn find_nearest_by(
+        &self,
+        coord: &Coordinate,
+        k: usize,
+        max_distance: f64,
+        filter: impl Fn(&T) -> bool,
+    ) -> Vec<(T, Coordinate, f64)> {
+        let mut result = Vec::new();
+        if k == 0 {
+            return result;
+        }
+
+        // Best-first, see the quadtree
+        let mut queue = BinaryHeap::new();
+        queue.push(Candidate {
+            distance: self.root.extent.distance(coord),
+            item: Item::Node(&self.root),
+        });
+
+        while let Some(Candidate { distance, item }) = queue.pop() {
+            if distance > max_distance {
+                break;
+            }
+
+            match item {
+                Item::Point((value, point)) => {
+                    result.push((T::clone(value), Coordinate::clone(point), distance));
+                    if result.len() == k {
+                        break;
+                    }
+                }
+                Item::Node(node) => match &node.entries {
+                    Entries::Leaf(values) => {
+                        for (value, point) in values.iter().filter(|(v, _)| filter(v)) {
+                            queue.push(Candidate {
+                                distance: coord.distance(point),
+                                item: Item::Point((value, point)),
+                            });
+                        }
+                    }
+                    Entries::Branch(children) => {
+                        for child in children {
+                            queue.push(Candidate {
+                                distance: child.extent.distance(coord),
+                                item: Item::Node(child),
+                            });
+                        }
+                    }
+                },
+            }
+        }
+
+        result
+    }
+
+    fn find_polygon(&self, polygon: &Polygon) -> Vec<(T, Coordinate)> {
+        let extent = match polygon.extent() {
+            Some(extent) => extent,
+            None => return Vec::new(),
+        };
+        self.find_bbox(&extent)
+            .into_iter()
+            .filter(|(_, point)| polygon.contains(point))
+            .collect()
+    }
+
+    fn find_corridor(&self, lines: &[Vec<Coordinate>], buffer: f64) -> Vec<(T, Coordinate, f64)> {
+        let segments: Vec<(&Coordinate, &Coordinate, Extent)> = lines
+            .iter()
+            .flat_map(|line| match line.len() {
+                1 => vec![(&line[0], &line[0])],
+                _ => line.windows(2).map(|w| (&w[0], &w[1])).collect(),
+            })
+            .map(|(a, b)| {
+                let extent = Extent::new(a.x, a.y, a.x, a.y).expand(b).buffer(buffer);
+                (a, b, extent)
+            })
+            .collect();
+
+        let mut result = Vec::new();
+        let mut stack = vec![&self.root];
+
+        while let Some(node) = stack.pop() {
+            if !segments
+                .iter()
+                .any(|(_, _, extent)| extent.intersects(&node.extent))
+            {
+                continue;
+            }
+            match &node.entries {
+                Entries::Leaf(values) => {
+                    for (value, point) in values {
+                        let distance = segments
+                            .iter()
+                            .filter(|(_, _, extent)| extent.contains(point))
+                            .map(|(a, b, _)| point.closest_on_segment(a, b).1)
+                            .fold(f64::INFINITY, f64::min);
+                        if distance <= buffer {
+                            result.push((value.clone(), point.clone(), distance));
+                        }
+                    }
+                }
+                Entries::Branch(children) => stack.extend(children),
+            }
+        }
+
+        result.sort_by(|a, b| a.2.total_cmp(&b.2));
+        result
+    }
+
+    /// Values of leaves larger than a cell are returned one by one
+    fn find_clusters(
+        &self,
+        extent: &Extent,
+        width: f64,
+        height: f64,
+        representatives: usize,
+        filter: impl Fn(&T) -> bool,
+    ) -> Vec<Cluster<T>> {
+        let mut clusters = Vec::new();
+        let mut stack = vec![&self.root];
+
+        while let Some(node) = stack.pop() {
+            if !node.extent.intersects(extent) {
+                continue;
+            }
+
+            let fits = node.extent.x_high - node.extent.x_low <= width
+                && node.extent.y_high - node.extent.y_low <= height;
+            if fits {
+                let mut values = Vec::new();
+                let mut subtree = vec![node];
+                while let Some(node) = subtree.pop() {
+                    match &node.entries {
+                        Entries::Leaf(entries) => values.extend(
+                            entries
+                                .iter()
+                                .filter(|(v, p)| extent.contains(p) && filter(v))
+                                .map(|(v, p)| (v, p)),
+                        ),
+                        Entries::Branch(children) => subtree.extend(children),
+                    }
+                }
+                clusters.extend(Cluster::build(values, representatives));
+                continue;
+            }
+
+            match &node.entries {
+                Entries::Leaf(entries) => {
+                    for (value, point) in entries {
+                        if extent.contains(point) && filter(value) {
+                            clusters.extend(Cluster::build(vec![(value, point)], representatives));
+                        }
+                    }
+                }
+                Entries::Branch(children) => stack.extend(children),
+            }
+        }
+
+        clusters
+    }
+
+    fn memory_usage(&self, value_size: impl Fn(&T) -> usize) -> usize {
+        let mut size = size_of::<Node<T>>();
+        let mut stack = vec![&self.root];
+
+        while let Some(node) = stack.pop() {
+            match &node.entries {
+                Entries::Leaf(values) => {
+                    size += values.capacity() * size_of::<(T, Coordinate)>()
+                        - values.len() * size_of::<T>();
+                    size += values.iter().map(|(v, _)| value_size(v)).sum::<usize>();
+                }
+                Entries::Branch(children) => {
+                    size += children.capacity() * size_of::<Node<T>>();
+                    stack.extend(children);
+                }
+            }
+        }
+
+        size
+    }
+}
+
+impl<T: Clone> Node<T> {
+    fn leaf(values: Vec<(T, Coordinate)>) -> Self {
+        Self {
+            extent: Extent::bounding(values.iter().map(|(_, c)| c))
+                .unwrap_or_else(|| Extent::new(0.0, 0.0, 0.0, 0.0)),
+            entries: Entries::Leaf(values),
+        }
+    }
+
+    fn branch(children: Vec<Node<T>>) -> Self {
+        let mut extent = children[0].extent;
+        for child in &children[1..] {
+            extent = extent.union(&child.extent);
+        }
+        Self {
+            extent,
+            entries: Entries::Branch(children),
+        }
+    }
+
+    /// Insert in the subtree, returning the new sibling of the node when it
+    /// had to split
+    fn insert(&mut self, coord: &Coordinate, value: T, node_size: usize) -> Option<Node<T>> {
+        self.extent = self.extent.expand(coord);
+
+        let full = match &mut self.entries {
+            Entries::Leaf(values) => {
+                values.push((value, coord.clone()));
+                values.len() > node_size
+            }
+            Entries::Branch(children) => {
+                // Child growing the least, then the smallest
+                let best = (0..children.len())
+                    .min_by(|&a, &b| {
+                        let (a, b) = (&children[a].extent, &children[b].extent);
+                        let growth = |e: &Extent| e.expand(coord).area() - e.area();
+                        growth(a)
+                            .total_cmp(&growth(b))
+                            .then_with(|| a.area().total_cmp(&b.area()))
+                    })
+                    .unwrap();
+                if let Some(sibling) = children[best].insert(coord, value, node_size) {
+                    children.push(sibling);
+                }
+                children.len() > node_size
+            }
+        };
+
+        if full {
+            Some(self.split())
+        } else {
+            None
+        }
+    }
+
+    /// Keep the lower half of the entries along the longest side of the
+    /// extent and return a node with the upper half
+    fn split(&mut self) -> Node<T> {
+        let horizontal =
+            self.extent.x_high - self.extent.x_low >= self.extent.y_high - self.extent.y_low;
+        let axis = |(x, y): (f64, f64)| if horizontal { x } else { y };
+
+        let entries = std::mem::replace(&mut self.entries, Entries::Leaf(Vec::new()));
+        let (low, high) = match entries {
+            Entries::Leaf(mut values) => {
+                values.sort_by(|a, b| axis((a.1.x, a.1.y)).total_cmp(&axis((b.1.x, b.1.y))));
+                let high = values.split_off(values.len() / 2);
+                (Node::leaf(values), Node::leaf(high))
+            }
+            Entries::Branch(mut children) => {
+                children
+                    .sort_by(|a, b| axis(a.extent.center()).total_cmp(&axis(b.extent.center())));
+                let high = children.split_off(children.len() / 2);
+                (Node::branch(children), Node::branch(high))
+            }
+        };
+
+        *self = low;
+        high
+    }
+}
+
+/// Sort-Tile-Recursive: sort by x into vertical slices of about
+/// `sqrt(n / node_size)` nodes, then by y within each slice, and cut
+/// groups of `node_size`
+fn pack<E>(mut items: Vec<E>, node_size: usize, center: impl Fn(&E) -> (f64, f64)) -> Vec<Vec<E>> {
+    let groups = items.len().div_ceil(node_size);
+    let slices = (groups as f64).sqrt().ceil() as usize;
+    let slice_len = slices.max(1) * node_size;
+
+    items.sort_by(|a, b| center(a).0.total_cmp(&center(b).0));
+    let mut packed = Vec::with_capacity(groups);
+    let mut items = items.into_iter();
+    loop {
+        let mut slice: Vec<E> = items.by_ref().take(slice_len).collect();
+        if slice.is_empty() {
+            break;
+        }
+        slice.sort_by(|a, b| center(a).1.total_cmp(&center(b).1));
+
+        let mut slice = slice.into_iter();
+        loop {
+            let group: Vec<E> = slice.by_ref().take(node_size).collect();
+            if group.is_empty() {
+                break;
+            }
+            packed.push(group);
+        }
+    }
+    packed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quadtree::QuadTree;
+
+    /// Points around Charleroi from a fixed LCG, every tenth one sharing
+    /// the coordinate of the previous
+    fn points(count: u32) -> Vec<(u32, Coordinate)> {
+        let mut state: u64 = 42;
+        let mut next = || {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (state >> 11) as f64 / (1u64 << 53) as f64
+        };
+
+        let mut points: Vec<(u32, Coordinate)> = Vec::new();
+        for id in 0..count {
+            let coord = match points.last() {
+                Some((_, last)) if id % 10 == 0 => last.clone(),
+                _ => Coordinate::new(4.3 + next() * 0.3, 50.3 + next() * 0.2),
+            };
+            points.push((id, coord));
+        }
+        points
+    }
+
+    /// Quadtree, bulk loaded R-tree and R-tree built by inserts over the
+    /// same points
+    fn indexes(count: u32) -> (QuadTree<u32>, RTree<u32>, RTree<u32>) {
+        let points = points(count);
+        let mut quadtree = QuadTree::new(Extent::new(0., 0., 0., 0.));
+        let mut inserted = RTree::new(DEFAULT_NODE_SIZE);
+        for (id, coord) in points.iter() {
+            assert!(quadtree.insert(coord, *id));
+            assert!(inserted.insert(coord, *id));
+        }
+        let packed = RTree::bulk_load(points, DEFAULT_NODE_SIZE);
+        (quadtree, packed, inserted)
+    }
+
+    fn ids<P>(values: Vec<(u32, P)>) -> Vec<u32> {
+        let mut ids: Vec<u32> = values.into_iter().map(|(id, _)| id).collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    fn ids_with_distance(values: Vec<(u32, Coordinate, f64)>) -> Vec<u32> {
+        ids(values.into_iter().map(|(id, _, d)| (id, d)).collect())
+    }
+
+    fn distances(values: &[(u32, Coordinate, f64)]) -> Vec<f64> {
+        values.iter().map(|(_, _, distance)| *distance).collect()
+    }
+
+    fn queries() -> Vec<Coordinate> {
+        vec![
+            Coordinate::new(4.45, 50.4),
+            Coordinate::new(4.3, 50.3),
+            Coordinate::new(4.61, 50.52),
+            Coordinate::new(5.5, 51.0),
+        ]
+    }
+
+    #[test]
+    fn bulk_load_packs_full_nodes() {
+        let (_, packed, _) = indexes(1000);
+        assert_eq!(packed.len(), 1000);
+        // 63 leaves, 4 branches and the root
+        assert_eq!(packed.height(), 3);
+
+        let empty: RTree<u32> = RTree::bulk_load(Vec::new(), DEFAULT_NODE_SIZE);
+        assert!(empty.is_empty());
+        assert_eq!(empty.height(), 1);
+
+        let invalid = RTree::bulk_load(
+            vec![
+                (0, Coordinate::new(0., 0.)),
+                (1, Coordinate::new(4.4, 50.4)),
+            ],
+            DEFAULT_NODE_SIZE,
+        );
+        assert_eq!(invalid.len(), 1);
+    }
+
+    #[test]
+    fn bbox_matches_quadtree() {
+        let (quadtree, packed, inserted) = indexes(1000);
+        let extents = [
+            Extent::new(4.4, 50.35, 4.5, 50.45),
+            Extent::new(4.0, 50.0, 5.0, 51.0),
+            Extent::new(6.0, 50.0, 7.0, 51.0),
+        ];
+        for extent in extents.iter() {
+            let expected = ids(quadtree.find_bbox(extent));
+            assert_eq!(ids(packed.find_bbox(extent)), expected);
+            assert_eq!(ids(inserted.find_bbox(extent)), expected);
+        }
+
+        // Borders are included
+        let (_, coord) = &points(1000)[500];
+        let extent = Extent::new(coord.x, coord.y, coord.x, coord.y);
+        assert!(!packed.find_bbox(&extent).is_empty());
+        assert_eq!(
+            ids(packed.find_bbox(&extent)),
+            ids(quadtree.find_bbox(&extent))
+        );
+    }
+
+    #[test]
+    fn radius_matches_quadtree() {
+        let (quadtree, packed, inserted) = indexes(1000);
+        for coord in queries().iter() {
+            for radius in [0., 500., 2_000., 50_000.] {
+                let expected = quadtree.find_radius(coord, radius);
+                for tree in [&packed, &inserted] {
+                    let found = tree.find_radius(coord, radius);
+                    assert_eq!(distances(&found), distances(&expected));
+                    assert_eq!(
+                        ids_with_distance(found),
+                        ids_with_distance(expected.clone())
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn nearest_matches_quadtree() {
+        let (quadtree, packed, inserted) = indexes(1000);
+        for coord in queries().iter() {
+            for (k, max_distance) in [(1, f64::INFINITY), (25, f64::INFINITY), (25, 1_000.)] {
+                let expected = quadtree.find_nearest(coord, k, max_distance);
+                for tree in [&packed, &inserted] {
+                    // Values sharing a coordinate tie, only distances are
+                    // certain to match
+                    assert_eq!(
+                        distances(&tree.find_nearest(coord, k, max_distance)),
+                        distances(&expected)
+                    );
+                }
+            }
+
+            // More than the tree holds
+            let expected = quadtree.find_nearest(coord, 5000, f64::INFINITY);
+            assert_eq!(expected.len(), 1000);
+            for tree in [&packed, &inserted] {
+                let found = tree.find_nearest(coord, 5000, f64::INFINITY);
+                assert_eq!(distances(&found), distances(&expected));
+                assert_eq!(
+                    ids_with_distance(found),
+                    ids_with_distance(expected.clone())
+                );
+            }
+        }
+
+        let first_half = |id: &u32| *id < 500;
+        let coord = &queries()[0];
+        let expected = quadtree.find_nearest_by(coord, 5000, f64::INFINITY, first_half);
+        let found = packed.find_nearest_by(coord, 5000, f64::INFINITY, first_half);
+        assert_eq!(expected.len(), 500);
+        assert_eq!(ids_with_distance(found), ids_with_distance(expected));
+
+        let empty: RTree<u32> = RTree::new(DEFAULT_NODE_SIZE);
+        assert!(empty.find_nearest(coord, 3, f64::INFINITY).is_empty());
+        assert!(packed.find_nearest(coord, 0, f64::INFINITY).is_empty());
+    }
+
+    #[test]
+    fn polygon_and_corridor_match_quadtree() {
+        let (quadtree, packed, inserted) = indexes(1000);
+        let polygon = Polygon::new(vec![
+            vec![
+                Coordinate::new(4.35, 50.32),
+                Coordinate::new(4.55, 50.34),
+                Coordinate::new(4.5, 50.48),
+                Coordinate::new(4.38, 50.45),
+            ],
+            // Hole
+            vec![
+                Coordinate::new(4.42, 50.38),
+                Coordinate::new(4.48, 50.38),
+                Coordinate::new(4.45, 50.42),
+            ],
+        ]);
+        let expected = ids(quadtree.find_polygon(&polygon));
+        assert!(!expected.is_empty());
+        assert_eq!(ids(packed.find_polygon(&polygon)), expected);
+        assert_eq!(ids(inserted.find_polygon(&polygon)), expected);
+
+        let lines = vec![
+            vec![
+                Coordinate::new(4.3, 50.3),
+                Coordinate::new(4.45, 50.4),
+                Coordinate::new(4.6, 50.42),
+            ],
+            vec![Coordinate::new(4.5, 50.5)],
+        ];
+        for buffer in [0., 300., 1_500.] {
+            let expected = quadtree.find_corridor(&lines, buffer);
+            for tree in [&packed, &inserted] {
+                let found = tree.find_corridor(&lines, buffer);
+                assert_eq!(distances(&found), distances(&expected));
+                assert_eq!(
+                    ids_with_distance(found),
+                    ids_with_distance(expected.clone())
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn clusters_cover_the_bbox() {
+        let (quadtree, packed, _) = indexes(1000);
+        let extent = Extent::new(4.35, 50.32, 4.55, 50.48);
+        let expected = quadtree.find_bbox(&extent).len();
+
+        for clusters in [
+            quadtree.find_clusters(&extent, 0.05, 0.05, 3, |_| true),
+            packed.find_clusters(&extent, 0.05, 0.05, 3, |_| true),
+        ] {
+            assert_eq!(clusters.iter().map(|c| c.count).sum::<usize>(), expected);
+            assert!(clusters.iter().all(|c| c.values.len() <= 3));
+        }
+    }
+}
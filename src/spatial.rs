@@ -0,0 +1,216 @@
+use std::{cmp::Ordering, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::quadtree::{Coordinate, Extent, Polygon};
+
+/// Point index queried by the API, implemented by [`QuadTree`] and
+/// [`RTree`]. Distances are great-circle distances in meters.
+///
+/// [`QuadTree`]: crate::quadtree::QuadTree
+/// [`RTree`]: crate::rtree::RTree
+pub trait SpatialIndex<T: Clone> {
+    /// Insert `value` at `coord`. Returns false and leaves the index
+    /// untouched when the coordinate is not a location, see
+    /// [`Coordinate::is_valid`].
+    fn insert(&mut self, coord: &Coordinate, value: T) -> bool;
+
+    /// Values inside `extent`, borders included
+    fn find_bbox(&self, extent: &Extent) -> Vec<(T, Coordinate)>;
+
+    /// Values within `radius` meters of `coord` with their distance,
+    /// closest first
+    fn find_radius(&self, coord: &Coordinate, radius: f64) -> Vec<(T, Coordinate, f64)>;
+
+    /// The `k` values closest to `coord` within `max_distance` meters that
+    /// `filter` accepts, closest first
+    fn find_nearest_by(
+        &self,
+        coord: &Coordinate,
+        k: usize,
+        max_distance: f64,
+        filter: impl Fn(&T) -> bool,
+    ) -> Vec<(T, Coordinate, f64)>;
+
+    /// Values inside `polygon`
+    fn find_polygon(&self, polygon: &Polygon) -> Vec<(T, Coordinate)>;
+
+    /// Values within `buffer` meters of one of the `lines`, with their
+    /// distance to the closest one, closest first
+    fn find_corridor(&self, lines: &[Vec<Coordinate>], buffer: f64) -> Vec<(T, Coordinate, f64)>;
+
+    /// Values inside `extent` accepted by `filter`, grouped by the nodes
+    /// that fit in a `width` by `height` cell, in degrees. Each cluster
+    /// keeps the `representatives` values closest to its centroid.
+    fn find_clusters(
+        &self,
+        extent: &Extent,
+        width: f64,
+        height: f64,
+        representatives: usize,
+        filter: impl Fn(&T) -> bool,
+    ) -> Vec<Cluster<T>>;
+
+    /// Approximate heap size of the index, `value_size` being the size of
+    /// one value including its own heap allocations
+    fn memory_usage(&self, value_size: impl Fn(&T) -> usize) -> usize;
+
+    /// The `k` values closest to `coord` within `max_distance` meters
+    fn find_nearest(
+        &self,
+        coord: &Coordinate,
+        k: usize,
+        max_distance: f64,
+    ) -> Vec<(T, Coordinate, f64)> {
+        self.find_nearest_by(coord, k, max_distance, |_| true)
+    }
+
+    /// Values inside `extent` accepted by `filter`, ordered by value so a
+    /// client can page through them with `offset` and `limit`
+    fn find_bbox_page(
+        &self,
+        extent: &Extent,
+        offset: usize,
+        limit: Option<usize>,
+        filter: impl Fn(&T) -> bool,
+    ) -> BboxPage<T>
+    where
+        T: Ord,
+    {
+        let mut values: Vec<(T, Coordinate)> = self
+            .find_bbox(extent)
+            .into_iter()
+            .filter(|(value, _)| filter(value))
+            .collect();
+        values.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let total = values.len();
+        let mut values: Vec<_> = values.into_iter().skip(offset).collect();
+        if let Some(limit) = limit {
+            values.truncate(limit);
+        }
+
+        BboxPage {
+            truncated: offset + values.len() < total,
+            values,
+            total,
+        }
+    }
+}
+
+/// Implementation of a [`SpatialIndex`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SpatialIndexKind {
+    QuadTree,
+    RTree,
+}
+
+impl SpatialIndexKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SpatialIndexKind::QuadTree => "quadtree",
+            SpatialIndexKind::RTree => "rtree",
+        }
+    }
+}
+
+impl FromStr for SpatialIndexKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "quadtree" => Ok(SpatialIndexKind::QuadTree),
+            "rtree" => Ok(SpatialIndexKind::RTree),
+            other => Err(format!("unknown spatial index {:?}", other)),
+        }
+    }
+}
+
+/// One page of [`SpatialIndex::find_bbox_page`]
+pub struct BboxPage<T> {
+    pub values: Vec<(T, Coordinate)>,
+    /// Number of values in the box, all pages included
+    pub total: usize,
+    /// Whether values come after this page
+    pub truncated: bool,
+}
+
+/// Values of one node of [`SpatialIndex::find_clusters`]
+#[derive(Debug)]
+pub struct Cluster<T> {
+    pub centroid: Coordinate,
+    pub count: usize,
+    /// Bounding box of the values
+    pub extent: Extent,
+    /// Values closest to the centroid, closest first
+    pub values: Vec<(T, Coordinate)>,
+}
+
+impl<T: Clone> Cluster<T> {
+    pub(crate) fn build(values: Vec<(&T, &Coordinate)>, representatives: usize) -> Option<Self> {
+        let extent = Extent::bounding(values.iter().map(|(_, point)| *point))?;
+        let count = values.len();
+        let centroid = Coordinate::new(
+            values.iter().map(|(_, point)| point.x).sum::<f64>() / count as f64,
+            values.iter().map(|(_, point)| point.y).sum::<f64>() / count as f64,
+        );
+
+        let mut values: Vec<(&T, &Coordinate, f64)> = values
+            .into_iter()
+            .map(|(value, point)| (value, point, centroid.distance(point)))
+            .collect();
+        values.sort_by(|a, b| a.2.total_cmp(&b.2));
+        let values = values
+            .into_iter()
+            .take(representatives)
+            .map(|(value, point, _)| (value.clone(), point.clone()))
+            .collect();
+
+        Some(Self {
+            centroid,
+            count,
+            extent,
+            values,
+        })
+    }
+}
+
+pub(crate) enum Item<N, P> {
+    Node(N),
+    Point(P),
+}
+
+/// Queue entry of a best-first nearest search, ordered so the closest pops
+/// first from a `BinaryHeap`
+pub(crate) struct Candidate<N, P> {
+    pub distance: f64,
+    pub item: Item<N, P>,
+}
+
+impl<N, P> PartialEq for Candidate<N, P> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<N, P> Eq for Candidate<N, P> {}
+
+impl<N, P> PartialOrd for Candidate<N, P> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<N, P> Ord for Candidate<N, P> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Reversed for the max-heap, points before nodes at equal distance
+        other
+            .distance
+            .total_cmp(&self.distance)
+            .then_with(|| match (&self.item, &other.item) {
+                (Item::Point(_), Item::Node(_)) => Ordering::Greater,
+                (Item::Node(_), Item::Point(_)) => Ordering::Less,
+                _ => Ordering::Equal,
+            })
+    }
+}
@@ -0,0 +1,707 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use ahash::{AHashMap, AHashSet};
+use gtfs_structures::{DirectionType, Route, Trip};
+use serde::{Deserialize, Serialize};
+
+use super::{feed::NAMESPACE_SEPARATOR, Feed};
+
+/// Namespace of the ids of the alerts added through the API, no feed may be
+/// named after it
+pub const MANUAL_NAMESPACE: &str = "manual";
+
+// Variants are named after GTFS-RT
+#[allow(clippy::enum_variant_names)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Cause {
+    #[default]
+    UnknownCause,
+    OtherCause,
+    TechnicalProblem,
+    Strike,
+    Demonstration,
+    Accident,
+    Holiday,
+    Weather,
+    Maintenance,
+    Construction,
+    PoliceActivity,
+    MedicalEmergency,
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Effect {
+    NoService,
+    ReducedService,
+    SignificantDelays,
+    Detour,
+    AdditionalService,
+    ModifiedService,
+    OtherEffect,
+    #[default]
+    UnknownEffect,
+    StopMoved,
+    NoEffect,
+    AccessibilityIssue,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SeverityLevel {
+    #[default]
+    UnknownSeverity,
+    Info,
+    Warning,
+    Severe,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Translation {
+    pub text: String,
+    pub language: Option<String>,
+}
+
+/// POSIX times, an open bound when missing
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct ActivePeriod {
+    pub start: Option<u64>,
+    pub end: Option<u64>,
+}
+
+/// Part of the network an alert is about: every field given must match.
+/// Ids are namespaced, and the route of a trip is always filled in.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct InformedEntity {
+    pub agency_id: Option<String>,
+    pub route_id: Option<String>,
+    pub trip_id: Option<String>,
+    pub stop_id: Option<String>,
+    pub direction_id: Option<u32>,
+}
+
+/// Service alert read from GTFS-RT or added through the API
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Alert {
+    /// Namespaced entity id, or `manual:n` for the alerts added through the
+    /// API
+    #[serde(default)]
+    pub id: String,
+    #[serde(default)]
+    pub cause: Cause,
+    #[serde(default)]
+    pub effect: Effect,
+    #[serde(default)]
+    pub severity: SeverityLevel,
+    #[serde(default)]
+    pub header: Vec<Translation>,
+    #[serde(default)]
+    pub description: Vec<Translation>,
+    #[serde(default)]
+    pub url: Vec<Translation>,
+    /// Always active when empty
+    #[serde(default)]
+    pub active_periods: Vec<ActivePeriod>,
+    #[serde(default)]
+    pub informed_entities: Vec<InformedEntity>,
+}
+
+impl Alert {
+    pub fn is_active(&self, at: u64) -> bool {
+        self.active_periods.is_empty()
+            || self.active_periods.iter().any(|period| {
+                period.start.unwrap_or(0) <= at && at <= period.end.unwrap_or(u64::MAX)
+            })
+    }
+}
+
+/// Trip an alert may be about, with its route and stops, ids namespaced
+pub struct TripScope {
+    trip_id: String,
+    route_id: String,
+    agency_id: Option<String>,
+    direction_id: Option<u32>,
+    stop_ids: AHashSet<String>,
+}
+
+impl TripScope {
+    pub fn new(feed: &Feed, trip: &Trip) -> Self {
+        Self {
+            trip_id: feed.namespaced(&trip.id),
+            route_id: feed.namespaced(&trip.route_id),
+            agency_id: feed
+                .get_gtfs()
+                .routes
+                .get(&trip.route_id)
+                .and_then(|route| route_agency(feed, route)),
+            direction_id: trip.direction_id.map(|direction| match direction {
+                DirectionType::Outbound => 0,
+                DirectionType::Inbound => 1,
+            }),
+            stop_ids: trip
+                .stop_times
+                .iter()
+                .map(|st| feed.namespaced(&st.stop.id))
+                .collect(),
+        }
+    }
+}
+
+/// Namespaced agency of a route, the only agency of the feed when the route
+/// does not name one
+pub fn route_agency(feed: &Feed, route: &Route) -> Option<String> {
+    let agencies = &feed.get_gtfs().agencies;
+    let agency_id = match &route.agency_id {
+        Some(agency_id) => Some(agency_id.as_str()),
+        None if agencies.len() == 1 => agencies[0].id.as_deref(),
+        None => None,
+    };
+    agency_id.map(|agency_id| feed.namespaced(agency_id))
+}
+
+/// Alerts to look up, ids namespaced
+pub enum AlertFilter {
+    All,
+    Stop(String),
+    Route {
+        route_id: String,
+        agency_id: Option<String>,
+    },
+    Trip(TripScope),
+}
+
+/// Alerts of every source, indexed by the routes, stops and trips they are
+/// about
+#[derive(Default)]
+pub struct Alerts {
+    /// Alert and the GTFS-RT source it was read from, `None` when added
+    /// through the API, by id
+    alerts: BTreeMap<String, (Option<String>, Alert)>,
+    by_route: AHashMap<String, Vec<String>>,
+    by_stop: AHashMap<String, Vec<String>>,
+    by_trip: AHashMap<String, Vec<String>>,
+    /// About a whole agency or direction
+    network: Vec<String>,
+    manual_count: u64,
+}
+
+impl Alerts {
+    /// Store the alerts of a message read from `source`. A full dataset
+    /// replaces every alert previously read from it, a differential one only
+    /// the alerts it lists.
+    pub fn apply(
+        &mut self,
+        source: &str,
+        full_dataset: bool,
+        alerts: Vec<Alert>,
+        deleted: Vec<String>,
+    ) {
+        if full_dataset {
+            self.alerts
+                .retain(|_, (from, _)| from.as_deref() != Some(source));
+        }
+        for id in deleted {
+            self.alerts.remove(&id);
+        }
+        for alert in alerts {
+            self.alerts
+                .insert(alert.id.clone(), (Some(source.to_string()), alert));
+        }
+        self.reindex();
+    }
+
+    /// Store an alert added through the API, returning the id given to it
+    pub fn add(&mut self, mut alert: Alert) -> String {
+        self.manual_count += 1;
+        alert.id = format!(
+            "{}{}{}",
+            MANUAL_NAMESPACE, NAMESPACE_SEPARATOR, self.manual_count
+        );
+        let id = alert.id.clone();
+        self.alerts.insert(id.clone(), (None, alert));
+        self.reindex();
+        id
+    }
+
+    /// Drop an alert added through the API, `false` if there is none with
+    /// this id
+    pub fn remove(&mut self, id: &str) -> bool {
+        match self.alerts.get(id) {
+            Some((None, _)) => {
+                self.alerts.remove(id);
+                self.reindex();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn reindex(&mut self) {
+        self.by_route.clear();
+        self.by_stop.clear();
+        self.by_trip.clear();
+        self.network.clear();
+
+        for (id, (_, alert)) in self.alerts.iter() {
+            for entity in alert.informed_entities.iter() {
+                let indexes = [
+                    (&entity.route_id, &mut self.by_route),
+                    (&entity.stop_id, &mut self.by_stop),
+                    (&entity.trip_id, &mut self.by_trip),
+                ];
+                let mut indexed = false;
+                for (key, index) in indexes {
+                    if let Some(key) = key {
+                        let ids = index.entry(key.clone()).or_default();
+                        if ids.last() != Some(id) {
+                            ids.push(id.clone());
+                        }
+                        indexed = true;
+                    }
+                }
+                if !indexed && self.network.last() != Some(id) {
+                    self.network.push(id.clone());
+                }
+            }
+        }
+    }
+
+    /// Alerts matching `filter` and active at `at`, ordered by id
+    pub fn find(&self, filter: &AlertFilter, at: u64) -> Vec<Alert> {
+        let mut candidates: BTreeSet<&String> = BTreeSet::new();
+        match filter {
+            AlertFilter::All => return self.collect(self.alerts.keys(), at, |_| true),
+            AlertFilter::Stop(stop_id) => lookup(&mut candidates, &self.by_stop, stop_id),
+            AlertFilter::Route { route_id, .. } => {
+                lookup(&mut candidates, &self.by_route, route_id)
+            }
+            AlertFilter::Trip(scope) => {
+                lookup(&mut candidates, &self.by_trip, &scope.trip_id);
+                lookup(&mut candidates, &self.by_route, &scope.route_id);
+                for stop_id in scope.stop_ids.iter() {
+                    lookup(&mut candidates, &self.by_stop, stop_id);
+                }
+            }
+        }
+        if !matches!(filter, AlertFilter::Stop(_)) {
+            candidates.extend(self.network.iter());
+        }
+
+        self.collect(candidates.into_iter(), at, |entity| match filter {
+            AlertFilter::All => true,
+            AlertFilter::Stop(stop_id) => entity.stop_id.as_ref() == Some(stop_id),
+            AlertFilter::Route {
+                route_id,
+                agency_id,
+            } => match &entity.route_id {
+                Some(id) => id == route_id,
+                None => {
+                    entity.trip_id.is_none()
+                        && entity.stop_id.is_none()
+                        && is_listed(&entity.agency_id, agency_id.as_deref())
+                }
+            },
+            AlertFilter::Trip(scope) => {
+                is_listed(&entity.trip_id, Some(&scope.trip_id))
+                    && is_listed(&entity.route_id, Some(&scope.route_id))
+                    && is_listed(&entity.agency_id, scope.agency_id.as_deref())
+                    && match (entity.direction_id, scope.direction_id) {
+                        (Some(direction), Some(trip_direction)) => direction == trip_direction,
+                        (Some(_), None) => false,
+                        (None, _) => true,
+                    }
+                    && match &entity.stop_id {
+                        Some(stop_id) => scope.stop_ids.contains(stop_id),
+                        None => true,
+                    }
+            }
+        })
+    }
+
+    /// Alerts active at `at` about each of `stop_ids`, the stops without
+    /// any being left out
+    pub fn find_by_stops<'a>(
+        &self,
+        stop_ids: impl Iterator<Item = &'a str>,
+        at: u64,
+    ) -> Vec<(&'a str, Vec<Alert>)> {
+        stop_ids
+            .filter_map(|stop_id| {
+                let ids = self.by_stop.get(stop_id)?;
+                let alerts = self.collect(ids.iter(), at, |entity| {
+                    entity.stop_id.as_deref() == Some(stop_id)
+                });
+                Some((stop_id, alerts)).filter(|(_, alerts)| !alerts.is_empty())
+            })
+            .collect()
+    }
+
+    fn collect<'a>(
+        &self,
+        ids: impl Iterator<Item = &'a String>,
+        at: u64,
+        matches: impl Fn(&InformedEntity) -> bool,
+    ) -> Vec<Alert> {
+        ids.filter_map(|id| self.alerts.get(id))
+            .map(|(_, alert)| alert)
+            .filter(|alert| alert.is_active(at) && alert.informed_entities.iter().any(&matches))
+            .cloned()
+            .collect()
+    }
+}
+
+fn lookup<'a>(
+    candidates: &mut BTreeSet<&'a String>,
+    index: &'a AHashMap<String, Vec<String>>,
+    key: &str,
+) {
+    if let Some(ids) = index.get(key) {
+        candidates.extend(ids.iter());
+    }
+}
+
+/// `true` when `field` is not set or equals `value`
+fn is_listed(field: &Option<String>, value: Option<&str>) -> bool {
+    match field {
+        Some(field) => Some(field.as_str()) == value,
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use gtfs_structures::{Agency, Gtfs, Stop, StopTime};
+
+    use super::*;
+    use crate::store::{FeedConfig, FeedSource, ValidationReport};
+
+    /// Agency `TEC` running `R1` with outbound trip `T1` calling at `S1`
+    /// and `S2`, and `R2`, naming no agency, with inbound trip `T2` calling
+    /// at `S2` and `S3`
+    fn feed() -> Feed {
+        let mut gtfs = Gtfs::default();
+        gtfs.agencies.push(Agency {
+            id: Some("TEC".to_string()),
+            ..Default::default()
+        });
+        for (route_id, agency_id) in [("R1", Some("TEC")), ("R2", None)] {
+            gtfs.routes.insert(
+                route_id.to_string(),
+                Route {
+                    id: route_id.to_string(),
+                    agency_id: agency_id.map(str::to_string),
+                    ..Default::default()
+                },
+            );
+        }
+        let trips = [
+            ("T1", "R1", DirectionType::Outbound, ["S1", "S2"]),
+            ("T2", "R2", DirectionType::Inbound, ["S2", "S3"]),
+        ];
+        for (trip_id, route_id, direction, stops) in trips {
+            gtfs.trips.insert(
+                trip_id.to_string(),
+                Trip {
+                    id: trip_id.to_string(),
+                    route_id: route_id.to_string(),
+                    direction_id: Some(direction),
+                    stop_times: stops
+                        .iter()
+                        .map(|stop_id| StopTime {
+                            stop: Arc::new(Stop {
+                                id: stop_id.to_string(),
+                                ..Default::default()
+                            }),
+                            ..Default::default()
+                        })
+                        .collect(),
+                    ..Default::default()
+                },
+            );
+        }
+
+        let config = FeedConfig {
+            name: "tec".to_string(),
+            source: FeedSource::Directory("gtfs".into()),
+        };
+        Feed::from_parts(&config, String::new(), gtfs, ValidationReport::default())
+    }
+
+    fn alert(id: &str, informed_entities: Vec<InformedEntity>) -> Alert {
+        Alert {
+            id: id.to_string(),
+            cause: Cause::default(),
+            effect: Effect::default(),
+            severity: SeverityLevel::default(),
+            header: vec![Translation {
+                text: id.to_string(),
+                language: None,
+            }],
+            description: Vec::new(),
+            url: Vec::new(),
+            active_periods: Vec::new(),
+            informed_entities,
+        }
+    }
+
+    fn entity(
+        route_id: Option<&str>,
+        trip_id: Option<&str>,
+        stop_id: Option<&str>,
+    ) -> InformedEntity {
+        InformedEntity {
+            route_id: route_id.map(str::to_string),
+            trip_id: trip_id.map(str::to_string),
+            stop_id: stop_id.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    fn agency(agency_id: &str) -> InformedEntity {
+        InformedEntity {
+            agency_id: Some(agency_id.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn ids(alerts: Vec<Alert>) -> Vec<String> {
+        alerts.into_iter().map(|alert| alert.id).collect()
+    }
+
+    /// One alert of every kind, read from source `rt`
+    fn alerts() -> Alerts {
+        let mut alerts = Alerts::default();
+        alerts.apply(
+            "rt",
+            true,
+            vec![
+                alert("tec:agency", vec![agency("tec:TEC")]),
+                alert("tec:other-agency", vec![agency("tec:OTHER")]),
+                alert("tec:route", vec![entity(Some("tec:R1"), None, None)]),
+                alert(
+                    "tec:route-stop",
+                    vec![entity(Some("tec:R1"), None, Some("tec:S3"))],
+                ),
+                alert("tec:trip", vec![entity(None, Some("tec:T1"), None)]),
+                alert("tec:stop", vec![entity(None, None, Some("tec:S1"))]),
+                alert(
+                    "tec:inbound",
+                    vec![InformedEntity {
+                        route_id: Some("tec:R1".to_string()),
+                        direction_id: Some(1),
+                        ..Default::default()
+                    }],
+                ),
+                alert(
+                    "tec:stops",
+                    vec![
+                        entity(None, None, Some("tec:S2")),
+                        entity(None, None, Some("tec:S3")),
+                    ],
+                ),
+            ],
+            Vec::new(),
+        );
+        alerts
+    }
+
+    #[test]
+    fn find_all() {
+        let mut alerts = alerts();
+        let mut ended = alert("tec:ended", vec![agency("tec:TEC")]);
+        ended.active_periods = vec![ActivePeriod {
+            start: None,
+            end: Some(100),
+        }];
+        alerts.apply("rt", false, vec![ended], Vec::new());
+
+        assert_eq!(
+            ids(alerts.find(&AlertFilter::All, 200)),
+            vec![
+                "tec:agency",
+                "tec:inbound",
+                "tec:other-agency",
+                "tec:route",
+                "tec:route-stop",
+                "tec:stop",
+                "tec:stops",
+                "tec:trip",
+            ]
+        );
+        assert_eq!(alerts.find(&AlertFilter::All, 50).len(), 9);
+    }
+
+    #[test]
+    fn find_by_stop() {
+        let alerts = alerts();
+        let find = |stop_id: &str| ids(alerts.find(&AlertFilter::Stop(stop_id.to_string()), 0));
+
+        // Network-wide alerts are left out of stops
+        assert_eq!(find("tec:S1"), vec!["tec:stop"]);
+        assert_eq!(find("tec:S2"), vec!["tec:stops"]);
+        assert_eq!(find("tec:S3"), vec!["tec:route-stop", "tec:stops"]);
+        assert!(find("tec:S4").is_empty());
+    }
+
+    #[test]
+    fn find_by_stops() {
+        let mut alerts = alerts();
+        let mut ended = alert("tec:ended", vec![entity(None, None, Some("tec:S2"))]);
+        ended.active_periods = vec![ActivePeriod {
+            start: None,
+            end: Some(100),
+        }];
+        alerts.apply("rt", false, vec![ended], Vec::new());
+
+        let found: Vec<(&str, Vec<String>)> = alerts
+            .find_by_stops(["tec:S1", "tec:S2", "tec:S3", "tec:S4"].into_iter(), 200)
+            .into_iter()
+            .map(|(stop_id, alerts)| (stop_id, ids(alerts)))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("tec:S1", vec!["tec:stop".to_string()]),
+                ("tec:S2", vec!["tec:stops".to_string()]),
+                (
+                    "tec:S3",
+                    vec!["tec:route-stop".to_string(), "tec:stops".to_string()]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn find_by_route() {
+        let alerts = alerts();
+        let feed = feed();
+        let find = |route_id: &str| {
+            let route = &feed.get_gtfs().routes[route_id];
+            let filter = AlertFilter::Route {
+                route_id: feed.namespaced(route_id),
+                agency_id: route_agency(&feed, route),
+            };
+            ids(alerts.find(&filter, 0))
+        };
+
+        assert_eq!(
+            find("R1"),
+            vec!["tec:agency", "tec:inbound", "tec:route", "tec:route-stop"]
+        );
+        // The only agency of the feed runs the routes naming none
+        assert_eq!(find("R2"), vec!["tec:agency"]);
+    }
+
+    #[test]
+    fn find_by_trip() {
+        let alerts = alerts();
+        let feed = feed();
+        let find = |trip_id: &str| {
+            let scope = TripScope::new(&feed, &feed.get_gtfs().trips[trip_id]);
+            ids(alerts.find(&AlertFilter::Trip(scope), 0))
+        };
+
+        // Not the inbound direction, nor the stops of R1 it does not call at
+        assert_eq!(
+            find("T1"),
+            vec![
+                "tec:agency",
+                "tec:route",
+                "tec:stop",
+                "tec:stops",
+                "tec:trip"
+            ]
+        );
+        assert_eq!(find("T2"), vec!["tec:agency", "tec:stops"]);
+    }
+
+    #[test]
+    fn find_trip_by_direction() {
+        let mut alerts = Alerts::default();
+        let direction = |id: &str, direction_id: u32| {
+            alert(
+                id,
+                vec![InformedEntity {
+                    direction_id: Some(direction_id),
+                    ..Default::default()
+                }],
+            )
+        };
+        alerts.apply(
+            "rt",
+            true,
+            vec![direction("tec:outbound", 0), direction("tec:inbound", 1)],
+            Vec::new(),
+        );
+        let feed = feed();
+        let scope = TripScope::new(&feed, &feed.get_gtfs().trips["T2"]);
+        assert_eq!(
+            ids(alerts.find(&AlertFilter::Trip(scope), 0)),
+            vec!["tec:inbound"]
+        );
+    }
+
+    #[test]
+    fn apply_full_and_differential() {
+        let stop = |id: &str| alert(id, vec![entity(None, None, Some("tec:S1"))]);
+        let find = |alerts: &Alerts| ids(alerts.find(&AlertFilter::Stop("tec:S1".to_string()), 0));
+        let mut alerts = Alerts::default();
+        alerts.apply("a", true, vec![stop("tec:a1"), stop("tec:a2")], Vec::new());
+        alerts.apply("b", true, vec![stop("tec:b1")], Vec::new());
+        let manual = alerts.add(stop("ignored"));
+
+        // A full dataset only replaces the alerts of its own source
+        alerts.apply("a", true, vec![stop("tec:a3")], Vec::new());
+        assert_eq!(find(&alerts), vec!["manual:1", "tec:a3", "tec:b1"]);
+
+        // A differential one adds, replaces and deletes
+        let mut replaced = stop("tec:a3");
+        replaced.informed_entities = vec![entity(None, None, Some("tec:S2"))];
+        alerts.apply(
+            "a",
+            false,
+            vec![stop("tec:a4"), replaced],
+            vec!["tec:b1".to_string()],
+        );
+        assert_eq!(find(&alerts), vec!["manual:1", "tec:a4"]);
+        assert_eq!(
+            ids(alerts.find(&AlertFilter::Stop("tec:S2".to_string()), 0)),
+            vec!["tec:a3"]
+        );
+
+        alerts.apply("a", true, Vec::new(), Vec::new());
+        assert_eq!(find(&alerts), vec![manual]);
+    }
+
+    #[test]
+    fn add_and_remove_manual_alerts() {
+        let mut alerts = alerts();
+        let first = alerts.add(alert("ignored", vec![agency("tec:TEC")]));
+        let second = alerts.add(alert("ignored", vec![entity(None, None, Some("tec:S1"))]));
+        assert_eq!((first.as_str(), second.as_str()), ("manual:1", "manual:2"));
+        assert_eq!(
+            ids(alerts.find(&AlertFilter::Stop("tec:S1".to_string()), 0)),
+            vec!["manual:2", "tec:stop"]
+        );
+
+        // Only the alerts added through the API can be removed
+        assert!(!alerts.remove("tec:stop"));
+        assert!(alerts.remove(&second));
+        assert!(!alerts.remove(&second));
+        assert_eq!(
+            ids(alerts.find(&AlertFilter::Stop("tec:S1".to_string()), 0)),
+            vec!["tec:stop"]
+        );
+
+        // Ids are not reused
+        assert_eq!(
+            alerts.add(alert("ignored", vec![agency("tec:TEC")])),
+            "manual:3"
+        );
+        assert_eq!(alerts.find(&AlertFilter::All, 0).len(), 10);
+    }
+}
@@ -0,0 +1,1310 @@
+use std::{
+    collections::HashMap,
+    env,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use bincode::Options;
+use chrono::{Datelike, NaiveDate};
+use gtfs_structures::{
+    Agency, Availability, BikesAllowedType, Calendar, CalendarDate, ContinuousPickupDropOff,
+    DirectionType, ExactTimes, Exception, FareAttribute, FeedInfo, Frequency, Gtfs, LocationType,
+    Pathway, PathwayDirectionType, PathwayMode, PaymentMethod, PickupDropOffType, Route, RouteType,
+    Shape, Stop, StopTime, StopTransfer, TimepointType, TransferType, Transfers, Trip,
+};
+use rgb::RGB8;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::{
+    index::{self, Indexes},
+    Feed, FeedConfig, FeedSnapshot, ValidationReport,
+};
+use crate::logger;
+
+/// Bump whenever a cached structure changes so stale files are ignored
+const CACHE_FORMAT: u32 = 11;
+
+/// Directory holding binary snapshots keyed by the checksums of their feeds,
+/// so a restart with unchanged feeds skips CSV parsing and index building
+pub struct SnapshotCache {
+    dir: PathBuf,
+}
+
+impl SnapshotCache {
+    /// Cache in `GTFS_CACHE_DIR`, disabled when unset
+    pub fn from_env() -> Option<Self> {
+        let dir = env::var("GTFS_CACHE_DIR").ok()?;
+        Some(Self {
+            dir: PathBuf::from(dir),
+        })
+    }
+
+    /// Key of a snapshot built from these `(feed name, sha256)` with the
+    /// configured spatial index
+    pub fn key<'a>(feeds: impl Iterator<Item = (&'a str, &'a str)>) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(CACHE_FORMAT.to_le_bytes());
+        hasher.update(index::spatial_index_kind().as_str().as_bytes());
+        for (name, checksum) in feeds {
+            hasher.update(name.as_bytes());
+            hasher.update(b"=");
+            hasher.update(checksum.as_bytes());
+            hasher.update(b";");
+        }
+        format!("{:x}", hasher.finalize())
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("snapshot-{}.bin", key))
+    }
+
+    /// Snapshot stored under `key`, `None` if absent or unreadable
+    pub fn load(&self, key: &str, version: u64, configs: &[FeedConfig]) -> Option<FeedSnapshot> {
+        let path = self.path(key);
+        let file = File::open(&path).ok()?;
+
+        let start_time = std::time::Instant::now();
+        let mut reader = Hashing::new(BufReader::new(file));
+        let cached: CachedSnapshot = match bincode::DefaultOptions::new()
+            .deserialize_from(&mut reader)
+            .map_err(|e| e.to_string())
+            .and_then(|cached| reader.verify().map(|_| cached))
+        {
+            Ok(cached) => cached,
+            Err(e) => {
+                logger::warn(
+                    "CACHE",
+                    &format!("Ignoring unreadable cache {}: {}", path.display(), e),
+                );
+                return None;
+            }
+        };
+
+        if cached.format != CACHE_FORMAT {
+            return None;
+        }
+
+        let mut feeds = Vec::with_capacity(cached.feeds.len());
+        for feed in cached.feeds {
+            let config = configs.iter().find(|c| c.name == feed.name)?;
+            match feed.gtfs.into_gtfs() {
+                Ok(gtfs) => feeds.push(Arc::new(Feed::from_parts(
+                    config,
+                    feed.checksum,
+                    gtfs,
+                    feed.validation,
+                ))),
+                Err(e) => {
+                    logger::warn(
+                        "CACHE",
+                        &format!("Ignoring invalid cache {}: {}", path.display(), e),
+                    );
+                    return None;
+                }
+            }
+        }
+
+        logger::fine(
+            "CACHE",
+            &format!(
+                "Loaded snapshot from {}: [{:?}]",
+                path.display(),
+                start_time.elapsed()
+            ),
+        );
+        Some(FeedSnapshot::from_parts(version, feeds, cached.indexes))
+    }
+
+    /// Write `snapshot` under `key` and remove every other cached snapshot
+    pub fn store(&self, key: &str, snapshot: &FeedSnapshot) {
+        let start_time = std::time::Instant::now();
+        if let Err(e) = self.write(key, snapshot) {
+            logger::warn("CACHE", &format!("Could not write cache: {}", e));
+            return;
+        }
+
+        let current = self.path(key);
+        if let Ok(entries) = std::fs::read_dir(&self.dir) {
+            for entry in entries.flatten() {
+                let path = entry.path();
+                let is_snapshot = path
+                    .file_name()
+                    .and_then(|n| n.to_str())
+                    .map(|n| n.starts_with("snapshot-") && n.ends_with(".bin"))
+                    .unwrap_or(false);
+                if is_snapshot && path != current {
+                    let _ = std::fs::remove_file(path);
+                }
+            }
+        }
+
+        logger::fine(
+            "CACHE",
+            &format!(
+                "Wrote snapshot to {}: [{:?}]",
+                current.display(),
+                start_time.elapsed()
+            ),
+        );
+    }
+
+    fn write(&self, key: &str, snapshot: &FeedSnapshot) -> Result<(), String> {
+        std::fs::create_dir_all(&self.dir).map_err(|e| e.to_string())?;
+
+        let cached = CachedSnapshotRef {
+            format: CACHE_FORMAT,
+            feeds: snapshot
+                .get_feeds()
+                .iter()
+                .map(|f| CachedFeed {
+                    name: f.get_name().to_string(),
+                    checksum: f.get_checksum().to_string(),
+                    gtfs: CachedGtfs::from_gtfs(f.get_gtfs()),
+                    validation: f.get_validation().clone(),
+                })
+                .collect(),
+            indexes: snapshot.get_indexes(),
+        };
+
+        // Write next to the target then rename so a crash never leaves a
+        // truncated file under a valid key
+        let path = self.path(key);
+        let tmp = path.with_extension("tmp");
+        let file = File::create(&tmp).map_err(|e| e.to_string())?;
+        let mut writer = Hashing::new(BufWriter::new(file));
+        bincode::DefaultOptions::new()
+            .serialize_into(&mut writer, &cached)
+            .map_err(|e| e.to_string())?;
+        writer.finish().map_err(|e| e.to_string())?;
+        std::fs::rename(&tmp, &path).map_err(|e| e.to_string())
+    }
+}
+
+/// Reader or writer hashing what goes through it. A cache file is the
+/// snapshot followed by its SHA-256, so a corrupted file is never loaded.
+struct Hashing<T> {
+    inner: T,
+    hasher: Sha256,
+}
+
+impl<T> Hashing<T> {
+    fn new(inner: T) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+}
+
+impl<W: Write> Hashing<W> {
+    /// Append the hash of everything written
+    fn finish(mut self) -> std::io::Result<()> {
+        let digest = self.hasher.finalize();
+        self.inner.write_all(&digest)?;
+        self.inner.flush()
+    }
+}
+
+impl<R: Read> Hashing<R> {
+    /// Check that the hash of everything read follows and ends the file
+    fn verify(mut self) -> Result<(), String> {
+        let mut digest = [0; 32];
+        self.inner
+            .read_exact(&mut digest)
+            .map_err(|e| format!("Missing checksum: {}", e))?;
+        if digest[..] != self.hasher.finalize()[..] {
+            return Err("Checksum mismatch".to_string());
+        }
+        match self.inner.read(&mut [0]) {
+            Ok(0) => Ok(()),
+            Ok(_) => Err("Trailing data".to_string()),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+}
+
+impl<W: Write> Write for Hashing<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: Read> Read for Hashing<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+#[derive(Serialize)]
+struct CachedSnapshotRef<'a> {
+    format: u32,
+    feeds: Vec<CachedFeed>,
+    indexes: &'a Indexes,
+}
+
+#[derive(Deserialize)]
+struct CachedSnapshot {
+    format: u32,
+    feeds: Vec<CachedFeed>,
+    indexes: Indexes,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedFeed {
+    name: String,
+    checksum: String,
+    gtfs: CachedGtfs,
+    validation: ValidationReport,
+}
+
+// The gtfs-structures types derive serde for CSV, which does not round-trip
+// through a binary format (bools written as numbers but read as strings,
+// skipped fields, ...), hence these plain mirrors.
+
+#[derive(Serialize, Deserialize)]
+struct CachedGtfs {
+    read_duration: i64,
+    calendar: Vec<CachedCalendar>,
+    calendar_dates: Vec<CachedCalendarDate>,
+    stops: Vec<CachedStop>,
+    routes: Vec<CachedRoute>,
+    trips: Vec<CachedTrip>,
+    agencies: Vec<Agency>,
+    shapes: Vec<Shape>,
+    fare_attributes: Vec<CachedFareAttribute>,
+    feed_info: Vec<CachedFeedInfo>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedCalendar {
+    id: String,
+    days: [bool; 7],
+    start_date: i32,
+    end_date: i32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedCalendarDate {
+    service_id: String,
+    date: i32,
+    exception_type: i16,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedStop {
+    id: String,
+    code: Option<String>,
+    name: String,
+    description: String,
+    location_type: i16,
+    parent_station: Option<String>,
+    zone_id: Option<String>,
+    url: Option<String>,
+    longitude: Option<f64>,
+    latitude: Option<f64>,
+    timezone: Option<String>,
+    wheelchair_boarding: i16,
+    level_id: Option<String>,
+    platform_code: Option<String>,
+    transfers: Vec<CachedStopTransfer>,
+    pathways: Vec<CachedPathway>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedStopTransfer {
+    to_stop_id: String,
+    transfer_type: i16,
+    min_transfer_time: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedPathway {
+    id: String,
+    to_stop_id: String,
+    mode: i16,
+    is_bidirectional: i16,
+    length: Option<f32>,
+    traversal_time: Option<u32>,
+    stair_count: Option<i32>,
+    max_slope: Option<f32>,
+    min_width: Option<f32>,
+    signposted_as: Option<String>,
+    reversed_signposted_as: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedRoute {
+    id: String,
+    short_name: String,
+    long_name: String,
+    desc: Option<String>,
+    route_type: i16,
+    url: Option<String>,
+    agency_id: Option<String>,
+    order: Option<u32>,
+    color: [u8; 3],
+    text_color: [u8; 3],
+    continuous_pickup: i16,
+    continuous_drop_off: i16,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedTrip {
+    id: String,
+    service_id: String,
+    route_id: String,
+    stop_times: Vec<CachedStopTime>,
+    shape_id: Option<String>,
+    trip_headsign: Option<String>,
+    trip_short_name: Option<String>,
+    direction_id: Option<i16>,
+    block_id: Option<String>,
+    wheelchair_accessible: i16,
+    bikes_allowed: i16,
+    frequencies: Vec<CachedFrequency>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedStopTime {
+    arrival_time: Option<u32>,
+    /// Index in `CachedGtfs::stops`, stops are shared and not repeated
+    stop: u32,
+    departure_time: Option<u32>,
+    pickup_type: i16,
+    drop_off_type: i16,
+    stop_sequence: u16,
+    stop_headsign: Option<String>,
+    continuous_pickup: i16,
+    continuous_drop_off: i16,
+    shape_dist_traveled: Option<f32>,
+    timepoint: i16,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedFrequency {
+    start_time: u32,
+    end_time: u32,
+    headway_secs: u32,
+    exact_times: Option<i16>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedFareAttribute {
+    id: String,
+    price: String,
+    currency: String,
+    payment_method: i16,
+    transfers: Option<i16>,
+    agency_id: Option<String>,
+    transfer_duration: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedFeedInfo {
+    name: String,
+    url: String,
+    lang: String,
+    default_lang: Option<String>,
+    start_date: Option<i32>,
+    end_date: Option<i32>,
+    version: Option<String>,
+    contact_email: Option<String>,
+    contact_url: Option<String>,
+}
+
+/// GTFS enum stored as its numeric code in the CSV
+trait Code: Sized {
+    fn code(&self) -> i16;
+
+    fn from_code(code: i16) -> Result<Self, String>;
+}
+
+/// Implement [`Code`] listing every variant with its code. The variant
+/// after `else` holds any other code.
+macro_rules! codes {
+    ($($enum:ident { $($variant:ident = $code:literal,)* } $(else $other:ident)?;)*) => {
+        $(
+            impl Code for $enum {
+                fn code(&self) -> i16 {
+                    match self {
+                        $($enum::$variant => $code,)*
+                        $($enum::$other(code) => *code,)?
+                    }
+                }
+
+                fn from_code(code: i16) -> Result<Self, String> {
+                    match code {
+                        $($code => Ok($enum::$variant),)*
+                        $(code => Ok($enum::$other(code)),)?
+                        #[allow(unreachable_patterns)]
+                        code => Err(format!("Invalid {} code {}", stringify!($enum), code)),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+codes! {
+    LocationType {
+        StopPoint = 0,
+        StopArea = 1,
+        StationEntrance = 2,
+        GenericNode = 3,
+        BoardingArea = 4,
+    } else Unknown;
+    Availability {
+        InformationNotAvailable = 0,
+        Available = 1,
+        NotAvailable = 2,
+    } else Unknown;
+    TransferType {
+        Recommended = 0,
+        Timed = 1,
+        MinTime = 2,
+        Impossible = 3,
+        StayOnBoard = 4,
+        MustAlight = 5,
+    };
+    PathwayMode {
+        Walkway = 1,
+        Stairs = 2,
+        MovingSidewalk = 3,
+        Escalator = 4,
+        Elevator = 5,
+        FareGate = 6,
+        ExitGate = 7,
+    };
+    PathwayDirectionType {
+        Unidirectional = 0,
+        Bidirectional = 1,
+    };
+    PickupDropOffType {
+        Regular = 0,
+        NotAvailable = 1,
+        ArrangeByPhone = 2,
+        CoordinateWithDriver = 3,
+    } else Unknown;
+    ContinuousPickupDropOff {
+        Continuous = 0,
+        NotAvailable = 1,
+        ArrangeByPhone = 2,
+        CoordinateWithDriver = 3,
+    } else Unknown;
+    TimepointType {
+        Approximate = 0,
+        Exact = 1,
+    };
+    DirectionType {
+        Outbound = 0,
+        Inbound = 1,
+    };
+    BikesAllowedType {
+        NoBikeInfo = 0,
+        AtLeastOneBike = 1,
+        NoBikesAllowed = 2,
+    } else Unknown;
+    ExactTimes {
+        FrequencyBased = 0,
+        ScheduleBased = 1,
+    };
+    Exception {
+        Added = 1,
+        Deleted = 2,
+    };
+    RouteType {
+        Tramway = 0,
+        Subway = 1,
+        Rail = 2,
+        Bus = 3,
+        Ferry = 4,
+        CableCar = 5,
+        Gondola = 6,
+        Funicular = 7,
+        Coach = 200,
+        Air = 1100,
+        Taxi = 1500,
+    } else Other;
+    PaymentMethod {
+        Aboard = 0,
+        PreBoarding = 1,
+    };
+}
+
+fn encode<T: Code>(value: &T) -> i16 {
+    value.code()
+}
+
+fn decode<T: Code>(code: i16) -> Result<T, String> {
+    T::from_code(code)
+}
+
+/// Code of the number of transfers, `None` when unlimited
+fn encode_transfers(transfers: &Transfers) -> Option<i16> {
+    match transfers {
+        Transfers::Unlimited => None,
+        Transfers::NoTransfer => Some(0),
+        Transfers::UniqueTransfer => Some(1),
+        Transfers::TwoTransfers => Some(2),
+        Transfers::Other(code) => Some(*code),
+    }
+}
+
+fn decode_transfers(code: Option<i16>) -> Transfers {
+    match code {
+        None => Transfers::Unlimited,
+        Some(0) => Transfers::NoTransfer,
+        Some(1) => Transfers::UniqueTransfer,
+        Some(2) => Transfers::TwoTransfers,
+        Some(code) => Transfers::Other(code),
+    }
+}
+
+fn encode_date(date: &NaiveDate) -> i32 {
+    date.num_days_from_ce()
+}
+
+fn decode_date(days: i32) -> Result<NaiveDate, String> {
+    NaiveDate::from_num_days_from_ce_opt(days).ok_or_else(|| format!("Invalid date {}", days))
+}
+
+impl CachedGtfs {
+    fn from_gtfs(gtfs: &Gtfs) -> Self {
+        let mut stop_index = HashMap::with_capacity(gtfs.stops.len());
+        let mut stops = Vec::with_capacity(gtfs.stops.len());
+        for (i, stop) in gtfs.stops.values().enumerate() {
+            stop_index.insert(stop.id.as_str(), i as u32);
+            stops.push(CachedStop {
+                id: stop.id.clone(),
+                code: stop.code.clone(),
+                name: stop.name.clone(),
+                description: stop.description.clone(),
+                location_type: encode(&stop.location_type),
+                parent_station: stop.parent_station.clone(),
+                zone_id: stop.zone_id.clone(),
+                url: stop.url.clone(),
+                longitude: stop.longitude,
+                latitude: stop.latitude,
+                timezone: stop.timezone.clone(),
+                wheelchair_boarding: encode(&stop.wheelchair_boarding),
+                level_id: stop.level_id.clone(),
+                platform_code: stop.platform_code.clone(),
+                transfers: stop
+                    .transfers
+                    .iter()
+                    .map(|t| CachedStopTransfer {
+                        to_stop_id: t.to_stop_id.clone(),
+                        transfer_type: encode(&t.transfer_type),
+                        min_transfer_time: t.min_transfer_time,
+                    })
+                    .collect(),
+                pathways: stop
+                    .pathways
+                    .iter()
+                    .map(|p| CachedPathway {
+                        id: p.id.clone(),
+                        to_stop_id: p.to_stop_id.clone(),
+                        mode: encode(&p.mode),
+                        is_bidirectional: encode(&p.is_bidirectional),
+                        length: p.length,
+                        traversal_time: p.traversal_time,
+                        stair_count: p.stair_count,
+                        max_slope: p.max_slope,
+                        min_width: p.min_width,
+                        signposted_as: p.signposted_as.clone(),
+                        reversed_signposted_as: p.reversed_signposted_as.clone(),
+                    })
+                    .collect(),
+            });
+        }
+
+        let trips = gtfs
+            .trips
+            .values()
+            .map(|trip| CachedTrip {
+                id: trip.id.clone(),
+                service_id: trip.service_id.clone(),
+                route_id: trip.route_id.clone(),
+                stop_times: trip
+                    .stop_times
+                    .iter()
+                    .map(|st| CachedStopTime {
+                        arrival_time: st.arrival_time,
+                        stop: stop_index[st.stop.id.as_str()],
+                        departure_time: st.departure_time,
+                        pickup_type: encode(&st.pickup_type),
+                        drop_off_type: encode(&st.drop_off_type),
+                        stop_sequence: st.stop_sequence,
+                        stop_headsign: st.stop_headsign.clone(),
+                        continuous_pickup: encode(&st.continuous_pickup),
+                        continuous_drop_off: encode(&st.continuous_drop_off),
+                        shape_dist_traveled: st.shape_dist_traveled,
+                        timepoint: encode(&st.timepoint),
+                    })
+                    .collect(),
+                shape_id: trip.shape_id.clone(),
+                trip_headsign: trip.trip_headsign.clone(),
+                trip_short_name: trip.trip_short_name.clone(),
+                direction_id: trip.direction_id.as_ref().map(encode),
+                block_id: trip.block_id.clone(),
+                wheelchair_accessible: encode(&trip.wheelchair_accessible),
+                bikes_allowed: encode(&trip.bikes_allowed),
+                frequencies: trip
+                    .frequencies
+                    .iter()
+                    .map(|f| CachedFrequency {
+                        start_time: f.start_time,
+                        end_time: f.end_time,
+                        headway_secs: f.headway_secs,
+                        exact_times: f.exact_times.as_ref().map(encode),
+                    })
+                    .collect(),
+            })
+            .collect();
+
+        Self {
+            read_duration: gtfs.read_duration,
+            calendar: gtfs
+                .calendar
+                .values()
+                .map(|c| CachedCalendar {
+                    id: c.id.clone(),
+                    days: [
+                        c.monday,
+                        c.tuesday,
+                        c.wednesday,
+                        c.thursday,
+                        c.friday,
+                        c.saturday,
+                        c.sunday,
+                    ],
+                    start_date: encode_date(&c.start_date),
+                    end_date: encode_date(&c.end_date),
+                })
+                .collect(),
+            calendar_dates: gtfs
+                .calendar_dates
+                .values()
+                .flatten()
+                .map(|d| CachedCalendarDate {
+                    service_id: d.service_id.clone(),
+                    date: encode_date(&d.date),
+                    exception_type: encode(&d.exception_type),
+                })
+                .collect(),
+            stops,
+            routes: gtfs
+                .routes
+                .values()
+                .map(|r| CachedRoute {
+                    id: r.id.clone(),
+                    short_name: r.short_name.clone(),
+                    long_name: r.long_name.clone(),
+                    desc: r.desc.clone(),
+                    route_type: encode(&r.route_type),
+                    url: r.url.clone(),
+                    agency_id: r.agency_id.clone(),
+                    order: r.order,
+                    color: [r.color.r, r.color.g, r.color.b],
+                    text_color: [r.text_color.r, r.text_color.g, r.text_color.b],
+                    continuous_pickup: encode(&r.continuous_pickup),
+                    continuous_drop_off: encode(&r.continuous_drop_off),
+                })
+                .collect(),
+            trips,
+            agencies: gtfs
+                .agencies
+                .iter()
+                .map(|a| Agency {
+                    id: a.id.clone(),
+                    name: a.name.clone(),
+                    url: a.url.clone(),
+                    timezone: a.timezone.clone(),
+                    lang: a.lang.clone(),
+                    phone: a.phone.clone(),
+                    fare_url: a.fare_url.clone(),
+                    email: a.email.clone(),
+                })
+                .collect(),
+            shapes: gtfs
+                .shapes
+                .values()
+                .flatten()
+                .map(|s| Shape {
+                    id: s.id.clone(),
+                    latitude: s.latitude,
+                    longitude: s.longitude,
+                    sequence: s.sequence,
+                    dist_traveled: s.dist_traveled,
+                })
+                .collect(),
+            fare_attributes: gtfs
+                .fare_attributes
+                .values()
+                .map(|f| CachedFareAttribute {
+                    id: f.id.clone(),
+                    price: f.price.clone(),
+                    currency: f.currency.clone(),
+                    payment_method: encode(&f.payment_method),
+                    transfers: encode_transfers(&f.transfers),
+                    agency_id: f.agency_id.clone(),
+                    transfer_duration: f.transfer_duration,
+                })
+                .collect(),
+            feed_info: gtfs
+                .feed_info
+                .iter()
+                .map(|f| CachedFeedInfo {
+                    name: f.name.clone(),
+                    url: f.url.clone(),
+                    lang: f.lang.clone(),
+                    default_lang: f.default_lang.clone(),
+                    start_date: f.start_date.as_ref().map(encode_date),
+                    end_date: f.end_date.as_ref().map(encode_date),
+                    version: f.version.clone(),
+                    contact_email: f.contact_email.clone(),
+                    contact_url: f.contact_url.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    fn into_gtfs(self) -> Result<Gtfs, String> {
+        let mut stops = Vec::with_capacity(self.stops.len());
+        for s in self.stops {
+            let mut transfers = Vec::with_capacity(s.transfers.len());
+            for t in s.transfers {
+                transfers.push(StopTransfer {
+                    to_stop_id: t.to_stop_id,
+                    transfer_type: decode(t.transfer_type)?,
+                    min_transfer_time: t.min_transfer_time,
+                });
+            }
+
+            let mut pathways = Vec::with_capacity(s.pathways.len());
+            for p in s.pathways {
+                pathways.push(Pathway {
+                    id: p.id,
+                    to_stop_id: p.to_stop_id,
+                    mode: decode(p.mode)?,
+                    is_bidirectional: decode(p.is_bidirectional)?,
+                    length: p.length,
+                    traversal_time: p.traversal_time,
+                    stair_count: p.stair_count,
+                    max_slope: p.max_slope,
+                    min_width: p.min_width,
+                    signposted_as: p.signposted_as,
+                    reversed_signposted_as: p.reversed_signposted_as,
+                });
+            }
+
+            stops.push(Arc::new(Stop {
+                id: s.id,
+                code: s.code,
+                name: s.name,
+                description: s.description,
+                location_type: decode(s.location_type)?,
+                parent_station: s.parent_station,
+                zone_id: s.zone_id,
+                url: s.url,
+                longitude: s.longitude,
+                latitude: s.latitude,
+                timezone: s.timezone,
+                wheelchair_boarding: decode(s.wheelchair_boarding)?,
+                level_id: s.level_id,
+                platform_code: s.platform_code,
+                transfers,
+                pathways,
+            }));
+        }
+
+        let mut trips = HashMap::with_capacity(self.trips.len());
+        for t in self.trips {
+            let mut stop_times = Vec::with_capacity(t.stop_times.len());
+            for st in t.stop_times {
+                let stop = match stops.get(st.stop as usize) {
+                    Some(stop) => stop.clone(),
+                    None => return Err(format!("Invalid stop index {}", st.stop)),
+                };
+                stop_times.push(StopTime {
+                    arrival_time: st.arrival_time,
+                    stop,
+                    departure_time: st.departure_time,
+                    pickup_type: decode(st.pickup_type)?,
+                    drop_off_type: decode(st.drop_off_type)?,
+                    stop_sequence: st.stop_sequence,
+                    stop_headsign: st.stop_headsign,
+                    continuous_pickup: decode(st.continuous_pickup)?,
+                    continuous_drop_off: decode(st.continuous_drop_off)?,
+                    shape_dist_traveled: st.shape_dist_traveled,
+                    timepoint: decode(st.timepoint)?,
+                });
+            }
+
+            let mut frequencies = Vec::with_capacity(t.frequencies.len());
+            for f in t.frequencies {
+                frequencies.push(Frequency {
+                    start_time: f.start_time,
+                    end_time: f.end_time,
+                    headway_secs: f.headway_secs,
+                    exact_times: f.exact_times.map(decode).transpose()?,
+                });
+            }
+
+            trips.insert(
+                t.id.clone(),
+                Trip {
+                    id: t.id,
+                    service_id: t.service_id,
+                    route_id: t.route_id,
+                    stop_times,
+                    shape_id: t.shape_id,
+                    trip_headsign: t.trip_headsign,
+                    trip_short_name: t.trip_short_name,
+                    direction_id: t.direction_id.map(decode).transpose()?,
+                    block_id: t.block_id,
+                    wheelchair_accessible: decode(t.wheelchair_accessible)?,
+                    bikes_allowed: decode(t.bikes_allowed)?,
+                    frequencies,
+                },
+            );
+        }
+
+        let mut calendar = HashMap::with_capacity(self.calendar.len());
+        for c in self.calendar {
+            let [monday, tuesday, wednesday, thursday, friday, saturday, sunday] = c.days;
+            calendar.insert(
+                c.id.clone(),
+                Calendar {
+                    id: c.id,
+                    monday,
+                    tuesday,
+                    wednesday,
+                    thursday,
+                    friday,
+                    saturday,
+                    sunday,
+                    start_date: decode_date(c.start_date)?,
+                    end_date: decode_date(c.end_date)?,
+                },
+            );
+        }
+
+        let mut calendar_dates: HashMap<String, Vec<CalendarDate>> = HashMap::new();
+        for d in self.calendar_dates {
+            calendar_dates
+                .entry(d.service_id.clone())
+                .or_default()
+                .push(CalendarDate {
+                    service_id: d.service_id,
+                    date: decode_date(d.date)?,
+                    exception_type: decode(d.exception_type)?,
+                });
+        }
+
+        let mut routes = HashMap::with_capacity(self.routes.len());
+        for r in self.routes {
+            routes.insert(
+                r.id.clone(),
+                Route {
+                    id: r.id,
+                    short_name: r.short_name,
+                    long_name: r.long_name,
+                    desc: r.desc,
+                    route_type: decode(r.route_type)?,
+                    url: r.url,
+                    agency_id: r.agency_id,
+                    order: r.order,
+                    color: RGB8::new(r.color[0], r.color[1], r.color[2]),
+                    text_color: RGB8::new(r.text_color[0], r.text_color[1], r.text_color[2]),
+                    continuous_pickup: decode(r.continuous_pickup)?,
+                    continuous_drop_off: decode(r.continuous_drop_off)?,
+                },
+            );
+        }
+
+        let mut shapes: HashMap<String, Vec<Shape>> = HashMap::new();
+        for s in self.shapes {
+            shapes.entry(s.id.clone()).or_default().push(s);
+        }
+
+        let mut fare_attributes = HashMap::with_capacity(self.fare_attributes.len());
+        for f in self.fare_attributes {
+            fare_attributes.insert(
+                f.id.clone(),
+                FareAttribute {
+                    id: f.id,
+                    price: f.price,
+                    currency: f.currency,
+                    payment_method: decode(f.payment_method)?,
+                    transfers: decode_transfers(f.transfers),
+                    agency_id: f.agency_id,
+                    transfer_duration: f.transfer_duration,
+                },
+            );
+        }
+
+        let mut feed_info = Vec::with_capacity(self.feed_info.len());
+        for f in self.feed_info {
+            feed_info.push(FeedInfo {
+                name: f.name,
+                url: f.url,
+                lang: f.lang,
+                default_lang: f.default_lang,
+                start_date: f.start_date.map(decode_date).transpose()?,
+                end_date: f.end_date.map(decode_date).transpose()?,
+                version: f.version,
+                contact_email: f.contact_email,
+                contact_url: f.contact_url,
+            });
+        }
+
+        Ok(Gtfs {
+            read_duration: self.read_duration,
+            calendar,
+            calendar_dates,
+            stops: stops.into_iter().map(|s| (s.id.clone(), s)).collect(),
+            routes,
+            trips,
+            agencies: self.agencies,
+            shapes,
+            fare_attributes,
+            feed_info,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::FeedSource;
+
+    /// Empty cache directory of its own for each test
+    fn cache(name: &str) -> SnapshotCache {
+        let dir = env::temp_dir().join(format!("tec-gtfs-cache-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        SnapshotCache { dir }
+    }
+
+    fn config() -> FeedConfig {
+        FeedConfig {
+            name: "tec".to_string(),
+            source: FeedSource::Directory(PathBuf::from("gtfs")),
+        }
+    }
+
+    /// Route `R1` calling at stations `S1` and `S2` on service `WEEK`, with
+    /// frequencies and fares whose codes are easily lost
+    fn snapshot() -> FeedSnapshot {
+        let mut gtfs = Gtfs::default();
+        let stops: Vec<Arc<Stop>> = (1..=2)
+            .map(|n| {
+                Arc::new(Stop {
+                    id: format!("S{}", n),
+                    name: format!("Stop {}", n),
+                    location_type: LocationType::StopArea,
+                    latitude: Some(50.4 + n as f64 / 100.0),
+                    longitude: Some(4.4),
+                    ..Default::default()
+                })
+            })
+            .collect();
+        for stop in stops.iter() {
+            gtfs.stops.insert(stop.id.clone(), stop.clone());
+        }
+        gtfs.routes.insert(
+            "R1".to_string(),
+            Route {
+                id: "R1".to_string(),
+                short_name: "1".to_string(),
+                route_type: RouteType::Bus,
+                color: RGB8::new(255, 0, 0),
+                ..Default::default()
+            },
+        );
+        gtfs.trips.insert(
+            "T1".to_string(),
+            Trip {
+                id: "T1".to_string(),
+                service_id: "WEEK".to_string(),
+                route_id: "R1".to_string(),
+                stop_times: stops
+                    .iter()
+                    .enumerate()
+                    .map(|(i, stop)| StopTime {
+                        stop: stop.clone(),
+                        stop_sequence: i as u16,
+                        arrival_time: Some(6 * 3600 + i as u32 * 120),
+                        ..Default::default()
+                    })
+                    .collect(),
+                frequencies: vec![
+                    Frequency {
+                        start_time: 6 * 3600,
+                        end_time: 9 * 3600,
+                        headway_secs: 600,
+                        exact_times: Some(ExactTimes::ScheduleBased),
+                    },
+                    Frequency {
+                        start_time: 9 * 3600,
+                        end_time: 12 * 3600,
+                        headway_secs: 900,
+                        exact_times: None,
+                    },
+                ],
+                ..Default::default()
+            },
+        );
+        let fares = [
+            ("F0", PaymentMethod::PreBoarding, Transfers::Unlimited),
+            ("F1", PaymentMethod::Aboard, Transfers::NoTransfer),
+            ("F2", PaymentMethod::Aboard, Transfers::TwoTransfers),
+            ("F3", PaymentMethod::Aboard, Transfers::Other(5)),
+        ];
+        for (id, payment_method, transfers) in fares {
+            gtfs.fare_attributes.insert(
+                id.to_string(),
+                FareAttribute {
+                    id: id.to_string(),
+                    price: "2.10".to_string(),
+                    currency: "EUR".to_string(),
+                    payment_method,
+                    transfers,
+                    agency_id: None,
+                    transfer_duration: Some(3600),
+                },
+            );
+        }
+        gtfs.calendar_dates.insert(
+            "WEEK".to_string(),
+            vec![CalendarDate {
+                service_id: "WEEK".to_string(),
+                date: NaiveDate::from_ymd_opt(2026, 10, 18).unwrap(),
+                exception_type: Exception::Added,
+            }],
+        );
+
+        let feed = Feed::from_parts(
+            &config(),
+            "checksum".to_string(),
+            gtfs,
+            ValidationReport::default(),
+        );
+        FeedSnapshot::build(1, vec![Arc::new(feed)])
+    }
+
+    fn key() -> String {
+        SnapshotCache::key([("tec", "checksum")].into_iter())
+    }
+
+    #[test]
+    fn round_trip() {
+        let cache = cache("round-trip");
+        cache.store(&key(), &snapshot());
+
+        let loaded = cache.load(&key(), 2, &[config()]).expect("cached snapshot");
+        let feed = &loaded.get_feeds()[0];
+        assert_eq!(feed.get_checksum(), "checksum");
+        let gtfs = feed.get_gtfs();
+
+        let stop = &gtfs.stops["S2"];
+        assert_eq!(stop.name, "Stop 2");
+        assert_eq!(stop.location_type, LocationType::StopArea);
+        let route = &gtfs.routes["R1"];
+        assert_eq!(route.route_type, RouteType::Bus);
+        assert_eq!(route.color, RGB8::new(255, 0, 0));
+        let trip = &gtfs.trips["T1"];
+        let stop_ids: Vec<&str> = trip
+            .stop_times
+            .iter()
+            .map(|st| st.stop.id.as_str())
+            .collect();
+        assert_eq!(stop_ids, vec!["S1", "S2"]);
+        assert_eq!(trip.stop_times[1].arrival_time, Some(6 * 3600 + 120));
+        // Stop times share the stops of the feed
+        assert!(Arc::ptr_eq(&trip.stop_times[0].stop, &gtfs.stops["S1"]));
+        assert_eq!(
+            gtfs.calendar_dates["WEEK"][0].exception_type,
+            Exception::Added
+        );
+
+        let frequencies: Vec<(u32, Option<ExactTimes>)> = trip
+            .frequencies
+            .iter()
+            .map(|f| (f.headway_secs, f.exact_times))
+            .collect();
+        assert_eq!(
+            frequencies,
+            vec![(600, Some(ExactTimes::ScheduleBased)), (900, None)]
+        );
+        let mut fares: Vec<(&str, PaymentMethod, Transfers, Option<usize>)> = gtfs
+            .fare_attributes
+            .values()
+            .map(|f| {
+                (
+                    f.id.as_str(),
+                    f.payment_method,
+                    f.transfers,
+                    f.transfer_duration,
+                )
+            })
+            .collect();
+        fares.sort_by_key(|fare| fare.0);
+        assert_eq!(
+            fares,
+            vec![
+                (
+                    "F0",
+                    PaymentMethod::PreBoarding,
+                    Transfers::Unlimited,
+                    Some(3600)
+                ),
+                (
+                    "F1",
+                    PaymentMethod::Aboard,
+                    Transfers::NoTransfer,
+                    Some(3600)
+                ),
+                (
+                    "F2",
+                    PaymentMethod::Aboard,
+                    Transfers::TwoTransfers,
+                    Some(3600)
+                ),
+                ("F3", PaymentMethod::Aboard, Transfers::Other(5), Some(3600)),
+            ]
+        );
+
+        let indexes = loaded.get_indexes();
+        assert!(indexes.interner.get("tec:S1").is_some());
+        assert_eq!(
+            indexes.get_stats().len(),
+            snapshot().get_indexes().get_stats().len()
+        );
+
+        let _ = std::fs::remove_dir_all(&cache.dir);
+    }
+
+    fn assert_codes<T: Code + PartialEq + std::fmt::Debug>(variants: &[T]) {
+        for variant in variants {
+            assert_eq!(T::from_code(variant.code()).as_ref(), Ok(variant));
+        }
+    }
+
+    #[test]
+    fn codes_round_trip() {
+        assert_codes(&[ExactTimes::FrequencyBased, ExactTimes::ScheduleBased]);
+        assert_codes(&[PaymentMethod::Aboard, PaymentMethod::PreBoarding]);
+        assert_codes(&[
+            RouteType::Tramway,
+            RouteType::Bus,
+            RouteType::Coach,
+            RouteType::Air,
+            RouteType::Taxi,
+            RouteType::Other(1700),
+        ]);
+        assert_codes(&[
+            LocationType::StopPoint,
+            LocationType::BoardingArea,
+            LocationType::Unknown(9),
+        ]);
+        assert_codes(&[PathwayMode::Walkway, PathwayMode::ExitGate]);
+        assert_codes(&[TimepointType::Approximate, TimepointType::Exact]);
+        assert_codes(&[
+            BikesAllowedType::NoBikeInfo,
+            BikesAllowedType::NoBikesAllowed,
+            BikesAllowedType::Unknown(7),
+        ]);
+        assert!(ExactTimes::from_code(2).is_err());
+        assert!(PathwayMode::from_code(0).is_err());
+
+        for transfers in [
+            Transfers::Unlimited,
+            Transfers::NoTransfer,
+            Transfers::UniqueTransfer,
+            Transfers::TwoTransfers,
+            Transfers::Other(-1),
+        ] {
+            assert_eq!(decode_transfers(encode_transfers(&transfers)), transfers);
+        }
+    }
+
+    #[test]
+    fn ignores_other_keys_and_feeds() {
+        let cache = cache("other-keys");
+        cache.store(&key(), &snapshot());
+
+        let other = SnapshotCache::key([("tec", "other")].into_iter());
+        assert!(cache.load(&other, 2, &[config()]).is_none());
+        assert!(cache.load(&key(), 2, &[]).is_none());
+
+        // Storing another key drops the previous snapshot
+        cache.store(&other, &snapshot());
+        assert!(cache.load(&key(), 2, &[config()]).is_none());
+        assert!(cache.load(&other, 2, &[config()]).is_some());
+
+        let _ = std::fs::remove_dir_all(&cache.dir);
+    }
+
+    #[test]
+    fn falls_back_on_a_truncated_file() {
+        let cache = cache("truncated");
+        cache.store(&key(), &snapshot());
+
+        let path = cache.path(&key());
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &bytes[..bytes.len() / 2]).unwrap();
+        assert!(cache.load(&key(), 2, &[config()]).is_none());
+
+        std::fs::write(&path, []).unwrap();
+        assert!(cache.load(&key(), 2, &[config()]).is_none());
+
+        let _ = std::fs::remove_dir_all(&cache.dir);
+    }
+
+    #[test]
+    fn falls_back_on_a_corrupted_file() {
+        let cache = cache("corrupted");
+        cache.store(&key(), &snapshot());
+
+        // A single flipped byte in the name of a stop still deserializes
+        let path = cache.path(&key());
+        let bytes = std::fs::read(&path).unwrap();
+        let at = bytes
+            .windows(6)
+            .position(|window| window == b"Stop 2")
+            .unwrap();
+        let mut corrupted = bytes.clone();
+        corrupted[at + 5] = b'3';
+        std::fs::write(&path, &corrupted).unwrap();
+        assert!(cache.load(&key(), 2, &[config()]).is_none());
+
+        let mut corrupted = bytes.clone();
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 0xff;
+        std::fs::write(&path, &corrupted).unwrap();
+        assert!(cache.load(&key(), 2, &[config()]).is_none());
+
+        let mut corrupted = bytes.clone();
+        corrupted.push(0);
+        std::fs::write(&path, &corrupted).unwrap();
+        assert!(cache.load(&key(), 2, &[config()]).is_none());
+
+        std::fs::write(&path, &bytes).unwrap();
+        assert!(cache.load(&key(), 2, &[config()]).is_some());
+
+        let _ = std::fs::remove_dir_all(&cache.dir);
+    }
+}
@@ -0,0 +1,169 @@
+use std::fmt;
+
+use serde::Serialize;
+
+use super::ValidationReport;
+
+/// Reason a feed could not be turned into a snapshot
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum LoadError {
+    /// The archive or one of its files could not be read
+    Io { message: String },
+    /// A file is not valid CSV or a row does not match the GTFS schema
+    Csv {
+        file: String,
+        line: Option<u64>,
+        message: String,
+        row: Option<Vec<String>>,
+    },
+    /// A file required by the GTFS reference is absent
+    MissingFile { file: String },
+    /// An entity references an id that is not defined in the feed
+    ReferentialIntegrity { id: String },
+    /// A field holds a value that cannot be interpreted (time, color, ...)
+    InvalidValue { message: String },
+    /// The loader itself failed (panicked task, ...)
+    Internal { message: String },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io { message } => write!(f, "I/O error: {}", message),
+            LoadError::Csv {
+                file,
+                line: Some(line),
+                message,
+                ..
+            } => write!(f, "CSV error in {} at line {}: {}", file, line, message),
+            LoadError::Csv { file, message, .. } => {
+                write!(f, "CSV error in {}: {}", file, message)
+            }
+            LoadError::MissingFile { file } => write!(f, "Missing required file {}", file),
+            LoadError::ReferentialIntegrity { id } => {
+                write!(f, "Referential integrity error: unknown id {}", id)
+            }
+            LoadError::InvalidValue { message } => write!(f, "Invalid value: {}", message),
+            LoadError::Internal { message } => write!(f, "Internal error: {}", message),
+        }
+    }
+}
+
+impl From<gtfs_structures::Error> for LoadError {
+    fn from(err: gtfs_structures::Error) -> Self {
+        use gtfs_structures::Error;
+
+        match err {
+            Error::MissingFile(file) => LoadError::MissingFile { file },
+            Error::ReferenceError(id) => LoadError::ReferentialIntegrity { id },
+            Error::IO(e) => LoadError::Io {
+                message: e.to_string(),
+            },
+            Error::NamedFileIO { file_name, source } => LoadError::Io {
+                message: format!("{}: {}", file_name, source),
+            },
+            Error::NotFileNorDirectory(path) => LoadError::Io {
+                message: format!("{} is neither a file nor a directory", path),
+            },
+            Error::CSVError {
+                file_name,
+                source,
+                line_in_error,
+            } => LoadError::Csv {
+                file: file_name,
+                line: source.position().map(|p| p.line()),
+                message: source.to_string(),
+                row: line_in_error.map(|l| l.values),
+            },
+            Error::Zip(e) => LoadError::Io {
+                message: e.to_string(),
+            },
+            Error::Fetch(e) => LoadError::Io {
+                message: e.to_string(),
+            },
+            e @ (Error::InvalidTime(_) | Error::InvalidColor(_)) => LoadError::InvalidValue {
+                message: e.to_string(),
+            },
+        }
+    }
+}
+
+impl From<tokio::task::JoinError> for LoadError {
+    fn from(err: tokio::task::JoinError) -> Self {
+        if !err.is_panic() {
+            return LoadError::Internal {
+                message: err.to_string(),
+            };
+        }
+
+        let payload = err.into_panic();
+        let message = match payload.downcast_ref::<&str>() {
+            Some(message) => message.to_string(),
+            None => match payload.downcast_ref::<String>() {
+                Some(message) => message.clone(),
+                None => "Loader panicked".to_string(),
+            },
+        };
+        LoadError::Internal { message }
+    }
+}
+
+/// Load error tied to the feed that caused it, `None` when building the
+/// indexes spanning every feed failed
+#[derive(Debug, Serialize)]
+pub struct FeedError {
+    pub feed: Option<String>,
+    #[serde(flatten)]
+    pub error: LoadError,
+    /// Report of the feed when it was read but could not be assembled,
+    /// usually listing the dangling references
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub validation: Option<Box<ValidationReport>>,
+}
+
+impl FeedError {
+    pub fn new(feed: &str, error: LoadError) -> Self {
+        Self {
+            feed: Some(feed.to_string()),
+            error,
+            validation: None,
+        }
+    }
+}
+
+impl fmt::Display for FeedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.feed {
+            Some(feed) => write!(f, "[{}] {}", feed, self.error),
+            None => write!(f, "{}", self.error),
+        }
+    }
+}
+
+/// Reason a refresh did not publish a new snapshot
+#[derive(Debug)]
+pub enum RefreshError {
+    /// Missing or wrong secret
+    Unauthorized,
+    /// No configured feed has this name
+    UnknownFeed(String),
+    /// A feed could not be loaded, the previous snapshot is still served
+    Load(FeedError),
+}
+
+impl fmt::Display for RefreshError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RefreshError::Unauthorized => write!(f, "Unauthorized"),
+            RefreshError::UnknownFeed(name) => write!(f, "Unknown feed {}", name),
+            RefreshError::Load(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<FeedError> for RefreshError {
+    fn from(err: FeedError) -> Self {
+        RefreshError::Load(err)
+    }
+}
@@ -0,0 +1,110 @@
+use chrono::{DateTime, Utc};
+use gtfs_structures::Gtfs;
+
+use super::{FeedConfig, FeedError, FeedSource, FetchedFeed, ValidationReport};
+use crate::logger;
+
+/// Separator between the feed name and the GTFS id in namespaced ids
+pub const NAMESPACE_SEPARATOR: char = ':';
+
+/// One parsed GTFS feed. Feeds are shared between snapshots so a feed that
+/// did not change is not parsed again when another one is reloaded.
+pub struct Feed {
+    name: String,
+    source: FeedSource,
+    checksum: String,
+    loaded_at: DateTime<Utc>,
+    gtfs: Gtfs,
+    validation: ValidationReport,
+}
+
+impl Feed {
+    pub fn load(config: &FeedConfig, fetched: FetchedFeed) -> Result<Self, FeedError> {
+        logger::fine("FETCHER", &format!("Loading GTFS {}", config.name));
+        let start_time = std::time::Instant::now();
+        let checksum = fetched.get_checksum().to_string();
+        let raw = fetched
+            .read()
+            .map_err(|error| FeedError::new(&config.name, error))?;
+
+        let validation = ValidationReport::build(&raw);
+        validation.log(&config.name);
+
+        let gtfs = match Gtfs::try_from(raw) {
+            Ok(gtfs) => gtfs,
+            Err(e) => {
+                return Err(FeedError {
+                    validation: Some(Box::new(validation)),
+                    ..FeedError::new(&config.name, e.into())
+                })
+            }
+        };
+        logger::fine(
+            "FETCHER",
+            &format!(
+                "Loaded GTFS {} from {} (sha256 {}): [{:?}]",
+                config.name,
+                config.source,
+                checksum,
+                start_time.elapsed()
+            ),
+        );
+
+        Ok(Self {
+            name: config.name.clone(),
+            source: config.source.clone(),
+            checksum,
+            loaded_at: Utc::now(),
+            gtfs,
+            validation,
+        })
+    }
+
+    /// Feed restored from the binary cache
+    pub fn from_parts(
+        config: &FeedConfig,
+        checksum: String,
+        gtfs: Gtfs,
+        validation: ValidationReport,
+    ) -> Self {
+        Self {
+            name: config.name.clone(),
+            source: config.source.clone(),
+            checksum,
+            loaded_at: Utc::now(),
+            gtfs,
+            validation,
+        }
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_source(&self) -> &FeedSource {
+        &self.source
+    }
+
+    /// sha256 of the content this feed was parsed from
+    pub fn get_checksum(&self) -> &str {
+        &self.checksum
+    }
+
+    pub fn get_loaded_at(&self) -> DateTime<Utc> {
+        self.loaded_at
+    }
+
+    pub fn get_gtfs(&self) -> &Gtfs {
+        &self.gtfs
+    }
+
+    /// Issues found when the feed was loaded
+    pub fn get_validation(&self) -> &ValidationReport {
+        &self.validation
+    }
+
+    /// `tec` + `X1234` => `tec:X1234`
+    pub fn namespaced(&self, id: &str) -> String {
+        format!("{}{}{}", self.name, NAMESPACE_SEPARATOR, id)
+    }
+}
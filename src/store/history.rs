@@ -0,0 +1,655 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    env,
+    sync::Arc,
+};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use gtfs_structures::{Calendar, Exception, Gtfs, Route, Stop, Trip};
+use serde::Serialize;
+
+use super::{Feed, FeedSnapshot};
+use crate::{logger, quadtree::Coordinate};
+
+const DEFAULT_HISTORY_SIZE: usize = 10;
+
+/// Metadata of the last published snapshots, most recent last
+pub struct History {
+    size: usize,
+    versions: VecDeque<Arc<FeedVersion>>,
+}
+
+/// What one published snapshot held and how it differs from the previous one
+#[derive(Serialize)]
+pub struct FeedVersion {
+    pub version: u64,
+    pub loaded_at: DateTime<Utc>,
+    pub feeds: Vec<FeedSummary>,
+    /// `None` for the first snapshot
+    pub diff: Option<SnapshotDiff>,
+}
+
+#[derive(Serialize)]
+pub struct FeedSummary {
+    pub name: String,
+    pub checksum: String,
+    pub routes: usize,
+    pub stops: usize,
+    pub trips: usize,
+    pub services: usize,
+}
+
+#[derive(Serialize)]
+pub struct SnapshotDiff {
+    pub previous_version: u64,
+    /// Only the feeds whose content changed
+    pub feeds: Vec<FeedDiff>,
+}
+
+/// Changes in one feed, ids are namespaced
+#[derive(Serialize)]
+pub struct FeedDiff {
+    pub feed: String,
+    pub previous_checksum: Option<String>,
+    pub checksum: String,
+    pub routes: EntityDiff,
+    pub stops: StopDiff,
+    pub trips: EntityDiff,
+    /// Services whose days, validity or exceptions changed
+    pub calendar: EntityDiff,
+}
+
+#[derive(Serialize, Default)]
+pub struct EntityDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub modified: Vec<String>,
+}
+
+#[derive(Serialize, Default)]
+pub struct StopDiff {
+    #[serde(flatten)]
+    pub changes: EntityDiff,
+    /// Stops whose coordinates changed, whether or not anything else did
+    pub moved: Vec<MovedStop>,
+}
+
+#[derive(Serialize)]
+pub struct MovedStop {
+    pub id: String,
+    pub from: Coordinate,
+    pub to: Coordinate,
+    /// Distance in meters
+    pub distance: f64,
+}
+
+impl History {
+    /// Keep `GTFS_HISTORY_SIZE` versions, 10 by default
+    pub fn from_env() -> Self {
+        let size = match env::var("GTFS_HISTORY_SIZE") {
+            Ok(raw) => match raw.trim().parse::<usize>() {
+                Ok(size) if size > 0 => size,
+                _ => {
+                    logger::warn(
+                        "HISTORY",
+                        &format!(
+                            "Invalid GTFS_HISTORY_SIZE {:?}, keeping {} versions",
+                            raw, DEFAULT_HISTORY_SIZE
+                        ),
+                    );
+                    DEFAULT_HISTORY_SIZE
+                }
+            },
+            Err(_) => DEFAULT_HISTORY_SIZE,
+        };
+
+        Self {
+            size,
+            versions: VecDeque::with_capacity(size),
+        }
+    }
+
+    pub fn push(&mut self, version: FeedVersion) {
+        if self.versions.len() == self.size {
+            self.versions.pop_front();
+        }
+        self.versions.push_back(Arc::new(version));
+    }
+
+    pub fn get_versions(&self) -> Vec<Arc<FeedVersion>> {
+        self.versions.iter().cloned().collect()
+    }
+
+    pub fn get_version(&self, version: u64) -> Option<Arc<FeedVersion>> {
+        self.versions.iter().find(|v| v.version == version).cloned()
+    }
+
+    pub fn get_latest(&self) -> Option<Arc<FeedVersion>> {
+        self.versions.back().cloned()
+    }
+}
+
+impl FeedVersion {
+    /// Describe `current`, diffing the feeds that changed since `previous`
+    pub fn build(previous: Option<&FeedSnapshot>, current: &FeedSnapshot) -> Self {
+        let feeds = current
+            .get_feeds()
+            .iter()
+            .map(|feed| {
+                let gtfs = feed.get_gtfs();
+                FeedSummary {
+                    name: feed.get_name().to_string(),
+                    checksum: feed.get_checksum().to_string(),
+                    routes: gtfs.routes.len(),
+                    stops: gtfs.stops.len(),
+                    trips: gtfs.trips.len(),
+                    services: gtfs
+                        .calendar
+                        .keys()
+                        .chain(gtfs.calendar_dates.keys())
+                        .collect::<HashSet<_>>()
+                        .len(),
+                }
+            })
+            .collect();
+
+        let diff = previous.map(|previous| {
+            let start_time = std::time::Instant::now();
+            let feeds: Vec<FeedDiff> = current
+                .get_feeds()
+                .iter()
+                .filter_map(|feed| {
+                    let old = previous.get_feed(feed.get_name());
+                    match old {
+                        Some(old) if Arc::ptr_eq(old, feed) => None,
+                        Some(old) if old.get_checksum() == feed.get_checksum() => None,
+                        _ => Some(FeedDiff::build(old.map(|f| f.as_ref()), feed)),
+                    }
+                })
+                .collect();
+            logger::fine(
+                "HISTORY",
+                &format!(
+                    "Diffed feed version {} against {}: [{:?}]",
+                    current.get_version(),
+                    previous.get_version(),
+                    start_time.elapsed()
+                ),
+            );
+
+            SnapshotDiff {
+                previous_version: previous.get_version(),
+                feeds,
+            }
+        });
+
+        Self {
+            version: current.get_version(),
+            loaded_at: current.get_loaded_at(),
+            feeds,
+            diff,
+        }
+    }
+}
+
+impl FeedDiff {
+    fn build(old: Option<&Feed>, new: &Feed) -> Self {
+        let empty = Gtfs::default();
+        let old_gtfs = old.map(|f| f.get_gtfs()).unwrap_or(&empty);
+        let new_gtfs = new.get_gtfs();
+
+        let mut stops = StopDiff {
+            changes: diff_maps(new, &old_gtfs.stops, &new_gtfs.stops, same_stop),
+            moved: Vec::new(),
+        };
+        for (id, stop) in new_gtfs.stops.iter() {
+            let old_stop = match old_gtfs.stops.get(id) {
+                Some(old_stop) => old_stop,
+                None => continue,
+            };
+            if let (Some(from), Some(to)) = (coordinate(old_stop), coordinate(stop)) {
+                let distance = from.distance(&to);
+                if distance > 0.0 {
+                    stops.moved.push(MovedStop {
+                        id: new.namespaced(id),
+                        from,
+                        to,
+                        distance,
+                    });
+                }
+            }
+        }
+        stops.moved.sort_by(|a, b| a.id.cmp(&b.id));
+
+        Self {
+            feed: new.get_name().to_string(),
+            previous_checksum: old.map(|f| f.get_checksum().to_string()),
+            checksum: new.get_checksum().to_string(),
+            routes: diff_maps(new, &old_gtfs.routes, &new_gtfs.routes, same_route),
+            stops,
+            trips: diff_maps(new, &old_gtfs.trips, &new_gtfs.trips, same_trip),
+            calendar: diff_maps(new, &services(old_gtfs), &services(new_gtfs), |a, b| a == b),
+        }
+    }
+}
+
+/// Ids added, removed, and present in both with `same` returning false
+fn diff_maps<T>(
+    feed: &Feed,
+    old: &HashMap<String, T>,
+    new: &HashMap<String, T>,
+    same: impl Fn(&T, &T) -> bool,
+) -> EntityDiff {
+    let mut diff = EntityDiff::default();
+    for (id, value) in new.iter() {
+        match old.get(id) {
+            None => diff.added.push(feed.namespaced(id)),
+            Some(old_value) if !same(old_value, value) => diff.modified.push(feed.namespaced(id)),
+            Some(_) => {}
+        }
+    }
+    for id in old.keys() {
+        if !new.contains_key(id) {
+            diff.removed.push(feed.namespaced(id));
+        }
+    }
+
+    diff.added.sort();
+    diff.removed.sort();
+    diff.modified.sort();
+    diff
+}
+
+fn coordinate(stop: &Stop) -> Option<Coordinate> {
+    match (stop.latitude, stop.longitude) {
+        (Some(lat), Some(lon)) => Some(Coordinate::new(lon, lat)),
+        _ => None,
+    }
+}
+
+/// Everything but the coordinates, reported apart as moves
+fn same_stop(a: &Arc<Stop>, b: &Arc<Stop>) -> bool {
+    a.name == b.name
+        && a.code == b.code
+        && a.description == b.description
+        && a.location_type == b.location_type
+        && a.parent_station == b.parent_station
+        && a.zone_id == b.zone_id
+        && a.wheelchair_boarding == b.wheelchair_boarding
+        && a.platform_code == b.platform_code
+        && (a.latitude.is_some() && a.longitude.is_some())
+            == (b.latitude.is_some() && b.longitude.is_some())
+}
+
+fn same_route(a: &Route, b: &Route) -> bool {
+    a.short_name == b.short_name
+        && a.long_name == b.long_name
+        && a.desc == b.desc
+        && a.route_type == b.route_type
+        && a.agency_id == b.agency_id
+        && a.order == b.order
+        && a.color == b.color
+        && a.text_color == b.text_color
+}
+
+fn same_trip(a: &Trip, b: &Trip) -> bool {
+    a.route_id == b.route_id
+        && a.service_id == b.service_id
+        && a.shape_id == b.shape_id
+        && a.trip_headsign == b.trip_headsign
+        && a.direction_id == b.direction_id
+        && a.stop_times.len() == b.stop_times.len()
+        && a.stop_times.iter().zip(b.stop_times.iter()).all(|(a, b)| {
+            a.stop.id == b.stop.id
+                && a.arrival_time == b.arrival_time
+                && a.departure_time == b.departure_time
+                && a.stop_sequence == b.stop_sequence
+        })
+}
+
+/// Comparable view of a service: weekdays and validity from `calendar`,
+/// sorted exceptions from `calendar_dates`
+#[derive(PartialEq)]
+struct Service {
+    days: Option<([bool; 7], NaiveDate, NaiveDate)>,
+    exceptions: Vec<(NaiveDate, bool)>,
+}
+
+fn services(gtfs: &Gtfs) -> HashMap<String, Service> {
+    let ids: HashSet<&String> = gtfs
+        .calendar
+        .keys()
+        .chain(gtfs.calendar_dates.keys())
+        .collect();
+
+    ids.into_iter()
+        .map(|id| {
+            let days = gtfs.calendar.get(id).map(|c: &Calendar| {
+                (
+                    [
+                        c.monday,
+                        c.tuesday,
+                        c.wednesday,
+                        c.thursday,
+                        c.friday,
+                        c.saturday,
+                        c.sunday,
+                    ],
+                    c.start_date,
+                    c.end_date,
+                )
+            });
+
+            let mut exceptions: Vec<(NaiveDate, bool)> = gtfs
+                .calendar_dates
+                .get(id)
+                .map(|dates| {
+                    dates
+                        .iter()
+                        .map(|d| (d.date, d.exception_type == Exception::Added))
+                        .collect()
+                })
+                .unwrap_or_default();
+            exceptions.sort();
+
+            (id.clone(), Service { days, exceptions })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use gtfs_structures::{CalendarDate, StopTime};
+
+    use super::*;
+    use crate::store::{FeedConfig, FeedSource, ValidationReport};
+
+    fn date(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 1, day).unwrap()
+    }
+
+    fn stop(id: &str, name: &str, latitude: f64) -> Arc<Stop> {
+        Arc::new(Stop {
+            id: id.to_string(),
+            name: name.to_string(),
+            latitude: Some(latitude),
+            longitude: Some(4.4),
+            ..Default::default()
+        })
+    }
+
+    fn calendar_date(service_id: &str, day: u32, exception_type: Exception) -> CalendarDate {
+        CalendarDate {
+            service_id: service_id.to_string(),
+            date: date(day),
+            exception_type,
+        }
+    }
+
+    /// Route `R1` with trips `T1` and `T2` calling at `S1` and `S2`, and
+    /// `S3` served by no trip. `T1` runs on weekdays (`WEEK`), with a day
+    /// off on the 1st, `T2` on the dates of `EXTRA`
+    fn gtfs() -> Gtfs {
+        let mut gtfs = Gtfs::default();
+        for (id, latitude) in [("S1", 50.4), ("S2", 50.5), ("S3", 50.6)] {
+            gtfs.stops.insert(id.to_string(), stop(id, id, latitude));
+        }
+        gtfs.routes.insert(
+            "R1".to_string(),
+            Route {
+                id: "R1".to_string(),
+                short_name: "1".to_string(),
+                ..Default::default()
+            },
+        );
+        for (id, service_id) in [("T1", "WEEK"), ("T2", "EXTRA")] {
+            let stop_times = ["S1", "S2"]
+                .iter()
+                .enumerate()
+                .map(|(i, stop_id)| StopTime {
+                    stop: gtfs.stops[*stop_id].clone(),
+                    departure_time: Some(3600 * 8 + 600 * i as u32),
+                    stop_sequence: i as u16,
+                    ..Default::default()
+                })
+                .collect();
+            gtfs.trips.insert(
+                id.to_string(),
+                Trip {
+                    id: id.to_string(),
+                    route_id: "R1".to_string(),
+                    service_id: service_id.to_string(),
+                    stop_times,
+                    ..Default::default()
+                },
+            );
+        }
+        gtfs.calendar.insert(
+            "WEEK".to_string(),
+            Calendar {
+                id: "WEEK".to_string(),
+                monday: true,
+                tuesday: true,
+                wednesday: true,
+                thursday: true,
+                friday: true,
+                saturday: false,
+                sunday: false,
+                start_date: date(1),
+                end_date: date(31),
+            },
+        );
+        gtfs.calendar_dates.insert(
+            "WEEK".to_string(),
+            vec![calendar_date("WEEK", 1, Exception::Deleted)],
+        );
+        gtfs.calendar_dates.insert(
+            "EXTRA".to_string(),
+            vec![
+                calendar_date("EXTRA", 6, Exception::Added),
+                calendar_date("EXTRA", 7, Exception::Added),
+            ],
+        );
+        gtfs
+    }
+
+    fn feed(name: &str, checksum: &str, gtfs: Gtfs) -> Arc<Feed> {
+        let config = FeedConfig {
+            name: name.to_string(),
+            source: FeedSource::Directory("gtfs".into()),
+        };
+        Arc::new(Feed::from_parts(
+            &config,
+            checksum.to_string(),
+            gtfs,
+            ValidationReport::default(),
+        ))
+    }
+
+    fn ids(diff: &EntityDiff) -> (Vec<&str>, Vec<&str>, Vec<&str>) {
+        fn ids(ids: &[String]) -> Vec<&str> {
+            ids.iter().map(String::as_str).collect()
+        }
+        (ids(&diff.added), ids(&diff.removed), ids(&diff.modified))
+    }
+
+    #[test]
+    fn unchanged_feed() {
+        let diff = FeedDiff::build(Some(&feed("tec", "a", gtfs())), &feed("tec", "b", gtfs()));
+        assert_eq!(diff.previous_checksum.as_deref(), Some("a"));
+        assert_eq!(diff.checksum, "b");
+        for entities in [
+            &diff.routes,
+            &diff.stops.changes,
+            &diff.trips,
+            &diff.calendar,
+        ] {
+            assert_eq!(ids(entities), (vec![], vec![], vec![]));
+        }
+        assert!(diff.stops.moved.is_empty());
+    }
+
+    #[test]
+    fn stops() {
+        let mut gtfs = gtfs();
+        // Moved only
+        gtfs.stops
+            .insert("S1".to_string(), stop("S1", "S1", 50.401));
+        // Renamed only
+        gtfs.stops
+            .insert("S2".to_string(), stop("S2", "Gare", 50.5));
+        gtfs.stops.remove("S3");
+        gtfs.stops.insert("S4".to_string(), stop("S4", "S4", 50.7));
+
+        let diff = FeedDiff::build(
+            Some(&feed("tec", "a", self::gtfs())),
+            &feed("tec", "b", gtfs),
+        );
+        assert_eq!(
+            ids(&diff.stops.changes),
+            (vec!["tec:S4"], vec!["tec:S3"], vec!["tec:S2"])
+        );
+        assert_eq!(diff.stops.moved.len(), 1);
+        let moved = &diff.stops.moved[0];
+        assert_eq!(moved.id, "tec:S1");
+        assert_eq!((moved.from.get_y(), moved.to.get_y()), (50.4, 50.401));
+        assert!((moved.distance - 111.2).abs() < 1.0, "{}", moved.distance);
+    }
+
+    #[test]
+    fn trips_and_routes() {
+        let mut gtfs = gtfs();
+        gtfs.trips.get_mut("T1").unwrap().stop_times[1].departure_time = Some(3600 * 9);
+        gtfs.trips.get_mut("T2").unwrap().trip_headsign = Some("Gare".to_string());
+        gtfs.trips.insert(
+            "T3".to_string(),
+            Trip {
+                id: "T3".to_string(),
+                route_id: "R2".to_string(),
+                ..Default::default()
+            },
+        );
+        gtfs.routes.insert(
+            "R2".to_string(),
+            Route {
+                id: "R2".to_string(),
+                ..Default::default()
+            },
+        );
+        gtfs.routes.get_mut("R1").unwrap().color = rgb::RGB8::new(255, 0, 0);
+
+        let diff = FeedDiff::build(
+            Some(&feed("tec", "a", self::gtfs())),
+            &feed("tec", "b", gtfs),
+        );
+        assert_eq!(ids(&diff.routes), (vec!["tec:R2"], vec![], vec!["tec:R1"]));
+        assert_eq!(
+            ids(&diff.trips),
+            (vec!["tec:T3"], vec![], vec!["tec:T1", "tec:T2"])
+        );
+    }
+
+    #[test]
+    fn calendar_exceptions() {
+        let mut gtfs = gtfs();
+        // Same exceptions in another order
+        gtfs.calendar_dates.get_mut("EXTRA").unwrap().reverse();
+        // The day off becomes a day on
+        gtfs.calendar_dates.get_mut("WEEK").unwrap()[0].exception_type = Exception::Added;
+        gtfs.calendar_dates.insert(
+            "SUNDAY".to_string(),
+            vec![calendar_date("SUNDAY", 7, Exception::Added)],
+        );
+
+        let diff = FeedDiff::build(
+            Some(&feed("tec", "a", self::gtfs())),
+            &feed("tec", "b", gtfs),
+        );
+        assert_eq!(
+            ids(&diff.calendar),
+            (vec!["tec:SUNDAY"], vec![], vec!["tec:WEEK"])
+        );
+
+        // Dropping the exceptions of a service with weekdays keeps it
+        let mut gtfs = self::gtfs();
+        gtfs.calendar_dates.remove("WEEK");
+        gtfs.calendar_dates.remove("EXTRA");
+        let diff = FeedDiff::build(
+            Some(&feed("tec", "a", self::gtfs())),
+            &feed("tec", "b", gtfs),
+        );
+        assert_eq!(
+            ids(&diff.calendar),
+            (vec![], vec!["tec:EXTRA"], vec!["tec:WEEK"])
+        );
+    }
+
+    #[test]
+    fn services_merge_calendar_and_dates() {
+        let services = services(&gtfs());
+        assert_eq!(services.len(), 2);
+
+        let week = &services["WEEK"];
+        assert_eq!(
+            week.days,
+            Some((
+                [true, true, true, true, true, false, false],
+                date(1),
+                date(31)
+            ))
+        );
+        assert_eq!(week.exceptions, vec![(date(1), false)]);
+
+        let extra = &services["EXTRA"];
+        assert_eq!(extra.days, None);
+        assert_eq!(extra.exceptions, vec![(date(6), true), (date(7), true)]);
+    }
+
+    #[test]
+    fn versions_diff_changed_feeds_only() {
+        let mut changed = gtfs();
+        changed.stops.remove("S3");
+        let first =
+            FeedSnapshot::build(1, vec![feed("tec", "a", gtfs()), feed("stib", "a", gtfs())]);
+        let second = FeedSnapshot::build(
+            2,
+            vec![
+                feed("tec", "a", gtfs()),
+                feed("stib", "b", changed),
+                feed("delijn", "a", gtfs()),
+            ],
+        );
+
+        let version = FeedVersion::build(None, &first);
+        assert_eq!(version.version, 1);
+        assert!(version.diff.is_none());
+        let summary = &version.feeds[0];
+        assert_eq!(
+            (
+                summary.routes,
+                summary.stops,
+                summary.trips,
+                summary.services
+            ),
+            (1, 3, 2, 2)
+        );
+
+        let version = FeedVersion::build(Some(&first), &second);
+        let diff = version.diff.unwrap();
+        assert_eq!(diff.previous_version, 1);
+        let feeds: Vec<&str> = diff.feeds.iter().map(|f| f.feed.as_str()).collect();
+        assert_eq!(feeds, vec!["stib", "delijn"]);
+
+        let stib = &diff.feeds[0];
+        assert_eq!(ids(&stib.stops.changes), (vec![], vec!["stib:S3"], vec![]));
+
+        // A new feed has everything added
+        let delijn = &diff.feeds[1];
+        assert_eq!(delijn.previous_checksum, None);
+        assert_eq!(
+            ids(&delijn.stops.changes).0,
+            vec!["delijn:S1", "delijn:S2", "delijn:S3"]
+        );
+        assert_eq!(ids(&delijn.calendar).0, vec!["delijn:EXTRA", "delijn:WEEK"]);
+    }
+}
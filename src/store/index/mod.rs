@@ -0,0 +1,224 @@
+use std::{mem::size_of, sync::Arc, time::Duration};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use super::{Feed, Interner, Symbol};
+use crate::logger;
+
+mod reverse_stops;
+mod shapes;
+mod stops;
+
+pub use reverse_stops::ReverseStopIndex;
+pub use shapes::{ShapeIndex, ShapeMatch};
+pub use stops::{spatial_index_kind, StopIndex, StopTree};
+
+/// Structure derived from the loaded feeds and stored in every snapshot.
+///
+/// Indexes read the `Gtfs` of each feed and hold the [`Symbol`] of
+/// namespaced ids (`tec:X1234`), resolved back to strings by the API. They
+/// are serialized with the snapshot cache, so changing the layout of one
+/// means bumping the cache format.
+///
+/// [`Symbol`]: super::Symbol
+pub trait Index: Sized + Send + Serialize + DeserializeOwned {
+    /// Name used in logs and stats
+    const NAME: &'static str;
+
+    /// Ids of `feed`, without namespace, the index looks up in the interner
+    fn interned_ids(feed: &Feed) -> Vec<&str>;
+
+    fn build(feeds: &[Arc<Feed>], interner: &Interner) -> Self;
+
+    /// Approximate heap size in bytes
+    fn memory_usage(&self) -> usize;
+
+    /// Approximate heap size in bytes if every symbol was its own `String`
+    fn memory_usage_with_strings(&self, interner: &Interner) -> usize;
+}
+
+/// Build time and size of one index
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexStats {
+    pub name: String,
+    pub build_time: Duration,
+    pub memory: usize,
+    /// Size the index would have with `String` ids instead of symbols
+    pub memory_with_strings: usize,
+}
+
+fn build<T: Index>(feeds: &[Arc<Feed>], interner: &Interner) -> (T, IndexStats) {
+    let start_time = std::time::Instant::now();
+    let index = T::build(feeds, interner);
+    let stats = IndexStats {
+        name: T::NAME.to_string(),
+        build_time: start_time.elapsed(),
+        memory: index.memory_usage(),
+        memory_with_strings: index.memory_usage_with_strings(interner),
+    };
+    logger::fine(
+        "FETCHER",
+        &format!(
+            "Built index {}: [{:?}] {} KiB",
+            stats.name,
+            stats.build_time,
+            stats.memory / 1024
+        ),
+    );
+    (index, stats)
+}
+
+/// Approximate heap size of an id held as a `String`
+pub(crate) fn string_size(interner: &Interner, symbol: &Symbol) -> usize {
+    size_of::<String>() + interner.resolve(*symbol).len()
+}
+
+/// Declare the indexes of a snapshot. Each one is built on its own thread
+/// when a snapshot is built.
+macro_rules! indexes {
+    ($($field:ident: $index:ty,)*) => {
+        /// Every index of a snapshot, see [`Index`]
+        #[derive(Serialize, Deserialize)]
+        pub struct Indexes {
+            pub interner: Interner,
+            $(pub $field: $index,)*
+            stats: Vec<IndexStats>,
+        }
+
+        impl Indexes {
+            /// Intern the ids every index asks for then build them in
+            /// parallel. A panic while building one is propagated to the
+            /// caller.
+            pub fn build(feeds: &[Arc<Feed>]) -> Self {
+                let interner = Interner::new(feeds.iter().flat_map(|feed| {
+                    [$(<$index as Index>::interned_ids(feed),)*]
+                        .into_iter()
+                        .flatten()
+                        .map(move |id| feed.namespaced(id))
+                }));
+                let indexes = std::thread::scope(|scope| {
+                    $(let $field = scope.spawn(|| build::<$index>(feeds, &interner));)*
+                    let mut stats = Vec::new();
+                    $(
+                        let $field = match $field.join() {
+                            Ok((index, index_stats)) => {
+                                stats.push(index_stats);
+                                index
+                            }
+                            Err(e) => std::panic::resume_unwind(e),
+                        };
+                    )*
+                    ($($field,)* stats)
+                });
+                let ($($field,)* stats) = indexes;
+
+                let indexes = Self { interner, $($field,)* stats };
+                indexes.log_memory();
+                indexes
+            }
+        }
+    };
+}
+
+indexes! {
+    stops: StopIndex,
+    reverse_stops: ReverseStopIndex,
+    shapes: ShapeIndex,
+}
+
+impl Indexes {
+    pub fn get_stats(&self) -> &[IndexStats] {
+        &self.stats
+    }
+
+    /// Size of the indexes and the interner, and the size the indexes would
+    /// have with `String` ids
+    pub fn get_memory(&self) -> (usize, usize) {
+        let interned =
+            self.interner.memory_usage() + self.stats.iter().map(|s| s.memory).sum::<usize>();
+        let with_strings = self.stats.iter().map(|s| s.memory_with_strings).sum();
+        (interned, with_strings)
+    }
+
+    fn log_memory(&self) {
+        let (interned, with_strings) = self.get_memory();
+        logger::fine(
+            "FETCHER",
+            &format!(
+                "Indexes use {} KiB with {} interned ids ({} KiB with string ids)",
+                interned / 1024,
+                self.interner.len(),
+                with_strings / 1024
+            ),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use gtfs_structures::{Gtfs, Route, Shape, Stop};
+
+    use super::*;
+    use crate::store::{FeedConfig, FeedSource, ValidationReport};
+
+    fn feed() -> Arc<Feed> {
+        let mut gtfs = Gtfs::default();
+        gtfs.stops.insert(
+            "S1".to_string(),
+            Arc::new(Stop {
+                id: "S1".to_string(),
+                latitude: Some(50.4),
+                longitude: Some(4.4),
+                ..Default::default()
+            }),
+        );
+        gtfs.routes.insert(
+            "R1".to_string(),
+            Route {
+                id: "R1".to_string(),
+                ..Default::default()
+            },
+        );
+        gtfs.shapes.insert(
+            "SH1".to_string(),
+            vec![Shape {
+                id: "SH1".to_string(),
+                latitude: 50.4,
+                longitude: 4.4,
+                sequence: 1,
+                dist_traveled: None,
+            }],
+        );
+        let config = FeedConfig {
+            name: "tec".to_string(),
+            source: FeedSource::Directory(PathBuf::from("gtfs")),
+        };
+        Arc::new(Feed::from_parts(
+            &config,
+            String::new(),
+            gtfs,
+            ValidationReport::default(),
+        ))
+    }
+
+    #[test]
+    fn interns_the_ids_of_every_index() {
+        let feeds = vec![feed()];
+        let indexes = Indexes::build(&feeds);
+
+        for id in ["tec:S1", "tec:R1", "tec:SH1"] {
+            assert!(indexes.interner.get(id).is_some(), "{} not interned", id);
+        }
+        assert_eq!(indexes.interner.len(), 3);
+        assert_eq!(
+            indexes
+                .get_stats()
+                .iter()
+                .map(|s| s.name.as_str())
+                .collect::<Vec<_>>(),
+            vec![StopIndex::NAME, ReverseStopIndex::NAME, ShapeIndex::NAME]
+        );
+    }
+}
@@ -0,0 +1,81 @@
+use std::{mem::size_of, ops::Deref, sync::Arc};
+
+use ahash::AHashMap;
+use serde::{Deserialize, Serialize};
+
+use super::{string_size, Index};
+use crate::store::{Feed, Interner, Symbol};
+
+/// Namespaced stop id to the namespaced ids of the routes serving it
+#[derive(Serialize, Deserialize)]
+pub struct ReverseStopIndex(AHashMap<Symbol, Vec<Symbol>>);
+
+impl Index for ReverseStopIndex {
+    const NAME: &'static str = "reverse_stops";
+
+    fn interned_ids(feed: &Feed) -> Vec<&str> {
+        let gtfs = feed.get_gtfs();
+        gtfs.stops
+            .keys()
+            .chain(gtfs.routes.keys())
+            .map(String::as_str)
+            .collect()
+    }
+
+    fn build(feeds: &[Arc<Feed>], interner: &Interner) -> Self {
+        let mut reverse_stops: AHashMap<Symbol, Vec<Symbol>> = AHashMap::new();
+
+        for feed in feeds.iter() {
+            for (_, val) in feed.get_gtfs().trips.iter() {
+                let route_id = match interner.get(&feed.namespaced(&val.route_id)) {
+                    Some(route_id) => route_id,
+                    None => continue,
+                };
+                for st in &val.stop_times {
+                    let stop_id = match interner.get(&feed.namespaced(&st.stop.id)) {
+                        Some(stop_id) => stop_id,
+                        None => continue,
+                    };
+                    let vec = reverse_stops.entry(stop_id).or_default();
+                    if !vec.contains(&route_id) {
+                        vec.push(route_id);
+                    }
+                }
+            }
+        }
+
+        Self(reverse_stops)
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.0.capacity() * size_of::<(Symbol, Vec<Symbol>)>()
+            + self
+                .0
+                .values()
+                .map(|routes| routes.capacity() * size_of::<Symbol>())
+                .sum::<usize>()
+    }
+
+    fn memory_usage_with_strings(&self, interner: &Interner) -> usize {
+        self.0.capacity() * size_of::<(String, Vec<String>)>()
+            + self
+                .0
+                .iter()
+                .map(|(stop_id, routes)| {
+                    string_size(interner, stop_id) - size_of::<String>()
+                        + routes
+                            .iter()
+                            .map(|route_id| string_size(interner, route_id))
+                            .sum::<usize>()
+                })
+                .sum::<usize>()
+    }
+}
+
+impl Deref for ReverseStopIndex {
+    type Target = AHashMap<Symbol, Vec<Symbol>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
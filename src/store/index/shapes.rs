@@ -0,0 +1,449 @@
+use std::{mem::size_of, sync::Arc};
+
+use ahash::AHashMap;
+use gtfs_structures::DirectionType;
+use serde::{Deserialize, Serialize};
+
+use super::{string_size, Index};
+use crate::{
+    quadtree::Coordinate,
+    rtree::{RTree, DEFAULT_NODE_SIZE},
+    spatial::SpatialIndex,
+    store::{Feed, Interner, Symbol},
+};
+
+/// Longest piece of segment indexed, in meters. Longer segments are indexed
+/// at the midpoint of each piece, so every point of a segment is within
+/// half of it from an indexed point.
+const MAX_PIECE_LENGTH: f64 = 200.0;
+
+/// Shape polylines of every feed, with the routes and directions each
+/// shape is used by.
+///
+/// Points of every shape are stored one after the other, a segment being
+/// known by the index of its first point.
+#[derive(Serialize, Deserialize)]
+pub struct ShapeIndex {
+    points: Vec<Coordinate>,
+    /// Meters from the start of its shape to each point
+    along: Vec<f32>,
+    /// Namespaced shape id and range of its points, in `points` order
+    shapes: Vec<(Symbol, u32, u32)>,
+    /// Position of each shape in `shapes`
+    positions: AHashMap<Symbol, u32>,
+    /// Pieces of segments by midpoint, values are segments
+    tree: RTree<u32>,
+    /// Namespaced route id and direction of the trips using each shape
+    routes: AHashMap<Symbol, Vec<(Symbol, Option<u8>)>>,
+}
+
+/// Closest point of a shape to a location
+#[derive(Debug, Clone)]
+pub struct ShapeMatch {
+    pub shape: Symbol,
+    pub closest: Coordinate,
+    /// Meters between the location and `closest`
+    pub distance: f64,
+    /// Meters from the start of the shape to `closest`
+    pub along: f64,
+}
+
+impl Index for ShapeIndex {
+    const NAME: &'static str = "shapes";
+
+    fn interned_ids(feed: &Feed) -> Vec<&str> {
+        let gtfs = feed.get_gtfs();
+        gtfs.shapes
+            .keys()
+            .chain(gtfs.routes.keys())
+            .map(String::as_str)
+            .collect()
+    }
+
+    fn build(feeds: &[Arc<Feed>], interner: &Interner) -> Self {
+        let mut points = Vec::new();
+        let mut along = Vec::new();
+        let mut shapes = Vec::new();
+        let mut pieces = Vec::new();
+        let mut routes: AHashMap<Symbol, Vec<(Symbol, Option<u8>)>> = AHashMap::new();
+
+        for feed in feeds.iter() {
+            let gtfs = feed.get_gtfs();
+            for (shape_id, shape_points) in gtfs.shapes.iter() {
+                let shape = match interner.get(&feed.namespaced(shape_id)) {
+                    Some(shape) => shape,
+                    None => continue,
+                };
+
+                let mut shape_points: Vec<_> = shape_points.iter().collect();
+                shape_points.sort_by_key(|point| point.sequence);
+
+                let first = points.len();
+                let mut length = 0.0;
+                for point in shape_points {
+                    let coord = Coordinate::new(point.longitude, point.latitude);
+                    if !coord.is_valid() {
+                        continue;
+                    }
+
+                    if points.len() > first {
+                        let segment = points.len() - 1;
+                        let from: &Coordinate = &points[segment];
+                        let distance = from.distance(&coord);
+                        let cuts = (distance / MAX_PIECE_LENGTH).ceil().max(1.0) as usize;
+                        for i in 0..cuts {
+                            let t = (i as f64 + 0.5) / cuts as f64;
+                            let midpoint = Coordinate::new(
+                                from.get_x() + (coord.get_x() - from.get_x()) * t,
+                                from.get_y() + (coord.get_y() - from.get_y()) * t,
+                            );
+                            pieces.push((segment as u32, midpoint));
+                        }
+                        length += distance;
+                    }
+                    points.push(coord);
+                    along.push(length as f32);
+                }
+                shapes.push((shape, first as u32, points.len() as u32));
+            }
+
+            for trip in gtfs.trips.values() {
+                let shape = match &trip.shape_id {
+                    Some(shape_id) => interner.get(&feed.namespaced(shape_id)),
+                    None => None,
+                };
+                let (shape, route) = match (shape, interner.get(&feed.namespaced(&trip.route_id))) {
+                    (Some(shape), Some(route)) => (shape, route),
+                    _ => continue,
+                };
+                let direction = trip.direction_id.map(|direction| match direction {
+                    DirectionType::Outbound => 0,
+                    DirectionType::Inbound => 1,
+                });
+                let used_by = routes.entry(shape).or_default();
+                if !used_by.contains(&(route, direction)) {
+                    used_by.push((route, direction));
+                }
+            }
+        }
+
+        let positions = shapes
+            .iter()
+            .enumerate()
+            .map(|(i, (shape, _, _))| (*shape, i as u32))
+            .collect();
+
+        Self {
+            points,
+            along,
+            shapes,
+            positions,
+            tree: RTree::bulk_load(pieces, DEFAULT_NODE_SIZE),
+            routes,
+        }
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.points.capacity() * size_of::<Coordinate>()
+            + self.along.capacity() * size_of::<f32>()
+            + self.shapes.capacity() * size_of::<(Symbol, u32, u32)>()
+            + self.positions.capacity() * size_of::<(Symbol, u32)>()
+            + self.tree.memory_usage(|_| size_of::<u32>())
+            + self.routes.capacity() * size_of::<(Symbol, Vec<(Symbol, Option<u8>)>)>()
+            + self
+                .routes
+                .values()
+                .map(|routes| routes.capacity() * size_of::<(Symbol, Option<u8>)>())
+                .sum::<usize>()
+    }
+
+    fn memory_usage_with_strings(&self, interner: &Interner) -> usize {
+        let strings = |symbol: &Symbol| string_size(interner, symbol) - size_of::<Symbol>();
+        self.memory_usage()
+            + self
+                .shapes
+                .iter()
+                .map(|(shape, _, _)| strings(shape))
+                .sum::<usize>()
+            + self.positions.keys().map(strings).sum::<usize>()
+            + self
+                .routes
+                .iter()
+                .map(|(shape, routes)| {
+                    strings(shape)
+                        + routes
+                            .iter()
+                            .map(|(route, _)| strings(route))
+                            .sum::<usize>()
+                })
+                .sum::<usize>()
+    }
+}
+
+impl ShapeIndex {
+    /// Closest point of every shape passing within `radius` meters of
+    /// `coord`, closest first
+    pub fn find_near(&self, coord: &Coordinate, radius: f64) -> Vec<ShapeMatch> {
+        let candidates = self
+            .tree
+            .find_radius(coord, radius + MAX_PIECE_LENGTH / 2.0 + 1.0);
+
+        let mut best: AHashMap<Symbol, ShapeMatch> = AHashMap::new();
+        for (segment, _, _) in candidates {
+            let found = self.closest_on_segment(segment as usize, coord);
+            if found.distance > radius {
+                continue;
+            }
+            match best.get(&found.shape) {
+                Some(current) if current.distance <= found.distance => {}
+                _ => {
+                    best.insert(found.shape, found);
+                }
+            }
+        }
+
+        let mut matches: Vec<ShapeMatch> = best.into_values().collect();
+        matches.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        matches
+    }
+
+    /// Closest point of `shape` to `coord`, `None` for an unknown shape or
+    /// one of less than two points
+    pub fn find_closest(&self, shape: Symbol, coord: &Coordinate) -> Option<ShapeMatch> {
+        let (_, first, last) = self.shapes[*self.positions.get(&shape)? as usize];
+        (first as usize..(last as usize).saturating_sub(1))
+            .map(|segment| self.closest_on_segment(segment, coord))
+            .min_by(|a, b| a.distance.total_cmp(&b.distance))
+    }
+
+    /// Namespaced route ids and directions of the trips using `shape`
+    pub fn get_routes(&self, shape: Symbol) -> &[(Symbol, Option<u8>)] {
+        match self.routes.get(&shape) {
+            Some(routes) => routes,
+            None => &[],
+        }
+    }
+
+    fn closest_on_segment(&self, segment: usize, coord: &Coordinate) -> ShapeMatch {
+        let position = self
+            .shapes
+            .partition_point(|(_, first, _)| *first as usize <= segment)
+            - 1;
+        let from = &self.points[segment];
+        let (closest, distance) = coord.closest_on_segment(from, &self.points[segment + 1]);
+        ShapeMatch {
+            shape: self.shapes[position].0,
+            along: self.along[segment] as f64 + from.distance(&closest),
+            closest,
+            distance,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use gtfs_structures::{Gtfs, Route, Shape, Trip};
+
+    use super::*;
+    use crate::store::{index::Indexes, FeedConfig, FeedSource, ValidationReport};
+
+    /// Feed whose shape `SH` goes through `points` (latitude, longitude),
+    /// used by route `R` with one trip per direction of `directions`
+    fn feed(name: &str, points: &[(f64, f64)], directions: &[Option<DirectionType>]) -> Arc<Feed> {
+        let mut gtfs = Gtfs::default();
+        // Listed backwards, the sequence gives the order
+        let shape = points
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(i, (latitude, longitude))| Shape {
+                id: "SH".to_string(),
+                latitude: *latitude,
+                longitude: *longitude,
+                sequence: i,
+                dist_traveled: None,
+            })
+            .collect();
+        gtfs.shapes.insert("SH".to_string(), shape);
+        gtfs.routes.insert(
+            "R".to_string(),
+            Route {
+                id: "R".to_string(),
+                ..Default::default()
+            },
+        );
+        for (i, direction) in directions.iter().enumerate() {
+            let id = format!("T{}", i);
+            gtfs.trips.insert(
+                id.clone(),
+                Trip {
+                    id,
+                    route_id: "R".to_string(),
+                    shape_id: Some("SH".to_string()),
+                    direction_id: *direction,
+                    ..Default::default()
+                },
+            );
+        }
+
+        let config = FeedConfig {
+            name: name.to_string(),
+            source: FeedSource::Directory(PathBuf::from("gtfs")),
+        };
+        Arc::new(Feed::from_parts(
+            &config,
+            String::new(),
+            gtfs,
+            ValidationReport::default(),
+        ))
+    }
+
+    /// One shape per feed, laid out in the order of the feeds:
+    /// - `a:SH` without points and `b:SH` of a single point, no segment
+    /// - `c:SH` north along the 4.4 meridian from 50.00 to 50.02, with an
+    ///   invalid point left out, segments 1 and 2 of 1112 m each
+    /// - `d:SH` without points, starting where `e:SH` starts
+    /// - `e:SH` north along the 4.41 meridian from 50.00 to 50.02, segment 4
+    fn indexes() -> Indexes {
+        let outbound = Some(DirectionType::Outbound);
+        let inbound = Some(DirectionType::Inbound);
+        Indexes::build(&[
+            feed("a", &[], &[]),
+            feed("b", &[(50.0, 4.4)], &[outbound]),
+            feed(
+                "c",
+                &[(50.0, 4.4), (50.01, 4.4), (0.0, 0.0), (50.02, 4.4)],
+                &[outbound, outbound, inbound],
+            ),
+            feed("d", &[], &[]),
+            feed("e", &[(50.0, 4.41), (50.02, 4.41)], &[None]),
+        ])
+    }
+
+    fn shape(indexes: &Indexes, id: &str) -> Symbol {
+        indexes.interner.get(id).unwrap()
+    }
+
+    fn assert_near(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 0.01,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn segments_are_cut_in_pieces() {
+        let index = indexes().shapes;
+        assert_eq!(index.points.len(), 6);
+        assert_eq!(
+            index.along,
+            vec![0.0, 0.0, 1111.9508, 2223.9016, 0.0, 2223.9016]
+        );
+        // 1112 m segments in 6 pieces, the 2224 m one in 12
+        assert_eq!(index.tree.len(), 6 + 6 + 12);
+    }
+
+    #[test]
+    fn segments_belong_to_their_shape() {
+        let indexes = indexes();
+        let index = &indexes.shapes;
+        let coord = Coordinate::new(4.404, 50.015);
+
+        let found = index.closest_on_segment(1, &coord);
+        assert_eq!(found.shape, shape(&indexes, "c:SH"));
+        assert_eq!((found.closest.get_x(), found.closest.get_y()), (4.4, 50.01));
+        assert_near(found.along, 1111.95);
+
+        let found = index.closest_on_segment(2, &coord);
+        assert_eq!(found.shape, shape(&indexes, "c:SH"));
+        assert_near(found.closest.get_y(), 50.015);
+        assert_near(found.distance, 285.81);
+        assert_near(found.along, 1667.93);
+
+        // First segment after an empty shape starting at the same point
+        let found = index.closest_on_segment(4, &coord);
+        assert_eq!(found.shape, shape(&indexes, "e:SH"));
+        assert_near(found.distance, 428.72);
+        assert_near(found.along, 1667.93);
+    }
+
+    #[test]
+    fn find_closest() {
+        let indexes = indexes();
+        let index = &indexes.shapes;
+
+        let found = index
+            .find_closest(shape(&indexes, "c:SH"), &Coordinate::new(4.404, 50.015))
+            .unwrap();
+        assert_near(found.distance, 285.81);
+        assert_near(found.along, 1667.93);
+
+        // Past the end of the shape
+        let found = index
+            .find_closest(shape(&indexes, "c:SH"), &Coordinate::new(4.4, 50.03))
+            .unwrap();
+        assert_eq!((found.closest.get_x(), found.closest.get_y()), (4.4, 50.02));
+        assert_near(found.distance, 1111.95);
+        assert_near(found.along, 2223.90);
+
+        // Shapes of less than two points, and a route which is not a shape
+        for id in ["a:SH", "b:SH", "d:SH", "c:R"] {
+            assert!(
+                index
+                    .find_closest(shape(&indexes, id), &Coordinate::new(4.4, 50.0))
+                    .is_none(),
+                "{}",
+                id
+            );
+        }
+    }
+
+    #[test]
+    fn find_near() {
+        let indexes = indexes();
+        let index = &indexes.shapes;
+        let coord = Coordinate::new(4.404, 50.015);
+        let shapes = |radius: f64| -> Vec<&str> {
+            index
+                .find_near(&coord, radius)
+                .iter()
+                .map(|found| indexes.interner.resolve(found.shape))
+                .collect()
+        };
+
+        assert!(shapes(250.0).is_empty());
+        assert_eq!(shapes(300.0), vec!["c:SH"]);
+        // One match per shape, closest first
+        assert_eq!(shapes(2000.0), vec!["c:SH", "e:SH"]);
+
+        let found = &index.find_near(&coord, 300.0)[0];
+        assert_near(found.distance, 285.81);
+        assert_near(found.along, 1667.93);
+
+        // Between two indexed midpoints, 93 m from each
+        let found = index.find_near(&Coordinate::new(4.4001, 50.005), 10.0);
+        assert_eq!(found.len(), 1);
+        assert_near(found[0].distance, 7.15);
+        assert_near(found[0].along, 555.98);
+    }
+
+    #[test]
+    fn routes_of_a_shape() {
+        let indexes = indexes();
+        let index = &indexes.shapes;
+        let route = shape(&indexes, "c:R");
+
+        let mut routes = index.get_routes(shape(&indexes, "c:SH")).to_vec();
+        routes.sort();
+        assert_eq!(routes, vec![(route, Some(0)), (route, Some(1))]);
+        assert_eq!(
+            index.get_routes(shape(&indexes, "e:SH")),
+            &[(shape(&indexes, "e:R"), None)]
+        );
+        assert!(index.get_routes(shape(&indexes, "a:SH")).is_empty());
+    }
+}
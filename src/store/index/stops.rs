@@ -0,0 +1,219 @@
+use std::{env, mem::size_of, ops::Deref, sync::Arc, sync::OnceLock};
+
+use serde::{Deserialize, Serialize};
+
+use super::{string_size, Index};
+use crate::{
+    logger,
+    quadtree::{Coordinate, Extent, Polygon, QuadTree},
+    rtree::{RTree, DEFAULT_NODE_SIZE},
+    spatial::{Cluster, SpatialIndex, SpatialIndexKind},
+    store::{Feed, Interner, Symbol},
+};
+
+/// Stops of every feed by location, values are namespaced stop ids
+#[derive(Serialize, Deserialize)]
+pub struct StopIndex(StopTree);
+
+/// Spatial index chosen with `GTFS_SPATIAL_INDEX`
+#[derive(Serialize, Deserialize)]
+pub enum StopTree {
+    QuadTree(QuadTree<Symbol>),
+    RTree(RTree<Symbol>),
+}
+
+/// `GTFS_SPATIAL_INDEX`, `quadtree` (default) or `rtree`, read once
+pub fn spatial_index_kind() -> SpatialIndexKind {
+    static KIND: OnceLock<SpatialIndexKind> = OnceLock::new();
+    *KIND.get_or_init(|| match env::var("GTFS_SPATIAL_INDEX") {
+        Ok(raw) => match raw.parse() {
+            Ok(kind) => kind,
+            Err(e) => {
+                logger::warn(
+                    "FETCHER",
+                    &format!("Invalid GTFS_SPATIAL_INDEX: {}, using the quadtree", e),
+                );
+                SpatialIndexKind::QuadTree
+            }
+        },
+        Err(_) => SpatialIndexKind::QuadTree,
+    })
+}
+
+impl Index for StopIndex {
+    const NAME: &'static str = "stops";
+
+    fn interned_ids(feed: &Feed) -> Vec<&str> {
+        feed.get_gtfs().stops.keys().map(String::as_str).collect()
+    }
+
+    fn build(feeds: &[Arc<Feed>], interner: &Interner) -> Self {
+        let mut stops = Vec::new();
+        let mut rejected = Vec::new();
+        for feed in feeds.iter() {
+            for (stop_id, val) in feed.get_gtfs().stops.iter() {
+                match (val.latitude, val.longitude) {
+                    (Some(lat), Some(lon)) => {
+                        if let Some(symbol) = interner.get(&feed.namespaced(stop_id)) {
+                            let coord = Coordinate::new(lon, lat);
+                            if coord.is_valid() {
+                                stops.push((symbol, coord));
+                            } else {
+                                rejected.push(format!("{} {:?}", interner.resolve(symbol), coord));
+                            }
+                        }
+                    }
+                    _ => continue,
+                }
+            }
+        }
+
+        if !rejected.is_empty() {
+            rejected.sort();
+            logger::warn(
+                "FETCHER",
+                &format!(
+                    "Rejected {} stops with invalid coordinates: {}{}",
+                    rejected.len(),
+                    rejected[..rejected.len().min(10)].join(", "),
+                    if rejected.len() > 10 { ", ..." } else { "" }
+                ),
+            );
+        }
+
+        let tree = match spatial_index_kind() {
+            SpatialIndexKind::QuadTree => {
+                let ext = Extent::bounding(stops.iter().map(|(_, c)| c))
+                    .unwrap_or_else(|| Extent::new(0.0, 0.0, 0.0, 0.0));
+                let mut qt: QuadTree<Symbol> = QuadTree::<Symbol>::new(ext);
+                for (symbol, coord) in stops {
+                    qt.insert(&coord, symbol);
+                }
+
+                let stats = qt.stats();
+                logger::fine(
+                    "FETCHER",
+                    &format!(
+                        "Stop quadtree: {} nodes, {} leaves, depth {}, {} stops at {} coordinates, up to {} per leaf",
+                        stats.nodes,
+                        stats.leaves,
+                        stats.depths.len().saturating_sub(1),
+                        stats.values,
+                        stats.points,
+                        stats.points_per_leaf.len().saturating_sub(1)
+                    ),
+                );
+                StopTree::QuadTree(qt)
+            }
+            SpatialIndexKind::RTree => {
+                let rtree = RTree::bulk_load(stops, DEFAULT_NODE_SIZE);
+                logger::fine(
+                    "FETCHER",
+                    &format!(
+                        "Stop R-tree: {} stops, height {}",
+                        rtree.len(),
+                        rtree.height()
+                    ),
+                );
+                StopTree::RTree(rtree)
+            }
+        };
+
+        Self(tree)
+    }
+
+    fn memory_usage(&self) -> usize {
+        self.0.memory_usage(|_| size_of::<Symbol>())
+    }
+
+    fn memory_usage_with_strings(&self, interner: &Interner) -> usize {
+        self.0.memory_usage(|symbol| string_size(interner, symbol))
+    }
+}
+
+impl Deref for StopIndex {
+    type Target = StopTree;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl SpatialIndex<Symbol> for StopTree {
+    fn insert(&mut self, coord: &Coordinate, value: Symbol) -> bool {
+        match self {
+            StopTree::QuadTree(tree) => tree.insert(coord, value),
+            StopTree::RTree(tree) => tree.insert(coord, value),
+        }
+    }
+
+    fn find_bbox(&self, extent: &Extent) -> Vec<(Symbol, Coordinate)> {
+        match self {
+            StopTree::QuadTree(tree) => tree.find_bbox(extent),
+            StopTree::RTree(tree) => tree.find_bbox(extent),
+        }
+    }
+
+    fn find_radius(&self, coord: &Coordinate, radius: f64) -> Vec<(Symbol, Coordinate, f64)> {
+        match self {
+            StopTree::QuadTree(tree) => tree.find_radius(coord, radius),
+            StopTree::RTree(tree) => tree.find_radius(coord, radius),
+        }
+    }
+
+    fn find_nearest_by(
+        &self,
+        coord: &Coordinate,
+        k: usize,
+        max_distance: f64,
+        filter: impl Fn(&Symbol) -> bool,
+    ) -> Vec<(Symbol, Coordinate, f64)> {
+        match self {
+            StopTree::QuadTree(tree) => tree.find_nearest_by(coord, k, max_distance, filter),
+            StopTree::RTree(tree) => tree.find_nearest_by(coord, k, max_distance, filter),
+        }
+    }
+
+    fn find_polygon(&self, polygon: &Polygon) -> Vec<(Symbol, Coordinate)> {
+        match self {
+            StopTree::QuadTree(tree) => tree.find_polygon(polygon),
+            StopTree::RTree(tree) => tree.find_polygon(polygon),
+        }
+    }
+
+    fn find_corridor(
+        &self,
+        lines: &[Vec<Coordinate>],
+        buffer: f64,
+    ) -> Vec<(Symbol, Coordinate, f64)> {
+        match self {
+            StopTree::QuadTree(tree) => tree.find_corridor(lines, buffer),
+            StopTree::RTree(tree) => tree.find_corridor(lines, buffer),
+        }
+    }
+
+    fn find_clusters(
+        &self,
+        extent: &Extent,
+        width: f64,
+        height: f64,
+        representatives: usize,
+        filter: impl Fn(&Symbol) -> bool,
+    ) -> Vec<Cluster<Symbol>> {
+        match self {
+            StopTree::QuadTree(tree) => {
+                tree.find_clusters(extent, width, height, representatives, filter)
+            }
+            StopTree::RTree(tree) => {
+                tree.find_clusters(extent, width, height, representatives, filter)
+            }
+        }
+    }
+
+    fn memory_usage(&self, value_size: impl Fn(&Symbol) -> usize) -> usize {
+        match self {
+            StopTree::QuadTree(tree) => tree.memory_usage(value_size),
+            StopTree::RTree(tree) => tree.memory_usage(value_size),
+        }
+    }
+}
@@ -0,0 +1,127 @@
+use std::mem::size_of;
+
+use serde::{Deserialize, Serialize};
+
+/// Compact handle of an interned id, only meaningful for the interner
+/// (and so the snapshot) it comes from. Symbols sort like their ids.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Symbol(u32);
+
+/// Namespaced ids of a snapshot, each stored once and referenced by
+/// [`Symbol`] from the indexes.
+///
+/// Ids are sorted and packed in a single buffer, so a symbol is its rank:
+/// resolving one is a slice and finding one a binary search.
+#[derive(Default, Serialize, Deserialize)]
+pub struct Interner {
+    buffer: String,
+    /// End of each id in `buffer`
+    ends: Vec<u32>,
+}
+
+impl Interner {
+    pub fn new(ids: impl IntoIterator<Item = String>) -> Self {
+        let mut ids: Vec<String> = ids.into_iter().collect();
+        ids.sort_unstable();
+        ids.dedup();
+
+        let mut interner = Self {
+            buffer: String::with_capacity(ids.iter().map(|id| id.len()).sum()),
+            ends: Vec::with_capacity(ids.len()),
+        };
+        for id in ids {
+            interner.buffer.push_str(&id);
+            interner.ends.push(interner.buffer.len() as u32);
+        }
+        interner
+    }
+
+    /// Symbol of an id, `None` when it was never interned
+    pub fn get(&self, value: &str) -> Option<Symbol> {
+        let mut low = 0;
+        let mut high = self.ends.len();
+        while low < high {
+            let mid = (low + high) / 2;
+            match self.resolve(Symbol(mid as u32)).cmp(value) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return Some(Symbol(mid as u32)),
+            }
+        }
+        None
+    }
+
+    pub fn resolve(&self, symbol: Symbol) -> &str {
+        let index = symbol.0 as usize;
+        let start = match index {
+            0 => 0,
+            _ => self.ends[index - 1] as usize,
+        };
+        &self.buffer[start..self.ends[index] as usize]
+    }
+
+    pub fn len(&self) -> usize {
+        self.ends.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ends.is_empty()
+    }
+
+    /// Approximate heap size in bytes
+    pub fn memory_usage(&self) -> usize {
+        self.buffer.capacity() + self.ends.capacity() * size_of::<u32>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interner() -> Interner {
+        Interner::new(
+            ["tec:S2", "tec:S1", "stib:S1", "tec:S1", "", "tec:S10"]
+                .iter()
+                .map(|id| id.to_string()),
+        )
+    }
+
+    #[test]
+    fn ids_are_deduplicated() {
+        let interner = interner();
+        assert_eq!(interner.len(), 5);
+        assert_eq!(interner.buffer, "stib:S1tec:S1tec:S10tec:S2");
+        assert_eq!(interner.get("tec:S1"), Some(Symbol(2)));
+    }
+
+    #[test]
+    fn resolve_gives_back_the_id() {
+        let interner = interner();
+        for id in ["", "stib:S1", "tec:S1", "tec:S10", "tec:S2"] {
+            let symbol = interner.get(id).unwrap();
+            assert_eq!(interner.resolve(symbol), id);
+        }
+    }
+
+    #[test]
+    fn symbols_sort_like_their_ids() {
+        let interner = interner();
+        let symbols: Vec<Symbol> = ["", "stib:S1", "tec:S1", "tec:S10", "tec:S2"]
+            .iter()
+            .map(|id| interner.get(id).unwrap())
+            .collect();
+        assert!(symbols.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn unknown_ids() {
+        let interner = interner();
+        for id in ["tec:S", "tec:S3", "S1", "a", "z", "tec:S1 "] {
+            assert_eq!(interner.get(id), None, "{}", id);
+        }
+
+        let empty = Interner::new(Vec::new());
+        assert!(empty.is_empty());
+        assert_eq!(empty.get(""), None);
+    }
+}
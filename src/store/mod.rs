@@ -0,0 +1,540 @@
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use arc_swap::ArcSwap;
+use chrono::NaiveDate;
+use tokio::sync::{broadcast, Mutex};
+
+use crate::{logger, quadtree::Extent};
+use cache::SnapshotCache;
+
+mod alerts;
+mod cache;
+mod error;
+mod feed;
+mod history;
+pub mod index;
+mod interner;
+pub mod poller;
+pub mod realtime;
+mod snapshot;
+mod source;
+mod trip_updates;
+mod validation;
+mod vehicles;
+
+pub use alerts::{route_agency, Alert, AlertFilter, Alerts, InformedEntity, TripScope};
+pub use error::{FeedError, LoadError, RefreshError};
+pub use feed::Feed;
+pub use history::{
+    EntityDiff, FeedDiff, FeedSummary, FeedVersion, History, MovedStop, SnapshotDiff, StopDiff,
+};
+pub use interner::{Interner, Symbol};
+pub use snapshot::FeedSnapshot;
+pub use source::{FeedConfig, FeedSource, FetchedFeed};
+pub use trip_updates::{
+    service_date, RealtimeStopTime, RealtimeTrip, StopRelationship, StopTimeEvent,
+    StopTimeUpdate, TripRelationship, TripUpdate, TripUpdateKey, TripUpdates,
+};
+pub use validation::{Issue, IssueKind, Severity, ValidationReport};
+pub use vehicles::{Bus, BusDelta, IngestSummary, Vehicles};
+
+/// Batches of bus changes kept for the live subscribers, one lagging
+/// further behind is dropped
+const BUS_UPDATES_CAPACITY: usize = 64;
+
+/// Result of a reload that did not fail
+#[derive(Debug, Clone, Copy)]
+pub enum RefreshOutcome {
+    /// A new snapshot with this version is now served
+    Published(u64),
+    /// The source content did not change, this version is still served
+    Unchanged(u64),
+}
+
+pub struct Store {
+    feed: ArcSwap<FeedSnapshot>,
+    feeds: Vec<FeedConfig>,
+    cache: Option<Arc<SnapshotCache>>,
+    history: RwLock<History>,
+    vehicles: RwLock<Vehicles>,
+    bus_updates: broadcast::Sender<Arc<Vec<BusDelta>>>,
+    trip_updates: RwLock<TripUpdates>,
+    alerts: RwLock<Alerts>,
+    refresh_lock: Mutex<()>,
+    secret: String,
+}
+
+/// Feed of a reload, either kept from the current snapshot or freshly fetched
+enum PendingFeed {
+    Kept(Arc<Feed>),
+    Fetched(FeedConfig, FetchedFeed),
+}
+
+impl PendingFeed {
+    fn name_and_checksum(&self) -> (&str, &str) {
+        match self {
+            PendingFeed::Kept(feed) => (feed.get_name(), feed.get_checksum()),
+            PendingFeed::Fetched(config, fetched) => (&config.name, fetched.get_checksum()),
+        }
+    }
+}
+
+/// Snapshot ready to be published, with the cache key it is stored under
+struct LoadedSnapshot {
+    snapshot: FeedSnapshot,
+    cache_key: String,
+    from_cache: bool,
+}
+
+impl Store {
+    pub async fn new(secret: &str, feeds: Vec<FeedConfig>) -> Result<Self, FeedError> {
+        let cache = SnapshotCache::from_env().map(Arc::new);
+        let loaded = match Self::load_snapshot(&feeds, cache.clone(), None, true, None).await? {
+            Some(loaded) => loaded,
+            None => LoadedSnapshot {
+                snapshot: Self::build_snapshot(1, Vec::new()).await?,
+                cache_key: SnapshotCache::key(std::iter::empty()),
+                from_cache: false,
+            },
+        };
+
+        let mut history = History::from_env();
+        history.push(FeedVersion::build(None, &loaded.snapshot));
+
+        let store = Self {
+            feed: ArcSwap::from_pointee(loaded.snapshot),
+            feeds,
+            cache,
+            history: RwLock::new(history),
+            vehicles: RwLock::new(Vehicles::from_env()),
+            bus_updates: broadcast::channel(BUS_UPDATES_CAPACITY).0,
+            trip_updates: RwLock::new(TripUpdates::default()),
+            alerts: RwLock::new(Alerts::default()),
+            refresh_lock: Mutex::new(()),
+            secret: secret.to_string(),
+        };
+        if !loaded.from_cache {
+            store.write_cache(loaded.cache_key);
+        }
+        Ok(store)
+    }
+
+    async fn fetch_feed(source: FeedSource) -> Result<FetchedFeed, LoadError> {
+        logger::fine("FETCHER", &format!("Fetching GTFS from {}", source));
+        tokio::task::spawn_blocking(move || source.fetch()).await?
+    }
+
+    /// Parse a feed off the async runtime.
+    /// A panic while loading is reported as an error instead of unwinding
+    /// into the caller.
+    async fn parse_feed(config: FeedConfig, fetched: FetchedFeed) -> Result<Feed, FeedError> {
+        let name = config.name.clone();
+        tokio::task::spawn_blocking(move || Feed::load(&config, fetched))
+            .await
+            .map_err(|e| FeedError::new(&name, e.into()))?
+    }
+
+    async fn build_snapshot(
+        version: u64,
+        feeds: Vec<Arc<Feed>>,
+    ) -> Result<FeedSnapshot, FeedError> {
+        tokio::task::spawn_blocking(move || FeedSnapshot::build(version, feeds))
+            .await
+            .map_err(|e| FeedError {
+                feed: None,
+                error: e.into(),
+                validation: None,
+            })
+    }
+
+    /// Fetch every configured feed (or only `only`) and build the snapshot
+    /// following `current`. Feeds whose content hash did not change are kept
+    /// as is, and the binary cache is used when it holds the exact same
+    /// feeds. `None` when nothing changed.
+    async fn load_snapshot(
+        feeds: &[FeedConfig],
+        cache: Option<Arc<SnapshotCache>>,
+        current: Option<&FeedSnapshot>,
+        force: bool,
+        only: Option<&str>,
+    ) -> Result<Option<LoadedSnapshot>, FeedError> {
+        let version = match current {
+            Some(current) => current.get_version() + 1,
+            None => 1,
+        };
+
+        let mut pending = Vec::with_capacity(feeds.len());
+        let mut changed = false;
+
+        for config in feeds {
+            let previous = current.and_then(|c| c.get_feed(&config.name));
+            if let (Some(previous), Some(only)) = (previous, only) {
+                if only != config.name {
+                    pending.push(PendingFeed::Kept(previous.clone()));
+                    continue;
+                }
+            }
+
+            let fetched = Self::fetch_feed(config.source.clone())
+                .await
+                .map_err(|error| FeedError::new(&config.name, error))?;
+            if let Some(previous) = previous {
+                if !force && fetched.get_checksum() == previous.get_checksum() {
+                    logger::info(
+                        "FETCHER",
+                        &format!(
+                            "GTFS {} unchanged (sha256 {})",
+                            config.name,
+                            previous.get_checksum()
+                        ),
+                    );
+                    pending.push(PendingFeed::Kept(previous.clone()));
+                    continue;
+                }
+            }
+
+            pending.push(PendingFeed::Fetched(config.clone(), fetched));
+            changed = true;
+        }
+
+        if !changed {
+            return Ok(None);
+        }
+
+        let cache_key = SnapshotCache::key(pending.iter().map(|p| p.name_and_checksum()));
+        if let Some(cache) = cache {
+            let key = cache_key.clone();
+            let configs = feeds.to_vec();
+            let cached =
+                tokio::task::spawn_blocking(move || cache.load(&key, version, &configs)).await;
+            if let Ok(Some(snapshot)) = cached {
+                return Ok(Some(LoadedSnapshot {
+                    snapshot,
+                    cache_key,
+                    from_cache: true,
+                }));
+            }
+        }
+
+        let mut loaded = Vec::with_capacity(pending.len());
+        for feed in pending {
+            match feed {
+                PendingFeed::Kept(feed) => loaded.push(feed),
+                PendingFeed::Fetched(config, fetched) => {
+                    let feed = Self::parse_feed(config, fetched).await?;
+                    loaded.push(Arc::new(feed));
+                }
+            }
+        }
+
+        Ok(Some(LoadedSnapshot {
+            snapshot: Self::build_snapshot(version, loaded).await?,
+            cache_key,
+            from_cache: false,
+        }))
+    }
+
+    /// Store the current snapshot in the binary cache in the background
+    fn write_cache(&self, key: String) {
+        if let Some(cache) = self.cache.clone() {
+            let snapshot = self.get_feed();
+            tokio::task::spawn_blocking(move || cache.store(&key, &snapshot));
+        }
+    }
+
+    /// Whether `secret` grants access to the admin endpoints
+    pub fn is_authorized(&self, secret: &str) -> bool {
+        !self.secret.is_empty() && self.secret == secret
+    }
+
+    pub async fn refresh_gtfs(
+        &self,
+        secret: &str,
+        feed: Option<&str>,
+    ) -> Result<u64, RefreshError> {
+        if self.secret.is_empty() {
+            logger::fine("FETCHER", "No secret, not refreshing GTFS");
+            return Err(RefreshError::Unauthorized);
+        }
+
+        if self.secret != secret {
+            logger::fine("FETCHER", "Wrong secret, not refreshing GTFS");
+            return Err(RefreshError::Unauthorized);
+        }
+
+        if let Some(feed) = feed {
+            if !self.feeds.iter().any(|f| f.name == feed) {
+                return Err(RefreshError::UnknownFeed(feed.to_string()));
+            }
+        }
+
+        match self.reload(true, feed).await? {
+            RefreshOutcome::Published(version) | RefreshOutcome::Unchanged(version) => Ok(version),
+        }
+    }
+
+    /// Fetch the configured feeds (or only `only`) and publish a snapshot
+    /// built from them. Unless `force` is set, a feed whose content hash did
+    /// not change is not parsed again, and nothing is published when no
+    /// feed changed.
+    pub async fn reload(
+        &self,
+        force: bool,
+        only: Option<&str>,
+    ) -> Result<RefreshOutcome, FeedError> {
+        // Only one reload at a time so versions are published in order
+        let _guard = self.refresh_lock.lock().await;
+        let current = self.feed.load_full();
+
+        let result =
+            Self::load_snapshot(&self.feeds, self.cache.clone(), Some(&current), force, only).await;
+
+        let loaded = match result {
+            Ok(Some(loaded)) => loaded,
+            Ok(None) => {
+                logger::info(
+                    "FETCHER",
+                    &format!(
+                        "GTFS unchanged, keeping feed version {}",
+                        current.get_version()
+                    ),
+                );
+                return Ok(RefreshOutcome::Unchanged(current.get_version()));
+            }
+            Err(e) => {
+                logger::critical(
+                    "FETCHER",
+                    &format!(
+                        "Error loading GTFS, keeping feed version {}: {}",
+                        current.get_version(),
+                        e
+                    ),
+                );
+                return Err(e);
+            }
+        };
+
+        let version = loaded.snapshot.get_version();
+        let snapshot = Arc::new(loaded.snapshot);
+        self.feed.store(snapshot.clone());
+        logger::fine("FETCHER", &format!("Published feed version {}", version));
+        if !loaded.from_cache {
+            self.write_cache(loaded.cache_key);
+        }
+
+        match tokio::task::spawn_blocking(move || FeedVersion::build(Some(&current), &snapshot))
+            .await
+        {
+            Ok(entry) => self.history.write().unwrap().push(entry),
+            Err(e) => logger::warn(
+                "HISTORY",
+                &format!("Could not diff feed version {}: {}", version, e),
+            ),
+        }
+        Ok(RefreshOutcome::Published(version))
+    }
+
+    /// Metadata of the last published snapshots, oldest first
+    pub fn get_history(&self) -> Vec<Arc<FeedVersion>> {
+        self.history.read().unwrap().get_versions()
+    }
+
+    /// Metadata of `version`, or of the current snapshot
+    pub fn get_history_entry(&self, version: Option<u64>) -> Option<Arc<FeedVersion>> {
+        let history = self.history.read().unwrap();
+        match version {
+            Some(version) => history.get_version(version),
+            None => history.get_latest(),
+        }
+    }
+
+    /// Current feed snapshot, cheap to call from any handler
+    pub fn get_feed(&self) -> Arc<FeedSnapshot> {
+        self.feed.load_full()
+    }
+
+    pub fn get_feed_configs(&self) -> &[FeedConfig] {
+        &self.feeds
+    }
+
+    /// Store a batch of bus positions pushed by the fetcher or read from
+    /// GTFS-RT, their line ids namespaced like the routes they refer to
+    pub fn ingest_buses(&self, buses: Vec<Bus>) -> IngestSummary {
+        let buses = buses
+            .into_iter()
+            .map(|bus| {
+                let line_id = self.line_id(bus.get_line_id());
+                bus.with_line_id(line_id)
+            })
+            .collect();
+        let mut vehicles = self.vehicles.write().unwrap();
+        let (summary, deltas) = vehicles.ingest(buses);
+        self.publish_buses(deltas);
+        if summary.rejected > 0 {
+            logger::warn(
+                "VEHICLES",
+                &format!(
+                    "Rejected {} buses with invalid coordinates",
+                    summary.rejected
+                ),
+            );
+        }
+        if summary.expired > 0 {
+            logger::fine(
+                "VEHICLES",
+                &format!(
+                    "Expired {} buses, tracking {}",
+                    summary.expired,
+                    vehicles.len()
+                ),
+            );
+        }
+        summary
+    }
+
+    /// Drop the buses not reported within the TTL even when no batch comes
+    /// in, so the live subscribers see them go
+    pub fn expire_buses(&self) {
+        let mut vehicles = self.vehicles.write().unwrap();
+        let deltas = vehicles.expire();
+        if !deltas.is_empty() {
+            logger::fine(
+                "VEHICLES",
+                &format!(
+                    "Expired {} buses, tracking {}",
+                    deltas.len(),
+                    vehicles.len()
+                ),
+            );
+        }
+        self.publish_buses(deltas);
+    }
+
+    /// Must be called under the vehicles lock so batches keep their order
+    fn publish_buses(&self, deltas: Vec<BusDelta>) {
+        if !deltas.is_empty() {
+            // An error only means nobody is listening
+            let _ = self.bus_updates.send(Arc::new(deltas));
+        }
+    }
+
+    pub fn get_bus_ttl(&self) -> Duration {
+        self.vehicles.read().unwrap().get_ttl()
+    }
+
+    /// Batches of changes of the tracked buses, from now on
+    pub fn subscribe_buses(&self) -> broadcast::Receiver<Arc<Vec<BusDelta>>> {
+        self.bus_updates.subscribe()
+    }
+
+    /// Namespaced id of the route a bare or namespaced line id refers to,
+    /// unchanged when no feed has it
+    pub fn line_id(&self, line_id: &str) -> String {
+        self.get_feed()
+            .find_route_id(line_id, None)
+            .unwrap_or_else(|| line_id.to_string())
+    }
+
+    pub fn get_buses(&self, line_id: Option<&str>) -> Vec<Bus> {
+        let line_id = line_id.map(|line_id| self.line_id(line_id));
+        self.vehicles.read().unwrap().get_buses(line_id.as_deref())
+    }
+
+    pub fn get_buses_bbox(&self, extent: &Extent, line_id: Option<&str>) -> Vec<Bus> {
+        let line_id = line_id.map(|line_id| self.line_id(line_id));
+        self.vehicles
+            .read()
+            .unwrap()
+            .find_bbox(extent, line_id.as_deref())
+    }
+
+    /// Store the trip updates of a GTFS-RT message read from `source`
+    pub fn apply_trip_updates(
+        &self,
+        source: &str,
+        full_dataset: bool,
+        updates: Vec<TripUpdate>,
+        deleted: Vec<TripUpdateKey>,
+    ) {
+        self.trip_updates
+            .write()
+            .unwrap()
+            .apply(source, full_dataset, updates, deleted);
+    }
+
+    /// Latest update of a namespaced trip on `start_date`, see
+    /// [`TripUpdates::get`]
+    pub fn get_trip_update(
+        &self,
+        trip_id: &str,
+        start_date: Option<NaiveDate>,
+    ) -> Option<TripUpdate> {
+        self.trip_updates
+            .read()
+            .unwrap()
+            .get(trip_id, start_date)
+            .cloned()
+    }
+
+    /// Store the alerts of a GTFS-RT message read from `source`
+    pub fn apply_alerts(
+        &self,
+        source: &str,
+        full_dataset: bool,
+        alerts: Vec<Alert>,
+        deleted: Vec<String>,
+    ) {
+        self.alerts
+            .write()
+            .unwrap()
+            .apply(source, full_dataset, alerts, deleted);
+    }
+
+    /// Store an alert added through the API, returning its id
+    pub fn add_alert(&self, alert: Alert) -> String {
+        let id = self.alerts.write().unwrap().add(alert);
+        logger::info("ALERTS", &format!("Added alert {}", id));
+        id
+    }
+
+    /// Drop an alert added through the API
+    pub fn remove_alert(&self, id: &str) -> bool {
+        let removed = self.alerts.write().unwrap().remove(id);
+        if removed {
+            logger::info("ALERTS", &format!("Removed alert {}", id));
+        }
+        removed
+    }
+
+    /// Alerts matching `filter` and active at `at` (POSIX time)
+    pub fn get_alerts(&self, filter: &AlertFilter, at: u64) -> Vec<Alert> {
+        self.alerts.read().unwrap().find(filter, at)
+    }
+
+    /// Alerts active at `at` about each of `stop_ids` (namespaced), the
+    /// stops without any being left out
+    pub fn get_stop_alerts<'a>(
+        &self,
+        stop_ids: impl Iterator<Item = &'a str>,
+        at: u64,
+    ) -> Vec<(&'a str, Vec<Alert>)> {
+        self.alerts.read().unwrap().find_by_stops(stop_ids, at)
+    }
+}
+
+/// Expire the buses a tenth of the TTL after they go stale at most, without
+/// waiting for the next batch
+pub fn spawn_bus_expiry(store: Arc<Store>) {
+    let period = (store.get_bus_ttl() / 10).max(Duration::from_secs(1));
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(period);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            store.expire_buses();
+        }
+    });
+}
@@ -0,0 +1,136 @@
+use std::{env, str::FromStr, sync::Arc, time::Duration};
+
+use chrono::Utc;
+use cron::Schedule;
+use tokio::time::MissedTickBehavior;
+
+use super::Store;
+use crate::logger;
+
+/// How the store keeps its feed up to date without a call to `/refresh_gtfs`
+#[derive(Default)]
+pub struct PollerConfig {
+    /// Delay between two checks of the source for changes
+    pub interval: Option<Duration>,
+    /// Cron expression (seconds first) at which a reload is attempted
+    pub schedule: Option<Schedule>,
+}
+
+impl PollerConfig {
+    /// Read `GTFS_POLL_INTERVAL` (seconds) and `GTFS_RELOAD_SCHEDULE` (cron).
+    /// Invalid values are reported and leave the matching trigger disabled.
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+
+        if let Ok(raw) = env::var("GTFS_POLL_INTERVAL") {
+            match raw.trim().parse::<u64>() {
+                Ok(0) => {}
+                Ok(secs) => config.interval = Some(Duration::from_secs(secs)),
+                Err(_) => logger::warn(
+                    "POLLER",
+                    &format!("Invalid GTFS_POLL_INTERVAL {:?}, polling disabled", raw),
+                ),
+            }
+        }
+
+        if let Ok(raw) = env::var("GTFS_RELOAD_SCHEDULE") {
+            match Schedule::from_str(raw.trim()) {
+                Ok(schedule) => config.schedule = Some(schedule),
+                Err(e) => logger::warn(
+                    "POLLER",
+                    &format!(
+                        "Invalid GTFS_RELOAD_SCHEDULE {:?} ({}), schedule disabled",
+                        raw, e
+                    ),
+                ),
+            }
+        }
+
+        config
+    }
+}
+
+/// Start the background reload tasks enabled in `config`
+pub fn spawn(store: Arc<Store>, config: PollerConfig) {
+    if let Some(interval) = config.interval {
+        for feed in store.get_feed_configs() {
+            logger::fine(
+                "POLLER",
+                &format!(
+                    "Watching {} ({}) every {:?}",
+                    feed.name, feed.source, interval
+                ),
+            );
+        }
+        tokio::spawn(watch(store.clone(), interval));
+    }
+
+    if let Some(schedule) = config.schedule {
+        logger::fine("POLLER", &format!("Reloading on schedule {}", schedule));
+        tokio::spawn(scheduled(store, schedule));
+    }
+}
+
+/// Reload a feed whenever its source fingerprint moves. The reload itself
+/// still compares content hashes, so touching a file does not rebuild
+/// anything.
+async fn watch(store: Arc<Store>, interval: Duration) {
+    let client = reqwest::Client::new();
+    let mut last = Vec::new();
+    for feed in store.get_feed_configs() {
+        last.push(feed.source.fingerprint(&client).await.unwrap_or_default());
+    }
+
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    ticker.tick().await;
+
+    loop {
+        ticker.tick().await;
+
+        for (feed, last) in store.get_feed_configs().iter().zip(last.iter_mut()) {
+            let current = match feed.source.fingerprint(&client).await {
+                Ok(current) => current,
+                Err(e) => {
+                    logger::warn(
+                        "POLLER",
+                        &format!("Could not check GTFS source of {}: {}", feed.name, e),
+                    );
+                    continue;
+                }
+            };
+
+            // Without a fingerprint the only way to know is to hash the content
+            if current.is_some() && current == *last {
+                continue;
+            }
+
+            logger::info(
+                "POLLER",
+                &format!("GTFS source of {} changed, reloading", feed.name),
+            );
+            // Errors are logged by the store, the previous feed stays live and
+            // the same broken content is not retried until it changes again
+            let _ = store.reload(false, Some(&feed.name)).await;
+            *last = current;
+        }
+    }
+}
+
+async fn scheduled(store: Arc<Store>, schedule: Schedule) {
+    loop {
+        let next = match schedule.upcoming(Utc).next() {
+            Some(next) => next,
+            None => {
+                logger::warn("POLLER", "Reload schedule has no upcoming date, stopping");
+                return;
+            }
+        };
+
+        let wait = (next - Utc::now()).to_std().unwrap_or_default();
+        tokio::time::sleep(wait).await;
+
+        logger::info("POLLER", "Scheduled GTFS reload");
+        let _ = store.reload(false, None).await;
+    }
+}
@@ -0,0 +1,358 @@
+use super::proto::{self, FeedMessage};
+use crate::store::{
+    alerts::{ActivePeriod, Cause, Effect, SeverityLevel, Translation},
+    Alert, Feed, InformedEntity,
+};
+
+/// Alerts of `message` about the routes, stops and trips of `feed`,
+/// namespaced like the rest of the API, and ids of the deleted alerts. Also
+/// returns how many alerts were left out for informing about nothing known.
+pub(super) fn to_alerts(message: &FeedMessage, feed: &Feed) -> (Vec<Alert>, Vec<String>, usize) {
+    let mut alerts = Vec::new();
+    let mut deleted = Vec::new();
+    let mut unmatched = 0;
+    for entity in message.entity.iter() {
+        if entity.is_deleted.unwrap_or(false) {
+            deleted.push(feed.namespaced(&entity.id));
+            continue;
+        }
+        let alert = match &entity.alert {
+            Some(alert) => alert,
+            None => continue,
+        };
+
+        let informed_entities: Vec<InformedEntity> = alert
+            .informed_entity
+            .iter()
+            .filter_map(|selector| to_informed_entity(selector, feed))
+            .collect();
+        if informed_entities.is_empty() {
+            unmatched += 1;
+            continue;
+        }
+
+        alerts.push(Alert {
+            id: feed.namespaced(&entity.id),
+            cause: to_cause(alert.cause()),
+            effect: to_effect(alert.effect()),
+            severity: to_severity(alert.severity_level()),
+            header: to_translations(alert.header_text.as_ref()),
+            description: to_translations(alert.description_text.as_ref()),
+            url: to_translations(alert.url.as_ref()),
+            active_periods: alert
+                .active_period
+                .iter()
+                .map(|period| ActivePeriod {
+                    start: period.start,
+                    end: period.end,
+                })
+                .collect(),
+            informed_entities,
+        });
+    }
+    (alerts, deleted, unmatched)
+}
+
+/// `None` when a route, stop or trip is not in the feed, or when only the
+/// route type is given: the static feed only keeps basic route types
+fn to_informed_entity(selector: &proto::EntitySelector, feed: &Feed) -> Option<InformedEntity> {
+    let gtfs = feed.get_gtfs();
+    let non_empty = |id: &Option<String>| id.clone().filter(|id| !id.is_empty());
+
+    let trip = match selector
+        .trip
+        .as_ref()
+        .and_then(|trip| non_empty(&trip.trip_id))
+    {
+        Some(trip_id) => Some(gtfs.trips.get(&trip_id)?),
+        None => None,
+    };
+    let route_id = match non_empty(&selector.route_id) {
+        Some(route_id) => Some(gtfs.routes.get(&route_id)?.id.clone()),
+        None => trip.map(|trip| trip.route_id.clone()),
+    };
+    let stop_id = match non_empty(&selector.stop_id) {
+        Some(stop_id) => Some(gtfs.stops.get(&stop_id)?.id.clone()),
+        None => None,
+    };
+    let direction_id = selector
+        .direction_id
+        .or(selector.trip.as_ref().and_then(|trip| trip.direction_id));
+
+    let entity = InformedEntity {
+        agency_id: non_empty(&selector.agency_id).map(|id| feed.namespaced(&id)),
+        route_id: route_id.map(|id| feed.namespaced(&id)),
+        trip_id: trip.map(|trip| feed.namespaced(&trip.id)),
+        stop_id: stop_id.map(|id| feed.namespaced(&id)),
+        direction_id,
+    };
+    Some(entity).filter(|entity| *entity != InformedEntity::default())
+}
+
+fn to_translations(text: Option<&proto::TranslatedString>) -> Vec<Translation> {
+    text.map(|text| {
+        text.translation
+            .iter()
+            .map(|translation| Translation {
+                text: translation.text.clone(),
+                language: translation.language.clone(),
+            })
+            .collect()
+    })
+    .unwrap_or_default()
+}
+
+fn to_cause(cause: proto::Cause) -> Cause {
+    match cause {
+        proto::Cause::UnknownCause => Cause::UnknownCause,
+        proto::Cause::OtherCause => Cause::OtherCause,
+        proto::Cause::TechnicalProblem => Cause::TechnicalProblem,
+        proto::Cause::Strike => Cause::Strike,
+        proto::Cause::Demonstration => Cause::Demonstration,
+        proto::Cause::Accident => Cause::Accident,
+        proto::Cause::Holiday => Cause::Holiday,
+        proto::Cause::Weather => Cause::Weather,
+        proto::Cause::Maintenance => Cause::Maintenance,
+        proto::Cause::Construction => Cause::Construction,
+        proto::Cause::PoliceActivity => Cause::PoliceActivity,
+        proto::Cause::MedicalEmergency => Cause::MedicalEmergency,
+    }
+}
+
+fn to_effect(effect: proto::Effect) -> Effect {
+    match effect {
+        proto::Effect::UnknownEffect => Effect::UnknownEffect,
+        proto::Effect::NoService => Effect::NoService,
+        proto::Effect::ReducedService => Effect::ReducedService,
+        proto::Effect::SignificantDelays => Effect::SignificantDelays,
+        proto::Effect::Detour => Effect::Detour,
+        proto::Effect::AdditionalService => Effect::AdditionalService,
+        proto::Effect::ModifiedService => Effect::ModifiedService,
+        proto::Effect::OtherEffect => Effect::OtherEffect,
+        proto::Effect::StopMoved => Effect::StopMoved,
+        proto::Effect::NoEffect => Effect::NoEffect,
+        proto::Effect::AccessibilityIssue => Effect::AccessibilityIssue,
+    }
+}
+
+fn to_severity(severity: proto::SeverityLevel) -> SeverityLevel {
+    match severity {
+        proto::SeverityLevel::UnknownSeverity => SeverityLevel::UnknownSeverity,
+        proto::SeverityLevel::Info => SeverityLevel::Info,
+        proto::SeverityLevel::Warning => SeverityLevel::Warning,
+        proto::SeverityLevel::Severe => SeverityLevel::Severe,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use gtfs_structures::{Gtfs, Route, Stop, Trip};
+
+    use super::*;
+    use crate::store::{FeedConfig, FeedSource, ValidationReport};
+
+    /// Trip `T1` of route `R1`, and stop `S1`
+    fn feed() -> Feed {
+        let mut gtfs = Gtfs::default();
+        gtfs.routes.insert(
+            "R1".to_string(),
+            Route {
+                id: "R1".to_string(),
+                ..Default::default()
+            },
+        );
+        gtfs.trips.insert(
+            "T1".to_string(),
+            Trip {
+                id: "T1".to_string(),
+                route_id: "R1".to_string(),
+                ..Default::default()
+            },
+        );
+        gtfs.stops.insert(
+            "S1".to_string(),
+            Arc::new(Stop {
+                id: "S1".to_string(),
+                ..Default::default()
+            }),
+        );
+
+        let config = FeedConfig {
+            name: "tec".to_string(),
+            source: FeedSource::Directory("gtfs".into()),
+        };
+        Feed::from_parts(&config, String::new(), gtfs, ValidationReport::default())
+    }
+
+    fn selector(route_id: &str, trip_id: &str, stop_id: &str) -> proto::EntitySelector {
+        let non_empty = |id: &str| Some(id.to_string()).filter(|id| !id.is_empty());
+        proto::EntitySelector {
+            route_id: non_empty(route_id),
+            trip: non_empty(trip_id).map(|trip_id| proto::TripDescriptor {
+                trip_id: Some(trip_id),
+                ..Default::default()
+            }),
+            stop_id: non_empty(stop_id),
+            ..Default::default()
+        }
+    }
+
+    fn entity(
+        route_id: Option<&str>,
+        trip_id: Option<&str>,
+        stop_id: Option<&str>,
+    ) -> InformedEntity {
+        InformedEntity {
+            route_id: route_id.map(str::to_string),
+            trip_id: trip_id.map(str::to_string),
+            stop_id: stop_id.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn informed_entities_are_namespaced() {
+        let feed = feed();
+        let informed = |selector: proto::EntitySelector| to_informed_entity(&selector, &feed);
+
+        assert_eq!(
+            informed(selector("R1", "", "S1")),
+            Some(entity(Some("tec:R1"), None, Some("tec:S1")))
+        );
+        // The route of a trip is filled in
+        assert_eq!(
+            informed(selector("", "T1", "")),
+            Some(entity(Some("tec:R1"), Some("tec:T1"), None))
+        );
+        assert_eq!(
+            informed(proto::EntitySelector {
+                agency_id: Some("TEC".to_string()),
+                ..Default::default()
+            }),
+            Some(InformedEntity {
+                agency_id: Some("tec:TEC".to_string()),
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn informed_entities_take_the_direction_of_the_trip() {
+        let feed = feed();
+        let mut by_trip = selector("R1", "", "");
+        by_trip.trip = Some(proto::TripDescriptor {
+            direction_id: Some(1),
+            ..Default::default()
+        });
+        assert_eq!(
+            to_informed_entity(&by_trip, &feed),
+            Some(InformedEntity {
+                route_id: Some("tec:R1".to_string()),
+                direction_id: Some(1),
+                ..Default::default()
+            })
+        );
+
+        by_trip.direction_id = Some(0);
+        assert_eq!(
+            to_informed_entity(&by_trip, &feed).and_then(|entity| entity.direction_id),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn informed_entities_unknown_to_the_feed_are_dropped() {
+        let feed = feed();
+        let informed = |selector: proto::EntitySelector| to_informed_entity(&selector, &feed);
+
+        assert_eq!(informed(selector("R2", "", "")), None);
+        assert_eq!(informed(selector("", "T2", "")), None);
+        assert_eq!(informed(selector("R1", "", "S2")), None);
+        assert_eq!(
+            informed(proto::EntitySelector {
+                route_type: Some(3),
+                ..Default::default()
+            }),
+            None
+        );
+        assert_eq!(informed(proto::EntitySelector::default()), None);
+        // Empty ids count as absent
+        assert_eq!(
+            informed(proto::EntitySelector {
+                route_id: Some(String::new()),
+                stop_id: Some("S1".to_string()),
+                ..Default::default()
+            }),
+            Some(entity(None, None, Some("tec:S1")))
+        );
+    }
+
+    #[test]
+    fn alerts_of_a_message() {
+        let alert = |informed_entity: Vec<proto::EntitySelector>| proto::Alert {
+            informed_entity,
+            header_text: Some(proto::TranslatedString {
+                translation: vec![proto::Translation {
+                    text: "Travaux".to_string(),
+                    language: Some("fr".to_string()),
+                }],
+            }),
+            active_period: vec![proto::TimeRange {
+                start: Some(100),
+                end: None,
+            }],
+            cause: Some(proto::Cause::Construction as i32),
+            effect: Some(proto::Effect::Detour as i32),
+            ..Default::default()
+        };
+        let message = FeedMessage {
+            entity: vec![
+                proto::FeedEntity {
+                    id: "A1".to_string(),
+                    alert: Some(alert(vec![selector("R1", "", ""), selector("R2", "", "")])),
+                    ..Default::default()
+                },
+                proto::FeedEntity {
+                    id: "A2".to_string(),
+                    alert: Some(alert(vec![selector("R2", "", "")])),
+                    ..Default::default()
+                },
+                proto::FeedEntity {
+                    id: "A3".to_string(),
+                    is_deleted: Some(true),
+                    ..Default::default()
+                },
+                proto::FeedEntity {
+                    id: "V1".to_string(),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+
+        let (alerts, deleted, unmatched) = to_alerts(&message, &feed());
+        assert_eq!(deleted, vec!["tec:A3"]);
+        assert_eq!(unmatched, 1);
+        assert_eq!(alerts.len(), 1);
+        let alert = &alerts[0];
+        assert_eq!(alert.id, "tec:A1");
+        assert_eq!(
+            alert.informed_entities,
+            vec![entity(Some("tec:R1"), None, None)]
+        );
+        assert_eq!(
+            (alert.cause, alert.effect),
+            (Cause::Construction, Effect::Detour)
+        );
+        assert_eq!(alert.header[0].language.as_deref(), Some("fr"));
+        assert_eq!(
+            alert.active_periods,
+            vec![ActivePeriod {
+                start: Some(100),
+                end: None
+            }]
+        );
+    }
+}
@@ -0,0 +1,379 @@
+use std::{env, fmt, path::PathBuf, sync::Arc, time::Duration};
+
+use prost::Message;
+use tokio::time::MissedTickBehavior;
+
+use super::{Feed, FeedSnapshot, LoadError, Store};
+use crate::logger;
+
+mod alerts;
+mod proto;
+mod trip_updates;
+mod vehicles;
+
+pub use proto::{FeedMessage, Incrementality};
+
+const DEFAULT_INTERVAL: u64 = 15;
+
+/// Where a GTFS-RT message is read from
+#[derive(Debug, Clone)]
+pub enum RealtimeSource {
+    /// Protobuf file rewritten in place by another process
+    File(PathBuf),
+    /// Protobuf served over HTTP, usually by a local proxy
+    Url(String),
+}
+
+/// A GTFS-RT source and the loaded feed its ids refer to, e.g.
+/// `tec=rt/vehicles.pb`. Without a name, the first feed is used.
+#[derive(Debug, Clone)]
+pub struct RealtimeFeed {
+    pub feed: Option<String>,
+    pub source: RealtimeSource,
+}
+
+/// GTFS-RT sources polled in the background
+pub struct RealtimeConfig {
+    pub interval: Duration,
+    pub vehicles: Vec<RealtimeFeed>,
+    pub trip_updates: Vec<RealtimeFeed>,
+    pub alerts: Vec<RealtimeFeed>,
+}
+
+impl RealtimeConfig {
+    /// Read `GTFS_RT_VEHICLES`, `GTFS_RT_TRIP_UPDATES` and `GTFS_RT_ALERTS`
+    /// (comma separated `[feed=]source`) and `GTFS_RT_INTERVAL` (seconds). Invalid values are reported and leave
+    /// the matching sources disabled.
+    pub fn from_env() -> Self {
+        let interval = match env::var("GTFS_RT_INTERVAL") {
+            Ok(raw) => match raw.trim().parse::<u64>() {
+                Ok(secs) if secs > 0 => secs,
+                _ => {
+                    logger::warn(
+                        "REALTIME",
+                        &format!(
+                            "Invalid GTFS_RT_INTERVAL {:?}, polling every {} seconds",
+                            raw, DEFAULT_INTERVAL
+                        ),
+                    );
+                    DEFAULT_INTERVAL
+                }
+            },
+            Err(_) => DEFAULT_INTERVAL,
+        };
+
+        Self {
+            interval: Duration::from_secs(interval),
+            vehicles: feeds_from_env("GTFS_RT_VEHICLES"),
+            trip_updates: feeds_from_env("GTFS_RT_TRIP_UPDATES"),
+            alerts: feeds_from_env("GTFS_RT_ALERTS"),
+        }
+    }
+}
+
+fn feeds_from_env(var: &str) -> Vec<RealtimeFeed> {
+    match env::var(var) {
+        Ok(raw) => match RealtimeFeed::parse_list(&raw) {
+            Ok(feeds) => feeds,
+            Err(e) => {
+                logger::warn("REALTIME", &format!("Invalid {}: {}, disabled", var, e));
+                Vec::new()
+            }
+        },
+        Err(_) => Vec::new(),
+    }
+}
+
+impl RealtimeFeed {
+    pub fn parse_list(raw: &str) -> Result<Vec<Self>, String> {
+        let mut feeds = Vec::new();
+        for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            // A URL may hold `=` in its query, a feed name holds neither `/` nor `:`
+            let (feed, source) = match entry.split_once('=') {
+                Some((name, source)) if !name.contains(['/', ':']) => {
+                    let name = name.trim();
+                    if name.is_empty() {
+                        return Err(format!("Invalid feed name in {:?}", entry));
+                    }
+                    (Some(name.to_string()), source)
+                }
+                _ => (None, entry),
+            };
+
+            feeds.push(Self {
+                feed,
+                source: RealtimeSource::parse(source),
+            });
+        }
+        Ok(feeds)
+    }
+
+    /// Loaded feed the ids of the messages refer to
+    fn resolve<'a>(&self, snapshot: &'a FeedSnapshot) -> Option<&'a Arc<Feed>> {
+        match &self.feed {
+            Some(name) => snapshot.get_feed(name),
+            None => snapshot.get_feeds().first(),
+        }
+    }
+}
+
+impl RealtimeSource {
+    pub fn parse(raw: &str) -> Self {
+        let raw = raw.trim();
+        if raw.starts_with("http://") || raw.starts_with("https://") {
+            RealtimeSource::Url(raw.to_string())
+        } else {
+            RealtimeSource::File(PathBuf::from(raw))
+        }
+    }
+
+    /// Read and decode the current message
+    pub async fn fetch(&self, client: &reqwest::Client) -> Result<FeedMessage, LoadError> {
+        let data = match self {
+            RealtimeSource::File(path) => {
+                tokio::fs::read(path).await.map_err(|e| LoadError::Io {
+                    message: format!("{}: {}", path.display(), e),
+                })?
+            }
+            RealtimeSource::Url(url) => client
+                .get(url)
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .map_err(|e| LoadError::Io {
+                    message: format!("{}: {}", url, e),
+                })?
+                .bytes()
+                .await
+                .map_err(|e| LoadError::Io {
+                    message: format!("{}: {}", url, e),
+                })?
+                .to_vec(),
+        };
+
+        FeedMessage::decode(data.as_slice()).map_err(|e| LoadError::InvalidValue {
+            message: format!("{} is not a GTFS-RT message: {}", self, e),
+        })
+    }
+}
+
+impl fmt::Display for RealtimeSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RealtimeSource::File(path) => write!(f, "file {}", path.display()),
+            RealtimeSource::Url(url) => write!(f, "url {}", url),
+        }
+    }
+}
+
+/// Entities a source is read for
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    Vehicles,
+    TripUpdates,
+    Alerts,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Kind::Vehicles => write!(f, "vehicle positions"),
+            Kind::TripUpdates => write!(f, "trip updates"),
+            Kind::Alerts => write!(f, "alerts"),
+        }
+    }
+}
+
+/// What the poller remembers of a source between two reads
+#[derive(Default, Clone)]
+struct SourceState {
+    /// Header timestamp of the last message read
+    timestamp: Option<u64>,
+    /// The last read failed, so the error was already logged
+    failing: bool,
+    /// The first message was logged
+    reported: bool,
+}
+
+/// Start polling the sources enabled in `config`
+pub fn spawn(store: Arc<Store>, config: RealtimeConfig) {
+    for (kind, feeds) in [
+        (Kind::Vehicles, config.vehicles),
+        (Kind::TripUpdates, config.trip_updates),
+        (Kind::Alerts, config.alerts),
+    ] {
+        let feeds: Vec<RealtimeFeed> = feeds
+            .into_iter()
+            .filter(|feed| match &feed.feed {
+                Some(name) if !store.get_feed_configs().iter().any(|c| c.name == *name) => {
+                    logger::warn(
+                        "REALTIME",
+                        &format!("Unknown feed {} for {}, ignored", name, feed.source),
+                    );
+                    false
+                }
+                _ => true,
+            })
+            .collect();
+        if feeds.is_empty() {
+            continue;
+        }
+
+        for feed in feeds.iter() {
+            logger::fine(
+                "REALTIME",
+                &format!(
+                    "Reading {} from {} every {:?}",
+                    kind, feed.source, config.interval
+                ),
+            );
+        }
+        tokio::spawn(poll(store.clone(), kind, feeds, config.interval));
+    }
+}
+
+/// Push the entities of every new message to the store. A message whose
+/// header timestamp did not move is skipped.
+async fn poll(store: Arc<Store>, kind: Kind, feeds: Vec<RealtimeFeed>, interval: Duration) {
+    let client = match reqwest::Client::builder().timeout(interval).build() {
+        Ok(client) => client,
+        Err(e) => {
+            logger::critical("REALTIME", &format!("Could not build HTTP client: {}", e));
+            return;
+        }
+    };
+    let mut states = vec![SourceState::default(); feeds.len()];
+
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        ticker.tick().await;
+
+        for (feed, state) in feeds.iter().zip(states.iter_mut()) {
+            let message = match feed.source.fetch(&client).await {
+                Ok(message) => message,
+                Err(e) => {
+                    if !state.failing {
+                        logger::warn("REALTIME", &format!("Could not read {}: {}", kind, e));
+                    }
+                    state.failing = true;
+                    continue;
+                }
+            };
+            if state.failing {
+                logger::info("REALTIME", &format!("Reading {} again", feed.source));
+                state.failing = false;
+            }
+
+            let timestamp = message.header.timestamp;
+            if timestamp.is_some() && timestamp == state.timestamp {
+                continue;
+            }
+            state.timestamp = timestamp;
+
+            let snapshot = store.get_feed();
+            // Only missing while no feed could be loaded at all
+            let static_feed = match feed.resolve(&snapshot) {
+                Some(static_feed) => static_feed,
+                None => continue,
+            };
+
+            let full_dataset = message.header.incrementality() == Incrementality::FullDataset;
+            let (read, unmatched) = match kind {
+                Kind::Vehicles => {
+                    let (buses, unmatched) = vehicles::to_buses(&message, static_feed);
+                    let read = buses.len();
+                    store.ingest_buses(buses);
+                    (read, unmatched)
+                }
+                Kind::TripUpdates => {
+                    let (updates, deleted, unmatched) =
+                        trip_updates::to_trip_updates(&message, static_feed);
+                    let read = updates.len();
+                    store.apply_trip_updates(
+                        &feed.source.to_string(),
+                        full_dataset,
+                        updates,
+                        deleted,
+                    );
+                    (read, unmatched)
+                }
+                Kind::Alerts => {
+                    let (alerts, deleted, unmatched) = alerts::to_alerts(&message, static_feed);
+                    let read = alerts.len();
+                    store.apply_alerts(&feed.source.to_string(), full_dataset, alerts, deleted);
+                    (read, unmatched)
+                }
+            };
+            if !state.reported {
+                state.reported = true;
+                logger::fine(
+                    "REALTIME",
+                    &format!(
+                        "Read {} {} from {}, {} left out",
+                        read, kind, feed.source, unmatched
+                    ),
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw: &str) -> Vec<(Option<String>, String)> {
+        RealtimeFeed::parse_list(raw)
+            .unwrap()
+            .into_iter()
+            .map(|feed| {
+                let source = match feed.source {
+                    RealtimeSource::File(path) => format!("file {}", path.display()),
+                    RealtimeSource::Url(url) => format!("url {}", url),
+                };
+                (feed.feed, source)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn feeds_are_named_before_their_source() {
+        assert_eq!(
+            parse(" tec = rt/vehicles.pb , ,https://example.org/vehicles.pb"),
+            vec![
+                (Some("tec".to_string()), "file rt/vehicles.pb".to_string()),
+                (None, "url https://example.org/vehicles.pb".to_string()),
+            ]
+        );
+        assert!(parse("").is_empty());
+    }
+
+    #[test]
+    fn equal_signs_inside_a_url_do_not_name_a_feed() {
+        assert_eq!(
+            parse("http://localhost:8080/rt?feed=vehicles&key=abc"),
+            vec![(
+                None,
+                "url http://localhost:8080/rt?feed=vehicles&key=abc".to_string()
+            )]
+        );
+        assert_eq!(
+            parse("stib=https://example.org/rt?key=a=b"),
+            vec![(
+                Some("stib".to_string()),
+                "url https://example.org/rt?key=a=b".to_string()
+            )]
+        );
+        assert_eq!(
+            parse("rt/a=b.pb"),
+            vec![(None, "file rt/a=b.pb".to_string())]
+        );
+    }
+
+    #[test]
+    fn empty_feed_names_are_rejected() {
+        assert!(RealtimeFeed::parse_list(" =rt/vehicles.pb").is_err());
+    }
+}
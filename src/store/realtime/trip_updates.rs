@@ -0,0 +1,103 @@
+use chrono::NaiveDate;
+
+use super::proto::{self, FeedMessage, ScheduleRelationship, StopTimeScheduleRelationship};
+use crate::store::{
+    Feed, StopRelationship, StopTimeEvent, StopTimeUpdate, TripRelationship, TripUpdate,
+    TripUpdateKey,
+};
+
+/// Trip updates of `message` for trips of `feed`, namespaced like the rest
+/// of the API, and ids and start dates of the trips whose update was
+/// deleted. Also returns how many updates were left out for naming no known
+/// trip.
+pub(super) fn to_trip_updates(
+    message: &FeedMessage,
+    feed: &Feed,
+) -> (Vec<TripUpdate>, Vec<TripUpdateKey>, usize) {
+    let mut updates = Vec::new();
+    let mut deleted = Vec::new();
+    let mut unmatched = 0;
+    for entity in message.entity.iter() {
+        let update = match &entity.trip_update {
+            Some(update) => update,
+            None => continue,
+        };
+        // Trips added in realtime are not in the schedule the updates are
+        // merged with
+        let trip = match update
+            .trip
+            .trip_id
+            .as_deref()
+            .and_then(|trip_id| feed.get_gtfs().trips.get(trip_id))
+        {
+            Some(trip) => trip,
+            None => {
+                unmatched += 1;
+                continue;
+            }
+        };
+
+        let start_date = update
+            .trip
+            .start_date
+            .as_deref()
+            .and_then(|date| NaiveDate::parse_from_str(date, "%Y%m%d").ok());
+
+        if entity.is_deleted.unwrap_or(false) {
+            deleted.push((feed.namespaced(&trip.id), start_date));
+            continue;
+        }
+
+        let vehicle_id = update
+            .vehicle
+            .as_ref()
+            .and_then(|descriptor| descriptor.id.as_deref().or(descriptor.label.as_deref()))
+            .filter(|vehicle_id| !vehicle_id.is_empty());
+
+        updates.push(TripUpdate {
+            trip_id: feed.namespaced(&trip.id),
+            start_date,
+            relationship: to_trip_relationship(update.trip.schedule_relationship()),
+            vehicle_id: vehicle_id.map(|vehicle_id| feed.namespaced(vehicle_id)),
+            timestamp: update.timestamp.or(message.header.timestamp),
+            delay: update.delay,
+            // Ordered by stop sequence in valid feeds
+            stop_times: update.stop_time_update.iter().map(to_stop_time).collect(),
+        });
+    }
+    (updates, deleted, unmatched)
+}
+
+fn to_stop_time(update: &proto::StopTimeUpdate) -> StopTimeUpdate {
+    StopTimeUpdate {
+        stop_sequence: update.stop_sequence,
+        stop_id: update.stop_id.clone().filter(|stop_id| !stop_id.is_empty()),
+        arrival: update.arrival.as_ref().map(to_event),
+        departure: update.departure.as_ref().map(to_event),
+        relationship: match update.schedule_relationship() {
+            StopTimeScheduleRelationship::Scheduled => StopRelationship::Scheduled,
+            StopTimeScheduleRelationship::Skipped => StopRelationship::Skipped,
+            StopTimeScheduleRelationship::NoData => StopRelationship::NoData,
+            StopTimeScheduleRelationship::Unscheduled => StopRelationship::Unscheduled,
+        },
+    }
+}
+
+fn to_event(event: &proto::StopTimeEvent) -> StopTimeEvent {
+    StopTimeEvent {
+        delay: event.delay,
+        time: event.time,
+    }
+}
+
+fn to_trip_relationship(relationship: ScheduleRelationship) -> TripRelationship {
+    match relationship {
+        ScheduleRelationship::Scheduled => TripRelationship::Scheduled,
+        ScheduleRelationship::Added => TripRelationship::Added,
+        ScheduleRelationship::Unscheduled => TripRelationship::Unscheduled,
+        ScheduleRelationship::Canceled => TripRelationship::Canceled,
+        ScheduleRelationship::Replacement => TripRelationship::Replacement,
+        ScheduleRelationship::Duplicated => TripRelationship::Duplicated,
+        ScheduleRelationship::Deleted => TripRelationship::Deleted,
+    }
+}
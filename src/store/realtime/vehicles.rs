@@ -0,0 +1,261 @@
+use super::proto::{FeedMessage, VehiclePosition};
+use crate::store::{Bus, Feed};
+
+/// Buses of the vehicle positions of `message`, with their trip and route
+/// resolved against `feed` and namespaced like the rest of the API. Also
+/// returns how many vehicles were left out for lacking a position or a
+/// known route.
+pub(super) fn to_buses(message: &FeedMessage, feed: &Feed) -> (Vec<Bus>, usize) {
+    let mut buses = Vec::new();
+    let mut unmatched = 0;
+    for entity in message.entity.iter() {
+        if entity.is_deleted.unwrap_or(false) {
+            continue;
+        }
+        let vehicle = match &entity.vehicle {
+            Some(vehicle) => vehicle,
+            None => continue,
+        };
+
+        match to_bus(&entity.id, vehicle, message.header.timestamp, feed) {
+            Some(bus) => buses.push(bus),
+            None => unmatched += 1,
+        }
+    }
+    (buses, unmatched)
+}
+
+fn to_bus(
+    entity_id: &str,
+    vehicle: &VehiclePosition,
+    header_timestamp: Option<u64>,
+    feed: &Feed,
+) -> Option<Bus> {
+    let position = vehicle.position.as_ref()?;
+    let gtfs = feed.get_gtfs();
+
+    let trip_id = vehicle
+        .trip
+        .as_ref()
+        .and_then(|trip| trip.trip_id.as_deref())
+        .filter(|trip_id| !trip_id.is_empty());
+    let trip = trip_id.and_then(|trip_id| gtfs.trips.get(trip_id));
+
+    // The descriptor may only name the trip, the route comes from the feed
+    let route_id = vehicle
+        .trip
+        .as_ref()
+        .and_then(|trip| trip.route_id.as_deref())
+        .filter(|route_id| !route_id.is_empty())
+        .or_else(|| trip.map(|trip| trip.route_id.as_str()))?;
+    let route = gtfs.routes.get(route_id)?;
+
+    let line = if route.short_name.is_empty() {
+        &route.long_name
+    } else {
+        &route.short_name
+    };
+
+    let vehicle_id = vehicle
+        .vehicle
+        .as_ref()
+        .and_then(|descriptor| {
+            let non_empty = |id: &&str| !id.is_empty();
+            descriptor
+                .id
+                .as_deref()
+                .filter(non_empty)
+                .or(descriptor.label.as_deref().filter(non_empty))
+        })
+        .unwrap_or(entity_id);
+
+    Some(
+        Bus::new(
+            feed.namespaced(vehicle_id),
+            line.clone(),
+            feed.namespaced(route_id),
+            position.latitude,
+            position.longitude,
+            position.speed.unwrap_or_default(),
+            vehicle.timestamp.or(header_timestamp).unwrap_or_default(),
+        )
+        .with_trip_id(trip.map(|trip| feed.namespaced(&trip.id))),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use gtfs_structures::{Gtfs, Route, Trip};
+
+    use super::*;
+    use crate::store::{
+        realtime::proto::{FeedEntity, Position, TripDescriptor, VehicleDescriptor},
+        FeedConfig, FeedSource, ValidationReport,
+    };
+
+    /// Trip `T1` of route `R1` named `1`, and route `R2` with only a long
+    /// name
+    fn feed() -> Feed {
+        let mut gtfs = Gtfs::default();
+        gtfs.routes.insert(
+            "R1".to_string(),
+            Route {
+                id: "R1".to_string(),
+                short_name: "1".to_string(),
+                long_name: "Charleroi - Gilly".to_string(),
+                ..Default::default()
+            },
+        );
+        gtfs.routes.insert(
+            "R2".to_string(),
+            Route {
+                id: "R2".to_string(),
+                long_name: "Navette".to_string(),
+                ..Default::default()
+            },
+        );
+        gtfs.trips.insert(
+            "T1".to_string(),
+            Trip {
+                id: "T1".to_string(),
+                route_id: "R1".to_string(),
+                ..Default::default()
+            },
+        );
+
+        let config = FeedConfig {
+            name: "tec".to_string(),
+            source: FeedSource::Directory("gtfs".into()),
+        };
+        Feed::from_parts(&config, String::new(), gtfs, ValidationReport::default())
+    }
+
+    fn trip(trip_id: Option<&str>, route_id: Option<&str>) -> Option<TripDescriptor> {
+        Some(TripDescriptor {
+            trip_id: trip_id.map(str::to_string),
+            route_id: route_id.map(str::to_string),
+            ..Default::default()
+        })
+    }
+
+    fn vehicle(id: Option<&str>, label: Option<&str>) -> Option<VehicleDescriptor> {
+        Some(VehicleDescriptor {
+            id: id.map(str::to_string),
+            label: label.map(str::to_string),
+            ..Default::default()
+        })
+    }
+
+    fn entity(
+        id: &str,
+        trip: Option<TripDescriptor>,
+        vehicle: Option<VehicleDescriptor>,
+    ) -> FeedEntity {
+        FeedEntity {
+            id: id.to_string(),
+            vehicle: Some(VehiclePosition {
+                trip,
+                vehicle,
+                position: Some(Position {
+                    latitude: 50.41,
+                    longitude: 4.44,
+                    speed: Some(8.5),
+                    ..Default::default()
+                }),
+                timestamp: Some(1_000),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn message(entity: Vec<FeedEntity>) -> FeedMessage {
+        let mut message = FeedMessage {
+            entity,
+            ..Default::default()
+        };
+        message.header.timestamp = Some(2_000);
+        message
+    }
+
+    fn bus(id: &str, line: &str, line_id: &str, speed: f32, last_update: u64) -> Bus {
+        Bus::new(
+            id.to_string(),
+            line.to_string(),
+            line_id.to_string(),
+            50.41,
+            4.44,
+            speed,
+            last_update,
+        )
+    }
+
+    #[test]
+    fn buses_are_namespaced_and_take_the_route_of_their_trip() {
+        let (buses, unmatched) = to_buses(
+            &message(vec![
+                entity("E1", trip(Some("T1"), None), vehicle(Some("V1"), None)),
+                entity("E2", trip(None, Some("R2")), vehicle(None, Some("L2"))),
+            ]),
+            &feed(),
+        );
+        assert_eq!(unmatched, 0);
+        assert_eq!(
+            buses,
+            vec![
+                bus("tec:V1", "1", "tec:R1", 8.5, 1_000).with_trip_id(Some("tec:T1".to_string())),
+                bus("tec:L2", "Navette", "tec:R2", 8.5, 1_000),
+            ]
+        );
+    }
+
+    #[test]
+    fn vehicle_ids_fall_back_to_the_label_then_the_entity() {
+        let id = |vehicle: Option<VehicleDescriptor>| {
+            let (buses, _) = to_buses(
+                &message(vec![entity("E1", trip(None, Some("R1")), vehicle)]),
+                &feed(),
+            );
+            buses[0].get_id().to_string()
+        };
+
+        assert_eq!(id(vehicle(Some("V1"), Some("L1"))), "tec:V1");
+        assert_eq!(id(vehicle(Some(""), Some("L1"))), "tec:L1");
+        assert_eq!(id(vehicle(None, Some(""))), "tec:E1");
+        assert_eq!(id(None), "tec:E1");
+    }
+
+    #[test]
+    fn vehicles_without_position_or_known_route_are_left_out() {
+        let mut no_position = entity("E1", trip(Some("T1"), None), None);
+        no_position.vehicle.as_mut().unwrap().position = None;
+        let mut no_timestamp = entity("E6", trip(Some("T1"), Some("")), None);
+        no_timestamp.vehicle.as_mut().unwrap().timestamp = None;
+        let deleted = FeedEntity {
+            is_deleted: Some(true),
+            ..entity("E7", trip(Some("T1"), None), None)
+        };
+
+        let (buses, unmatched) = to_buses(
+            &message(vec![
+                no_position,
+                entity("E2", trip(None, Some("R3")), None),
+                entity("E3", trip(Some("T2"), None), None),
+                entity("E4", None, None),
+                FeedEntity {
+                    id: "E5".to_string(),
+                    ..Default::default()
+                },
+                no_timestamp,
+                deleted,
+            ]),
+            &feed(),
+        );
+        assert_eq!(unmatched, 4);
+        // An empty route id is ignored and the header time used
+        assert_eq!(
+            buses,
+            vec![bus("tec:E6", "1", "tec:R1", 8.5, 2_000).with_trip_id(Some("tec:T1".to_string()))]
+        );
+    }
+}
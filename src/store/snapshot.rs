@@ -0,0 +1,127 @@
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use gtfs_structures::Trip;
+
+use super::{
+    feed::NAMESPACE_SEPARATOR,
+    index::{Indexes, ReverseStopIndex, ShapeIndex, StopIndex},
+    Feed, Symbol,
+};
+
+/// Immutable view of the loaded GTFS feeds and every index derived from them.
+///
+/// A snapshot is never mutated once built: a refresh builds a new one and
+/// the store swaps it in, so a handler holding an `Arc<FeedSnapshot>` always
+/// sees feeds and indexes that belong together.
+///
+/// Indexes span all feeds and hold namespaced ids (`tec:X1234`).
+pub struct FeedSnapshot {
+    version: u64,
+    loaded_at: DateTime<Utc>,
+    feeds: Vec<Arc<Feed>>,
+    indexes: Indexes,
+}
+
+impl FeedSnapshot {
+    pub fn build(version: u64, feeds: Vec<Arc<Feed>>) -> Self {
+        let indexes = Indexes::build(&feeds);
+        Self::from_parts(version, feeds, indexes)
+    }
+
+    /// Snapshot from already built indexes, e.g. restored from the cache
+    pub fn from_parts(version: u64, feeds: Vec<Arc<Feed>>, indexes: Indexes) -> Self {
+        Self {
+            version,
+            loaded_at: Utc::now(),
+            feeds,
+            indexes,
+        }
+    }
+
+    pub fn get_version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn get_loaded_at(&self) -> DateTime<Utc> {
+        self.loaded_at
+    }
+
+    pub fn get_feeds(&self) -> &[Arc<Feed>] {
+        &self.feeds
+    }
+
+    pub fn get_feed(&self, name: &str) -> Option<&Arc<Feed>> {
+        self.feeds.iter().find(|f| f.get_name() == name)
+    }
+
+    /// Feeds an id may belong to, with the id stripped of its namespace.
+    ///
+    /// `tec:X1234` only matches the `tec` feed. A bare id matches the
+    /// `filter` feed if given, every feed otherwise.
+    pub fn resolve<'a, 'b>(
+        &'a self,
+        id: &'b str,
+        filter: Option<&str>,
+    ) -> Vec<(&'a Feed, &'b str)> {
+        if let Some((name, local)) = id.split_once(NAMESPACE_SEPARATOR) {
+            if let Some(feed) = self.get_feed(name) {
+                return match filter {
+                    Some(filter) if filter != name => Vec::new(),
+                    _ => vec![(feed.as_ref(), local)],
+                };
+            }
+        }
+
+        self.feeds
+            .iter()
+            .filter(|f| match filter {
+                Some(filter) => f.get_name() == filter,
+                None => true,
+            })
+            .map(|f| (f.as_ref(), id))
+            .collect()
+    }
+
+    /// Namespaced id of a route given by namespaced or bare id, see
+    /// [`FeedSnapshot::resolve`]
+    pub fn find_route_id(&self, id: &str, filter: Option<&str>) -> Option<String> {
+        self.resolve(id, filter)
+            .into_iter()
+            .find(|(feed, id)| feed.get_gtfs().routes.contains_key(*id))
+            .map(|(feed, id)| feed.namespaced(id))
+    }
+
+    /// Trip by namespaced or bare id, see [`FeedSnapshot::resolve`]
+    pub fn find_trip(&self, id: &str, filter: Option<&str>) -> Option<(&Feed, &Trip)> {
+        self.resolve(id, filter)
+            .into_iter()
+            .find_map(|(feed, id)| feed.get_gtfs().get_trip(id).ok().map(|t| (feed, t)))
+    }
+
+    pub fn get_indexes(&self) -> &Indexes {
+        &self.indexes
+    }
+
+    /// Namespaced id of a symbol held by an index
+    pub fn resolve_symbol(&self, symbol: Symbol) -> &str {
+        self.indexes.interner.resolve(symbol)
+    }
+
+    /// Symbol of a namespaced id, `None` when no index refers to it
+    pub fn get_symbol(&self, id: &str) -> Option<Symbol> {
+        self.indexes.interner.get(id)
+    }
+
+    pub fn get_stops(&self) -> &StopIndex {
+        &self.indexes.stops
+    }
+
+    pub fn get_reverse_stops(&self) -> &ReverseStopIndex {
+        &self.indexes.reverse_stops
+    }
+
+    pub fn get_shapes(&self) -> &ShapeIndex {
+        &self.indexes.shapes
+    }
+}
@@ -0,0 +1,285 @@
+use std::{
+    fmt,
+    io::{Cursor, Read},
+    path::PathBuf,
+    time::SystemTime,
+};
+
+use gtfs_structures::{GtfsReader, RawGtfs};
+use reqwest::header::{ETAG, LAST_MODIFIED};
+use sha2::{Digest, Sha256};
+
+use super::{alerts::MANUAL_NAMESPACE, feed::NAMESPACE_SEPARATOR, LoadError};
+
+/// Where the GTFS feed is read from
+#[derive(Debug, Clone)]
+pub enum FeedSource {
+    /// Unpacked feed, one `.txt` file per table
+    Directory(PathBuf),
+    /// Zip archive as published by LETEC
+    Zip(PathBuf),
+    /// Zip archive served over HTTP by an internal mirror
+    Url(String),
+}
+
+/// A named feed as configured, e.g. `tec=gtfs`
+#[derive(Debug, Clone)]
+pub struct FeedConfig {
+    pub name: String,
+    pub source: FeedSource,
+}
+
+/// Raw content of a feed, read once so it can be hashed then parsed
+pub struct FetchedFeed {
+    checksum: String,
+    content: FeedContent,
+}
+
+enum FeedContent {
+    Directory(PathBuf),
+    Archive(Vec<u8>),
+}
+
+impl FeedConfig {
+    /// Parse a comma separated `name=source` list such as
+    /// `tec=gtfs,sncb=/data/sncb.zip`
+    pub fn parse_list(raw: &str) -> Result<Vec<Self>, String> {
+        let mut feeds: Vec<Self> = Vec::new();
+        for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (name, source) = match entry.split_once('=') {
+                Some((name, source)) => (name.trim(), source.trim()),
+                None => return Err(format!("{:?} is not name=source", entry)),
+            };
+
+            if name.is_empty() || name.contains(NAMESPACE_SEPARATOR) {
+                return Err(format!("Invalid feed name {:?}", name));
+            }
+            if name == MANUAL_NAMESPACE {
+                return Err(format!(
+                    "Feed name {} is kept for the alerts added through the API",
+                    name
+                ));
+            }
+            if feeds.iter().any(|f| f.name == name) {
+                return Err(format!("Feed {} is defined twice", name));
+            }
+
+            feeds.push(Self {
+                name: name.to_string(),
+                source: FeedSource::parse(source),
+            });
+        }
+
+        if feeds.is_empty() {
+            return Err("No feed configured".to_string());
+        }
+        Ok(feeds)
+    }
+}
+
+impl FeedSource {
+    /// `http(s)://...` is a mirror URL, a path ending in `.zip` an archive,
+    /// anything else an unpacked directory
+    pub fn parse(raw: &str) -> Self {
+        let raw = raw.trim();
+        if raw.starts_with("http://") || raw.starts_with("https://") {
+            return FeedSource::Url(raw.to_string());
+        }
+
+        let path = PathBuf::from(raw);
+        let is_zip = match path.extension() {
+            Some(ext) => ext.eq_ignore_ascii_case("zip"),
+            None => false,
+        };
+
+        if is_zip || path.is_file() {
+            FeedSource::Zip(path)
+        } else {
+            FeedSource::Directory(path)
+        }
+    }
+
+    /// Read the whole feed and compute its sha256
+    pub fn fetch(&self) -> Result<FetchedFeed, LoadError> {
+        match self {
+            FeedSource::Directory(path) => {
+                let mut entries = Vec::new();
+                for entry in std::fs::read_dir(path).map_err(|e| LoadError::Io {
+                    message: format!("{}: {}", path.display(), e),
+                })? {
+                    let entry = entry.map_err(|e| LoadError::Io {
+                        message: e.to_string(),
+                    })?;
+                    if entry.path().is_file() {
+                        entries.push(entry.path());
+                    }
+                }
+                entries.sort();
+
+                // Hash names too so a renamed file changes the checksum
+                let mut hasher = Sha256::new();
+                for file in entries {
+                    let content = std::fs::read(&file).map_err(|e| LoadError::Io {
+                        message: format!("{}: {}", file.display(), e),
+                    })?;
+                    if let Some(name) = file.file_name() {
+                        hasher.update(name.to_string_lossy().as_bytes());
+                    }
+                    hasher.update(&content);
+                }
+
+                Ok(FetchedFeed {
+                    checksum: format!("{:x}", hasher.finalize()),
+                    content: FeedContent::Directory(path.clone()),
+                })
+            }
+            FeedSource::Zip(path) => {
+                let data = std::fs::read(path).map_err(|e| LoadError::Io {
+                    message: format!("{}: {}", path.display(), e),
+                })?;
+                Ok(FetchedFeed::archive(data))
+            }
+            FeedSource::Url(url) => {
+                let mut response = reqwest::blocking::get(url)
+                    .and_then(|r| r.error_for_status())
+                    .map_err(|e| LoadError::Io {
+                        message: format!("{}: {}", url, e),
+                    })?;
+                let mut data = Vec::new();
+                response.read_to_end(&mut data).map_err(|e| LoadError::Io {
+                    message: format!("{}: {}", url, e),
+                })?;
+                Ok(FetchedFeed::archive(data))
+            }
+        }
+    }
+
+    /// Cheap marker that changes whenever the content is likely to have
+    /// changed: newest mtime, count and size of local files, or the
+    /// `ETag`/`Last-Modified` headers of the mirror. `None` when the mirror
+    /// sends neither header and the content has to be hashed to know.
+    pub async fn fingerprint(&self, client: &reqwest::Client) -> Result<Option<String>, String> {
+        match self {
+            FeedSource::Directory(_) | FeedSource::Zip(_) => {
+                // Reading metadata blocks, keep it off the runtime threads
+                let source = self.clone();
+                tokio::task::spawn_blocking(move || source.local_fingerprint())
+                    .await
+                    .map_err(|e| e.to_string())?
+                    .map(Some)
+            }
+            FeedSource::Url(url) => {
+                let response = client
+                    .head(url)
+                    .send()
+                    .await
+                    .and_then(|r| r.error_for_status())
+                    .map_err(|e| e.to_string())?;
+                let headers = response.headers();
+                let etag = headers.get(ETAG).and_then(|v| v.to_str().ok());
+                let modified = headers.get(LAST_MODIFIED).and_then(|v| v.to_str().ok());
+                match (etag, modified) {
+                    (None, None) => Ok(None),
+                    (etag, modified) => Ok(Some(format!(
+                        "{}/{}",
+                        etag.unwrap_or_default(),
+                        modified.unwrap_or_default()
+                    ))),
+                }
+            }
+        }
+    }
+
+    /// Fingerprint of a directory or zip source, read from the file system
+    fn local_fingerprint(&self) -> Result<String, String> {
+        match self {
+            FeedSource::Directory(path) => {
+                let mut newest = SystemTime::UNIX_EPOCH;
+                let mut count = 0;
+                let mut size = 0;
+                for entry in std::fs::read_dir(path).map_err(|e| e.to_string())? {
+                    let meta = entry
+                        .and_then(|e| e.metadata())
+                        .map_err(|e| e.to_string())?;
+                    if !meta.is_file() {
+                        continue;
+                    }
+                    newest = newest.max(meta.modified().map_err(|e| e.to_string())?);
+                    count += 1;
+                    size += meta.len();
+                }
+                Ok(format!("{:?}/{}/{}", newest, count, size))
+            }
+            FeedSource::Zip(path) => {
+                let meta = std::fs::metadata(path).map_err(|e| e.to_string())?;
+                let modified = meta.modified().map_err(|e| e.to_string())?;
+                Ok(format!("{:?}/{}", modified, meta.len()))
+            }
+            FeedSource::Url(url) => Err(format!("{} is not a local source", url)),
+        }
+    }
+}
+
+impl fmt::Display for FeedSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FeedSource::Directory(path) => write!(f, "directory {}", path.display()),
+            FeedSource::Zip(path) => write!(f, "zip {}", path.display()),
+            FeedSource::Url(url) => write!(f, "url {}", url),
+        }
+    }
+}
+
+impl FetchedFeed {
+    fn archive(data: Vec<u8>) -> Self {
+        Self {
+            checksum: format!("{:x}", Sha256::digest(&data)),
+            content: FeedContent::Archive(data),
+        }
+    }
+
+    pub fn get_checksum(&self) -> &str {
+        &self.checksum
+    }
+
+    /// Read every file of the feed, references between them are not checked
+    pub fn read(self) -> Result<RawGtfs, LoadError> {
+        let raw = match self.content {
+            FeedContent::Directory(path) => GtfsReader::default()
+                .raw()
+                .read_from_path(path.display().to_string())?,
+            FeedContent::Archive(data) => GtfsReader::default()
+                .raw()
+                .read_from_reader(Cursor::new(data))?,
+        };
+        Ok(raw)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn fingerprint_follows_local_files() {
+        let dir = std::env::temp_dir().join(format!("tec-gtfs-source-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let client = reqwest::Client::new();
+
+        let source = FeedSource::Directory(dir.clone());
+        let empty = source.fingerprint(&client).await.unwrap();
+        assert!(empty.is_some());
+        std::fs::write(dir.join("stops.txt"), "stop_id\n").unwrap();
+        let one = source.fingerprint(&client).await.unwrap();
+        assert_ne!(one, empty);
+        assert_eq!(source.fingerprint(&client).await.unwrap(), one);
+
+        let zip = FeedSource::Zip(dir.join("stops.txt"));
+        assert!(zip.fingerprint(&client).await.unwrap().is_some());
+        let missing = FeedSource::Zip(dir.join("gtfs.zip"));
+        assert!(missing.fingerprint(&client).await.is_err());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,585 @@
+use ahash::AHashMap;
+use chrono::{NaiveDate, TimeZone, Utc};
+use chrono_tz::Tz;
+use gtfs_structures::{StopTime, Trip};
+use serde::Serialize;
+
+use super::Feed;
+
+/// Schedule relationship of a trip, as published by GTFS-RT
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TripRelationship {
+    Scheduled,
+    Added,
+    Unscheduled,
+    Canceled,
+    Replacement,
+    Duplicated,
+    Deleted,
+}
+
+/// Schedule relationship of a stop of a trip
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StopRelationship {
+    Scheduled,
+    Skipped,
+    /// No prediction for this stop, nor for the following ones until the
+    /// next update
+    NoData,
+    Unscheduled,
+}
+
+/// Predicted arrival or departure, as a delay or as a POSIX time
+#[derive(Debug, Clone, Default)]
+pub struct StopTimeEvent {
+    pub delay: Option<i32>,
+    pub time: Option<i64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct StopTimeUpdate {
+    pub stop_sequence: Option<u32>,
+    /// Id of the stop in the feed of the trip, without namespace
+    pub stop_id: Option<String>,
+    pub arrival: Option<StopTimeEvent>,
+    pub departure: Option<StopTimeEvent>,
+    pub relationship: StopRelationship,
+}
+
+/// Latest GTFS-RT update of a trip of the static schedule
+#[derive(Debug, Clone)]
+pub struct TripUpdate {
+    /// Namespaced
+    pub trip_id: String,
+    pub start_date: Option<NaiveDate>,
+    pub relationship: TripRelationship,
+    /// Namespaced
+    pub vehicle_id: Option<String>,
+    pub timestamp: Option<u64>,
+    /// Delay of the stops not covered by a stop time update
+    pub delay: Option<i32>,
+    /// Ordered by stop sequence
+    pub stop_times: Vec<StopTimeUpdate>,
+}
+
+/// Namespaced trip id and start date of an update
+pub type TripUpdateKey = (String, Option<NaiveDate>);
+
+/// Trip updates read from the GTFS-RT sources, by namespaced trip id and
+/// start date: a trip running past midnight can have an update for each of
+/// two service days
+#[derive(Default)]
+pub struct TripUpdates {
+    /// Update and the source it was read from
+    trips: AHashMap<TripUpdateKey, (String, TripUpdate)>,
+}
+
+impl TripUpdates {
+    /// Store the updates of a message read from `source`. A full dataset
+    /// replaces every trip previously read from it, a differential one only
+    /// the trips it lists.
+    pub fn apply(
+        &mut self,
+        source: &str,
+        full_dataset: bool,
+        updates: Vec<TripUpdate>,
+        deleted: Vec<TripUpdateKey>,
+    ) {
+        if full_dataset {
+            self.trips.retain(|_, (from, _)| from != source);
+        }
+        for key in deleted {
+            self.trips.remove(&key);
+        }
+        for update in updates {
+            let key = (update.trip_id.clone(), update.start_date);
+            self.trips.insert(key, (source.to_string(), update));
+        }
+    }
+
+    /// Update of a trip on `start_date`, `None` being the updates that do
+    /// not give one
+    pub fn get(&self, trip_id: &str, start_date: Option<NaiveDate>) -> Option<&TripUpdate> {
+        self.trips
+            .get(&(trip_id.to_string(), start_date))
+            .map(|(_, update)| update)
+    }
+}
+
+/// Stop of a trip with its scheduled and predicted times, in seconds since
+/// the start of the service day like the static `stop_times`
+#[derive(Serialize, Debug)]
+pub struct RealtimeStopTime {
+    stop_id: String,
+    stop_sequence: u16,
+    scheduled_arrival: Option<u32>,
+    scheduled_departure: Option<u32>,
+    predicted_arrival: Option<i64>,
+    predicted_departure: Option<i64>,
+    arrival_delay: Option<i32>,
+    departure_delay: Option<i32>,
+    skipped: bool,
+    /// Of the stop time update applying to this stop, `None` when the delay
+    /// is propagated from an earlier stop
+    schedule_relationship: Option<StopRelationship>,
+}
+
+/// Static trip merged with its latest update
+#[derive(Serialize, Debug)]
+pub struct RealtimeTrip {
+    trip_id: String,
+    route_id: String,
+    /// An update of the trip is known
+    realtime: bool,
+    schedule_relationship: TripRelationship,
+    start_date: Option<NaiveDate>,
+    vehicle_id: Option<String>,
+    timestamp: Option<u64>,
+    stop_times: Vec<RealtimeStopTime>,
+}
+
+impl RealtimeTrip {
+    /// Predictions for every stop of `trip`. A delay applies to the
+    /// following stops until the next stop time update, starting from the
+    /// delay of the trip itself, and absolute times are compared with the
+    /// schedule in the timezone of the agency.
+    pub fn build(feed: &Feed, trip: &Trip, update: Option<&TripUpdate>) -> Self {
+        let relationship = update.map_or(TripRelationship::Scheduled, |u| u.relationship);
+        let canceled = matches!(
+            relationship,
+            TripRelationship::Canceled | TripRelationship::Deleted
+        );
+        let stop_updates = update.map_or(&[][..], |u| u.stop_times.as_slice());
+        let day_start = update.and_then(|u| {
+            let date = u.start_date.or_else(|| service_date(feed, trip))?;
+            service_day_start(feed, trip, date)
+        });
+
+        let mut propagated = update.and_then(|u| u.delay);
+        let mut next = 0;
+        let mut stop_times = Vec::with_capacity(trip.stop_times.len());
+        for st in trip.stop_times.iter() {
+            let stop_update = stop_updates[next..]
+                .iter()
+                .position(|u| u.applies_to(st))
+                .map(|offset| {
+                    next += offset + 1;
+                    &stop_updates[next - 1]
+                });
+
+            let (arrival_delay, departure_delay, skipped) = match stop_update {
+                _ if canceled => (None, None, true),
+                Some(u) => match u.relationship {
+                    StopRelationship::Skipped => (None, None, true),
+                    StopRelationship::NoData => {
+                        propagated = None;
+                        (None, None, false)
+                    }
+                    StopRelationship::Scheduled | StopRelationship::Unscheduled => {
+                        let arrival = event_delay(u.arrival.as_ref(), st.arrival_time, day_start);
+                        let departure =
+                            event_delay(u.departure.as_ref(), st.departure_time, day_start);
+                        let arrival = arrival.or(propagated);
+                        let departure = departure.or(arrival);
+                        propagated = departure;
+                        (arrival, departure, false)
+                    }
+                },
+                None => (propagated, propagated, false),
+            };
+
+            stop_times.push(RealtimeStopTime {
+                stop_id: feed.namespaced(&st.stop.id),
+                stop_sequence: st.stop_sequence,
+                scheduled_arrival: st.arrival_time,
+                scheduled_departure: st.departure_time,
+                predicted_arrival: predict(st.arrival_time, arrival_delay),
+                predicted_departure: predict(st.departure_time, departure_delay),
+                arrival_delay,
+                departure_delay,
+                skipped,
+                schedule_relationship: stop_update.map(|u| u.relationship),
+            });
+        }
+
+        Self {
+            trip_id: feed.namespaced(&trip.id),
+            route_id: feed.namespaced(&trip.route_id),
+            realtime: update.is_some(),
+            schedule_relationship: relationship,
+            start_date: update.and_then(|u| u.start_date),
+            vehicle_id: update.and_then(|u| u.vehicle_id.clone()),
+            timestamp: update.and_then(|u| u.timestamp),
+            stop_times,
+        }
+    }
+}
+
+impl StopTimeUpdate {
+    fn applies_to(&self, st: &StopTime) -> bool {
+        match (self.stop_sequence, &self.stop_id) {
+            (Some(sequence), _) => sequence == st.stop_sequence as u32,
+            (None, Some(stop_id)) => *stop_id == st.stop.id,
+            (None, None) => false,
+        }
+    }
+}
+
+/// Delay of an event, from its absolute time when no delay is given
+fn event_delay(
+    event: Option<&StopTimeEvent>,
+    scheduled: Option<u32>,
+    day_start: Option<i64>,
+) -> Option<i32> {
+    let event = event?;
+    match (event.delay, event.time, scheduled, day_start) {
+        (Some(delay), _, _, _) => Some(delay),
+        (None, Some(time), Some(scheduled), Some(day_start)) => {
+            i32::try_from(time - day_start - scheduled as i64).ok()
+        }
+        _ => None,
+    }
+}
+
+fn predict(scheduled: Option<u32>, delay: Option<i32>) -> Option<i64> {
+    Some(scheduled? as i64 + delay? as i64)
+}
+
+/// Today in the timezone of the agency of `trip`
+pub fn service_date(feed: &Feed, trip: &Trip) -> Option<NaiveDate> {
+    let timezone = agency_timezone(feed, trip)?;
+    Some(Utc::now().with_timezone(&timezone).date_naive())
+}
+
+/// POSIX time from which the times of `trip` are counted on `date`: noon
+/// minus 12h in the timezone of its agency, which is midnight except on
+/// the days the clock changes
+fn service_day_start(feed: &Feed, trip: &Trip, date: NaiveDate) -> Option<i64> {
+    let timezone = agency_timezone(feed, trip)?;
+    let noon = timezone
+        .from_local_datetime(&date.and_hms_opt(12, 0, 0)?)
+        .single()?;
+    Some(noon.timestamp() - 12 * 3600)
+}
+
+/// Timezone of the agency of the route of `trip`, or of the first agency
+fn agency_timezone(feed: &Feed, trip: &Trip) -> Option<Tz> {
+    let gtfs = feed.get_gtfs();
+    let agency_id = gtfs
+        .routes
+        .get(&trip.route_id)
+        .and_then(|route| route.agency_id.as_deref());
+    let agency = gtfs
+        .agencies
+        .iter()
+        .find(|agency| agency_id.is_some() && agency.id.as_deref() == agency_id)
+        .or(gtfs.agencies.first())?;
+    agency.timezone.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use gtfs_structures::{Agency, Gtfs, Route, Stop};
+
+    use super::*;
+    use crate::store::{FeedConfig, FeedSource, ValidationReport};
+
+    /// Trip `T1` of route `R1` of agency `TEC` in Brussels, calling at `S1`
+    /// to `S5` every 3 minutes from 06:00
+    fn feed() -> Feed {
+        let mut gtfs = Gtfs::default();
+        gtfs.agencies.push(Agency {
+            id: Some("TEC".to_string()),
+            timezone: "Europe/Brussels".to_string(),
+            ..Default::default()
+        });
+        gtfs.routes.insert(
+            "R1".to_string(),
+            Route {
+                id: "R1".to_string(),
+                agency_id: Some("TEC".to_string()),
+                ..Default::default()
+            },
+        );
+        let stop_times = (1..=5)
+            .map(|sequence| StopTime {
+                stop: Arc::new(Stop {
+                    id: format!("S{}", sequence),
+                    ..Default::default()
+                }),
+                stop_sequence: sequence,
+                arrival_time: Some(6 * 3600 + (sequence as u32 - 1) * 180),
+                departure_time: Some(6 * 3600 + (sequence as u32 - 1) * 180 + 30),
+                ..Default::default()
+            })
+            .collect();
+        gtfs.trips.insert(
+            "T1".to_string(),
+            Trip {
+                id: "T1".to_string(),
+                route_id: "R1".to_string(),
+                stop_times,
+                ..Default::default()
+            },
+        );
+
+        let config = FeedConfig {
+            name: "tec".to_string(),
+            source: FeedSource::Directory("gtfs".into()),
+        };
+        Feed::from_parts(&config, String::new(), gtfs, ValidationReport::default())
+    }
+
+    fn update(
+        relationship: TripRelationship,
+        delay: Option<i32>,
+        stop_times: Vec<StopTimeUpdate>,
+    ) -> TripUpdate {
+        TripUpdate {
+            trip_id: "tec:T1".to_string(),
+            start_date: NaiveDate::from_ymd_opt(2026, 3, 29),
+            relationship,
+            vehicle_id: None,
+            timestamp: None,
+            delay,
+            stop_times,
+        }
+    }
+
+    fn stop_update(
+        stop_sequence: u32,
+        relationship: StopRelationship,
+        arrival: Option<StopTimeEvent>,
+    ) -> StopTimeUpdate {
+        StopTimeUpdate {
+            stop_sequence: Some(stop_sequence),
+            stop_id: None,
+            arrival,
+            departure: None,
+            relationship,
+        }
+    }
+
+    fn delayed(delay: i32) -> Option<StopTimeEvent> {
+        Some(StopTimeEvent {
+            delay: Some(delay),
+            time: None,
+        })
+    }
+
+    fn build(update: &TripUpdate) -> RealtimeTrip {
+        let feed = feed();
+        let trip = &feed.get_gtfs().trips["T1"];
+        RealtimeTrip::build(&feed, trip, Some(update))
+    }
+
+    fn delays(trip: &RealtimeTrip) -> Vec<(Option<i32>, Option<i32>, bool)> {
+        trip.stop_times
+            .iter()
+            .map(|st| (st.arrival_delay, st.departure_delay, st.skipped))
+            .collect()
+    }
+
+    #[test]
+    fn without_update() {
+        let feed = feed();
+        let trip = RealtimeTrip::build(&feed, &feed.get_gtfs().trips["T1"], None);
+        assert!(!trip.realtime);
+        assert_eq!(trip.schedule_relationship, TripRelationship::Scheduled);
+        assert_eq!(trip.stop_times.len(), 5);
+        assert_eq!(trip.stop_times[0].stop_id, "tec:S1");
+        assert!(delays(&trip).iter().all(|d| *d == (None, None, false)));
+    }
+
+    #[test]
+    fn delays_propagate_until_the_next_update() {
+        let update = update(
+            TripRelationship::Scheduled,
+            Some(30),
+            vec![
+                stop_update(2, StopRelationship::Scheduled, delayed(120)),
+                stop_update(4, StopRelationship::Scheduled, delayed(-60)),
+            ],
+        );
+        let trip = build(&update);
+        assert_eq!(
+            delays(&trip),
+            vec![
+                (Some(30), Some(30), false),
+                (Some(120), Some(120), false),
+                (Some(120), Some(120), false),
+                (Some(-60), Some(-60), false),
+                (Some(-60), Some(-60), false),
+            ]
+        );
+        assert_eq!(trip.stop_times[1].predicted_arrival, Some(6 * 3600 + 300));
+        assert_eq!(trip.stop_times[1].predicted_departure, Some(6 * 3600 + 330));
+        assert_eq!(trip.stop_times[2].schedule_relationship, None);
+    }
+
+    #[test]
+    fn no_data_stops_the_propagation() {
+        let update = update(
+            TripRelationship::Scheduled,
+            None,
+            vec![
+                stop_update(1, StopRelationship::Scheduled, delayed(60)),
+                stop_update(3, StopRelationship::NoData, None),
+                stop_update(5, StopRelationship::Scheduled, delayed(90)),
+            ],
+        );
+        assert_eq!(
+            delays(&build(&update)),
+            vec![
+                (Some(60), Some(60), false),
+                (Some(60), Some(60), false),
+                (None, None, false),
+                (None, None, false),
+                (Some(90), Some(90), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn skipped_stops_keep_the_propagation() {
+        let update = update(
+            TripRelationship::Scheduled,
+            None,
+            vec![
+                stop_update(2, StopRelationship::Scheduled, delayed(60)),
+                stop_update(3, StopRelationship::Skipped, None),
+            ],
+        );
+        assert_eq!(
+            delays(&build(&update)),
+            vec![
+                (None, None, false),
+                (Some(60), Some(60), false),
+                (None, None, true),
+                (Some(60), Some(60), false),
+                (Some(60), Some(60), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn canceled_trips_skip_every_stop() {
+        for relationship in [TripRelationship::Canceled, TripRelationship::Deleted] {
+            let update = update(
+                relationship,
+                Some(60),
+                vec![stop_update(2, StopRelationship::Scheduled, delayed(60))],
+            );
+            let trip = build(&update);
+            assert_eq!(trip.schedule_relationship, relationship);
+            assert!(delays(&trip).iter().all(|d| *d == (None, None, true)));
+        }
+    }
+
+    #[test]
+    fn updates_match_by_stop_id_without_sequence() {
+        let mut stop_update = stop_update(0, StopRelationship::Scheduled, delayed(45));
+        stop_update.stop_sequence = None;
+        stop_update.stop_id = Some("S4".to_string());
+        let update = update(TripRelationship::Scheduled, None, vec![stop_update]);
+        assert_eq!(
+            delays(&build(&update))[2..],
+            [
+                (None, None, false),
+                (Some(45), Some(45), false),
+                (Some(45), Some(45), false)
+            ]
+        );
+    }
+
+    #[test]
+    fn absolute_times_on_a_dst_day() {
+        let feed = feed();
+        let trip = &feed.get_gtfs().trips["T1"];
+
+        // Clocks go forward at 02:00 on 29 March 2026: the service day
+        // starts at noon CEST minus 12h, 23:00 CET the day before
+        let date = NaiveDate::from_ymd_opt(2026, 3, 29).unwrap();
+        let noon = Utc
+            .with_ymd_and_hms(2026, 3, 29, 10, 0, 0)
+            .unwrap()
+            .timestamp();
+        let day_start = service_day_start(&feed, trip, date).unwrap();
+        assert_eq!(day_start, noon - 12 * 3600);
+        let midnight = Utc
+            .with_ymd_and_hms(2026, 3, 28, 23, 0, 0)
+            .unwrap()
+            .timestamp();
+        assert_eq!(midnight - day_start, 3600);
+
+        // And back at 03:00 on 25 October
+        let date = NaiveDate::from_ymd_opt(2026, 10, 25).unwrap();
+        let noon = Utc
+            .with_ymd_and_hms(2026, 10, 25, 11, 0, 0)
+            .unwrap()
+            .timestamp();
+        assert_eq!(service_day_start(&feed, trip, date), Some(noon - 12 * 3600));
+
+        // Arriving at 06:02 CEST at the second stop, scheduled at 06:03:
+        // counting from midnight would make it 59 minutes late
+        let arrival = Utc
+            .with_ymd_and_hms(2026, 3, 29, 4, 2, 0)
+            .unwrap()
+            .timestamp();
+        let update = update(
+            TripRelationship::Scheduled,
+            None,
+            vec![stop_update(
+                2,
+                StopRelationship::Scheduled,
+                Some(StopTimeEvent {
+                    delay: None,
+                    time: Some(arrival),
+                }),
+            )],
+        );
+        let trip = build(&update);
+        assert_eq!(trip.stop_times[1].arrival_delay, Some(-60));
+        assert_eq!(trip.stop_times[2].arrival_delay, Some(-60));
+    }
+
+    #[test]
+    fn updates_are_kept_per_start_date() {
+        let mut updates = TripUpdates::default();
+        let today = update(TripRelationship::Scheduled, Some(60), Vec::new());
+        let mut tomorrow = update(TripRelationship::Canceled, None, Vec::new());
+        tomorrow.start_date = NaiveDate::from_ymd_opt(2026, 3, 30);
+        let mut undated = update(TripRelationship::Scheduled, Some(10), Vec::new());
+        undated.start_date = None;
+        updates.apply("rt", true, vec![today, tomorrow, undated], Vec::new());
+
+        let get = |updates: &TripUpdates, day| {
+            updates
+                .get("tec:T1", NaiveDate::from_ymd_opt(2026, 3, day))
+                .map(|u| u.relationship)
+        };
+        assert_eq!(get(&updates, 29), Some(TripRelationship::Scheduled));
+        assert_eq!(get(&updates, 30), Some(TripRelationship::Canceled));
+        assert_eq!(get(&updates, 31), None);
+        assert_eq!(updates.get("tec:T1", None).and_then(|u| u.delay), Some(10));
+
+        updates.apply(
+            "rt",
+            false,
+            Vec::new(),
+            vec![("tec:T1".to_string(), NaiveDate::from_ymd_opt(2026, 3, 30))],
+        );
+        assert_eq!(get(&updates, 29), Some(TripRelationship::Scheduled));
+        assert_eq!(get(&updates, 30), None);
+
+        // A full dataset only replaces the updates of its own source
+        updates.apply("other", true, Vec::new(), Vec::new());
+        assert!(get(&updates, 29).is_some());
+        updates.apply("rt", true, Vec::new(), Vec::new());
+        assert!(get(&updates, 29).is_none());
+    }
+}
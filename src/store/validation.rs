@@ -0,0 +1,597 @@
+use std::collections::BTreeMap;
+
+use ahash::{AHashMap, AHashSet};
+use gtfs_structures::RawGtfs;
+use serde::{Deserialize, Serialize};
+
+use crate::{logger, quadtree::Coordinate};
+
+/// Issues kept per kind, the counts still cover every occurrence
+const MAX_ISSUES_PER_KIND: usize = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    /// Data is dropped or the feed cannot be loaded
+    Error,
+    /// Data is loaded but some features will not work for it
+    Warning,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IssueKind {
+    /// Id defined more than once, the last row wins
+    DuplicateId,
+    /// `stop_times` row pointing to a trip that does not exist
+    UnknownTrip,
+    /// `stop_times` row pointing to a stop that does not exist
+    UnknownStop,
+    /// Trip pointing to a route that does not exist
+    UnknownRoute,
+    /// Trip whose service is in neither `calendar` nor `calendar_dates`
+    UnknownService,
+    /// Stop without coordinates, absent from the stop index
+    MissingCoordinates,
+    /// Stop whose coordinates are not a location (NaN, out of range, 0/0),
+    /// absent from the stop index
+    InvalidCoordinates,
+    /// Stop pointing to a parent station that does not exist
+    UnknownParentStation,
+    /// Trip without `shape_id`
+    MissingShape,
+    /// Trip pointing to a shape that does not exist
+    UnknownShape,
+    /// Trip that no `stop_times` row belongs to
+    TripWithoutStopTimes,
+}
+
+impl IssueKind {
+    /// Same name as in the JSON report
+    pub fn as_str(self) -> &'static str {
+        match self {
+            IssueKind::DuplicateId => "duplicate_id",
+            IssueKind::UnknownTrip => "unknown_trip",
+            IssueKind::UnknownStop => "unknown_stop",
+            IssueKind::UnknownRoute => "unknown_route",
+            IssueKind::UnknownService => "unknown_service",
+            IssueKind::MissingCoordinates => "missing_coordinates",
+            IssueKind::InvalidCoordinates => "invalid_coordinates",
+            IssueKind::UnknownParentStation => "unknown_parent_station",
+            IssueKind::MissingShape => "missing_shape",
+            IssueKind::UnknownShape => "unknown_shape",
+            IssueKind::TripWithoutStopTimes => "trip_without_stop_times",
+        }
+    }
+
+    pub fn severity(self) -> Severity {
+        match self {
+            IssueKind::DuplicateId
+            | IssueKind::UnknownTrip
+            | IssueKind::UnknownStop
+            | IssueKind::UnknownRoute
+            | IssueKind::UnknownService => Severity::Error,
+            IssueKind::MissingCoordinates
+            | IssueKind::InvalidCoordinates
+            | IssueKind::UnknownParentStation
+            | IssueKind::MissingShape
+            | IssueKind::UnknownShape
+            | IssueKind::TripWithoutStopTimes => Severity::Warning,
+        }
+    }
+}
+
+/// One problem found in a feed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Issue {
+    pub kind: IssueKind,
+    pub file: String,
+    /// Position of the record in the file, the first one after the header
+    /// being row 1. Not a line number, quoted fields may span several lines
+    pub row: u64,
+    /// Id of the entity the row defines (stop, trip, ...)
+    pub entity_id: Option<String>,
+    pub message: String,
+}
+
+/// Problems found in a feed when it was loaded
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ValidationReport {
+    pub errors: Vec<Issue>,
+    pub warnings: Vec<Issue>,
+    /// Number of issues of each kind, including the ones not listed
+    pub counts: BTreeMap<IssueKind, usize>,
+}
+
+impl ValidationReport {
+    /// Check the references between the files of a feed. Files that could
+    /// not be read are skipped, the load error already reports them.
+    pub fn build(raw: &RawGtfs) -> Self {
+        let mut report = Self::default();
+
+        let mut stops = AHashSet::new();
+        if let Ok(raw_stops) = &raw.stops {
+            for (i, stop) in raw_stops.iter().enumerate() {
+                if !stops.insert(stop.id.as_str()) {
+                    report.push(
+                        IssueKind::DuplicateId,
+                        "stops.txt",
+                        i,
+                        &stop.id,
+                        format!("stop_id {} is defined twice", stop.id),
+                    );
+                }
+                match (stop.latitude, stop.longitude) {
+                    (Some(lat), Some(lon)) => {
+                        if !Coordinate::new(lon, lat).is_valid() {
+                            report.push(
+                                IssueKind::InvalidCoordinates,
+                                "stops.txt",
+                                i,
+                                &stop.id,
+                                format!(
+                                    "Stop {} has invalid coordinates {}, {}",
+                                    stop.id, lat, lon
+                                ),
+                            );
+                        }
+                    }
+                    _ => report.push(
+                        IssueKind::MissingCoordinates,
+                        "stops.txt",
+                        i,
+                        &stop.id,
+                        format!("Stop {} has no coordinates", stop.id),
+                    ),
+                }
+            }
+
+            for (i, stop) in raw_stops.iter().enumerate() {
+                if let Some(parent) = &stop.parent_station {
+                    if !parent.is_empty() && !stops.contains(parent.as_str()) {
+                        report.push(
+                            IssueKind::UnknownParentStation,
+                            "stops.txt",
+                            i,
+                            &stop.id,
+                            format!("Unknown parent_station {}", parent),
+                        );
+                    }
+                }
+            }
+        }
+
+        let mut routes = AHashSet::new();
+        if let Ok(raw_routes) = &raw.routes {
+            for (i, route) in raw_routes.iter().enumerate() {
+                if !routes.insert(route.id.as_str()) {
+                    report.push(
+                        IssueKind::DuplicateId,
+                        "routes.txt",
+                        i,
+                        &route.id,
+                        format!("route_id {} is defined twice", route.id),
+                    );
+                }
+            }
+        }
+
+        // A service can only be called unknown if no calendar failed to load
+        let services_read =
+            !matches!(raw.calendar, Some(Err(_))) && !matches!(raw.calendar_dates, Some(Err(_)));
+        let mut services = AHashSet::new();
+        if let Some(Ok(calendar)) = &raw.calendar {
+            services.extend(calendar.iter().map(|c| c.id.as_str()));
+        }
+        if let Some(Ok(calendar_dates)) = &raw.calendar_dates {
+            services.extend(calendar_dates.iter().map(|c| c.service_id.as_str()));
+        }
+
+        let shapes: Option<AHashSet<&str>> = match &raw.shapes {
+            Some(Ok(shapes)) => Some(shapes.iter().map(|s| s.id.as_str()).collect()),
+            _ => None,
+        };
+
+        // Row of each trip, to report trips left without stop times
+        let mut trips = AHashMap::new();
+        if let Ok(raw_trips) = &raw.trips {
+            for (i, trip) in raw_trips.iter().enumerate() {
+                if trips.insert(trip.id.as_str(), i).is_some() {
+                    report.push(
+                        IssueKind::DuplicateId,
+                        "trips.txt",
+                        i,
+                        &trip.id,
+                        format!("trip_id {} is defined twice", trip.id),
+                    );
+                }
+                if raw.routes.is_ok() && !routes.contains(trip.route_id.as_str()) {
+                    report.push(
+                        IssueKind::UnknownRoute,
+                        "trips.txt",
+                        i,
+                        &trip.id,
+                        format!("Unknown route_id {}", trip.route_id),
+                    );
+                }
+                if services_read && !services.contains(trip.service_id.as_str()) {
+                    report.push(
+                        IssueKind::UnknownService,
+                        "trips.txt",
+                        i,
+                        &trip.id,
+                        format!("Unknown service_id {}", trip.service_id),
+                    );
+                }
+                match (&trip.shape_id, &shapes) {
+                    (None, _) => report.push(
+                        IssueKind::MissingShape,
+                        "trips.txt",
+                        i,
+                        &trip.id,
+                        format!("Trip {} has no shape_id", trip.id),
+                    ),
+                    (Some(shape_id), Some(shapes)) if !shapes.contains(shape_id.as_str()) => report
+                        .push(
+                            IssueKind::UnknownShape,
+                            "trips.txt",
+                            i,
+                            &trip.id,
+                            format!("Unknown shape_id {}", shape_id),
+                        ),
+                    _ => {}
+                }
+            }
+        }
+
+        if let Ok(stop_times) = &raw.stop_times {
+            let mut served = AHashSet::new();
+            for (i, stop_time) in stop_times.iter().enumerate() {
+                if raw.trips.is_ok() {
+                    if trips.contains_key(stop_time.trip_id.as_str()) {
+                        served.insert(stop_time.trip_id.as_str());
+                    } else {
+                        report.push(
+                            IssueKind::UnknownTrip,
+                            "stop_times.txt",
+                            i,
+                            &stop_time.trip_id,
+                            format!("Unknown trip_id {}", stop_time.trip_id),
+                        );
+                    }
+                }
+                if raw.stops.is_ok() && !stops.contains(stop_time.stop_id.as_str()) {
+                    report.push(
+                        IssueKind::UnknownStop,
+                        "stop_times.txt",
+                        i,
+                        &stop_time.trip_id,
+                        format!("Unknown stop_id {}", stop_time.stop_id),
+                    );
+                }
+            }
+
+            let mut unserved: Vec<(&str, usize)> = trips
+                .into_iter()
+                .filter(|(id, _)| !served.contains(id))
+                .collect();
+            unserved.sort_by_key(|(_, i)| *i);
+            for (id, i) in unserved {
+                report.push(
+                    IssueKind::TripWithoutStopTimes,
+                    "trips.txt",
+                    i,
+                    id,
+                    format!("Trip {} has no stop_times", id),
+                );
+            }
+        }
+
+        report
+    }
+
+    /// `index` is the position of the record in the file, header excluded
+    fn push(&mut self, kind: IssueKind, file: &str, index: usize, id: &str, message: String) {
+        let count = self.counts.entry(kind).or_default();
+        *count += 1;
+        if *count > MAX_ISSUES_PER_KIND {
+            return;
+        }
+
+        let issue = Issue {
+            kind,
+            file: file.to_string(),
+            row: index as u64 + 1,
+            entity_id: Some(id.to_string()),
+            message,
+        };
+        match kind.severity() {
+            Severity::Error => self.errors.push(issue),
+            Severity::Warning => self.warnings.push(issue),
+        }
+    }
+
+    /// Number of errors, including the ones not listed
+    pub fn error_count(&self) -> usize {
+        self.count(Severity::Error)
+    }
+
+    /// Number of warnings, including the ones not listed
+    pub fn warning_count(&self) -> usize {
+        self.count(Severity::Warning)
+    }
+
+    fn count(&self, severity: Severity) -> usize {
+        self.counts
+            .iter()
+            .filter(|(kind, _)| kind.severity() == severity)
+            .map(|(_, count)| count)
+            .sum()
+    }
+
+    /// One line summary in the logs, e.g.
+    /// `Validated GTFS tec: 0 errors, 12 warnings (missing_shape: 12)`
+    pub fn log(&self, feed: &str) {
+        let details: Vec<String> = self
+            .counts
+            .iter()
+            .map(|(kind, count)| format!("{}: {}", kind.as_str(), count))
+            .collect();
+
+        let mut message = format!(
+            "Validated GTFS {}: {} errors, {} warnings",
+            feed,
+            self.error_count(),
+            self.warning_count()
+        );
+        if !details.is_empty() {
+            message.push_str(&format!(" ({})", details.join(", ")));
+        }
+
+        if self.error_count() > 0 {
+            logger::warn("VALIDATOR", &message);
+        } else {
+            logger::fine("VALIDATOR", &message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+    use gtfs_structures::{
+        CalendarDate, Error, Exception, RawStopTime, RawTrip, Route, SourceFormat, Stop,
+    };
+
+    fn stop(id: &str, latitude: Option<f64>, longitude: Option<f64>) -> Stop {
+        Stop {
+            id: id.to_string(),
+            latitude,
+            longitude,
+            ..Default::default()
+        }
+    }
+
+    fn trip(id: &str, route_id: &str, service_id: &str) -> RawTrip {
+        RawTrip {
+            id: id.to_string(),
+            route_id: route_id.to_string(),
+            service_id: service_id.to_string(),
+            shape_id: Some("SH1".to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn stop_time(trip_id: &str, stop_id: &str) -> RawStopTime {
+        RawStopTime {
+            trip_id: trip_id.to_string(),
+            stop_id: stop_id.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Route `R1` with trip `T1` on service `WEEK` calling at `S1` and `S2`
+    fn raw() -> RawGtfs {
+        RawGtfs {
+            read_duration: 0,
+            calendar: None,
+            calendar_dates: Some(Ok(vec![CalendarDate {
+                service_id: "WEEK".to_string(),
+                date: NaiveDate::from_ymd_opt(2024, 1, 1).unwrap(),
+                exception_type: Exception::Added,
+            }])),
+            stops: Ok(vec![
+                stop("S1", Some(50.4), Some(4.4)),
+                stop("S2", Some(50.5), Some(4.5)),
+            ]),
+            routes: Ok(vec![Route {
+                id: "R1".to_string(),
+                ..Default::default()
+            }]),
+            trips: Ok(vec![trip("T1", "R1", "WEEK")]),
+            agencies: Ok(Vec::new()),
+            shapes: None,
+            fare_attributes: None,
+            frequencies: None,
+            transfers: None,
+            pathways: None,
+            feed_info: None,
+            stop_times: Ok(vec![stop_time("T1", "S1"), stop_time("T1", "S2")]),
+            files: Vec::new(),
+            source_format: SourceFormat::Directory,
+            sha256: None,
+        }
+    }
+
+    fn kinds(report: &ValidationReport) -> Vec<(IssueKind, &str, u64, &str)> {
+        report
+            .errors
+            .iter()
+            .chain(&report.warnings)
+            .map(|issue| {
+                (
+                    issue.kind,
+                    issue.file.as_str(),
+                    issue.row,
+                    issue.entity_id.as_deref().unwrap_or_default(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn valid_feed() {
+        let report = ValidationReport::build(&raw());
+        assert!(report.errors.is_empty());
+        assert!(report.warnings.is_empty());
+        assert!(report.counts.is_empty());
+    }
+
+    #[test]
+    fn duplicates() {
+        let mut raw = raw();
+        raw.stops
+            .as_mut()
+            .unwrap()
+            .push(stop("S1", Some(50.4), Some(4.4)));
+        raw.routes.as_mut().unwrap().push(Route {
+            id: "R1".to_string(),
+            ..Default::default()
+        });
+        raw.trips.as_mut().unwrap().push(trip("T1", "R1", "WEEK"));
+
+        let report = ValidationReport::build(&raw);
+        assert_eq!(
+            kinds(&report),
+            vec![
+                (IssueKind::DuplicateId, "stops.txt", 3, "S1"),
+                (IssueKind::DuplicateId, "routes.txt", 2, "R1"),
+                (IssueKind::DuplicateId, "trips.txt", 2, "T1"),
+            ]
+        );
+        assert_eq!(report.error_count(), 3);
+    }
+
+    #[test]
+    fn dangling_references() {
+        let mut raw = raw();
+        raw.trips
+            .as_mut()
+            .unwrap()
+            .push(trip("T2", "R9", "HOLIDAY"));
+        let stop_times = raw.stop_times.as_mut().unwrap();
+        stop_times.push(stop_time("T2", "S9"));
+        stop_times.push(stop_time("T9", "S1"));
+        raw.stops.as_mut().unwrap()[1].parent_station = Some("P9".to_string());
+
+        let report = ValidationReport::build(&raw);
+        assert_eq!(
+            kinds(&report),
+            vec![
+                (IssueKind::UnknownRoute, "trips.txt", 2, "T2"),
+                (IssueKind::UnknownService, "trips.txt", 2, "T2"),
+                (IssueKind::UnknownStop, "stop_times.txt", 3, "T2"),
+                (IssueKind::UnknownTrip, "stop_times.txt", 4, "T9"),
+                (IssueKind::UnknownParentStation, "stops.txt", 2, "S2"),
+            ]
+        );
+    }
+
+    #[test]
+    fn unreadable_files_are_skipped() {
+        let mut raw = raw();
+        raw.trips
+            .as_mut()
+            .unwrap()
+            .push(trip("T2", "R9", "HOLIDAY"));
+        raw.stop_times.as_mut().unwrap().push(stop_time("T2", "S9"));
+        raw.routes = Err(Error::MissingFile("routes.txt".to_string()));
+        raw.stops = Err(Error::MissingFile("stops.txt".to_string()));
+        raw.calendar_dates = Some(Err(Error::MissingFile("calendar_dates.txt".to_string())));
+
+        let report = ValidationReport::build(&raw);
+        assert!(kinds(&report).is_empty());
+    }
+
+    #[test]
+    fn unknown_service_without_any_calendar() {
+        let mut raw = raw();
+        raw.calendar_dates = None;
+
+        let report = ValidationReport::build(&raw);
+        assert_eq!(
+            kinds(&report),
+            vec![(IssueKind::UnknownService, "trips.txt", 1, "T1")]
+        );
+    }
+
+    #[test]
+    fn missing_and_invalid_coordinates() {
+        let mut raw = raw();
+        let stops = raw.stops.as_mut().unwrap();
+        stops.push(stop("S3", None, Some(4.4)));
+        stops.push(stop("S4", Some(0.0), Some(0.0)));
+        stops.push(stop("S5", Some(f64::NAN), Some(4.4)));
+        stops.push(stop("S6", Some(91.0), Some(4.4)));
+
+        let report = ValidationReport::build(&raw);
+        assert_eq!(
+            kinds(&report),
+            vec![
+                (IssueKind::MissingCoordinates, "stops.txt", 3, "S3"),
+                (IssueKind::InvalidCoordinates, "stops.txt", 4, "S4"),
+                (IssueKind::InvalidCoordinates, "stops.txt", 5, "S5"),
+                (IssueKind::InvalidCoordinates, "stops.txt", 6, "S6"),
+            ]
+        );
+        assert_eq!(report.error_count(), 0);
+        assert_eq!(report.warning_count(), 4);
+    }
+
+    #[test]
+    fn shapes_and_trips_without_stop_times() {
+        let mut raw = raw();
+        let trips = raw.trips.as_mut().unwrap();
+        trips.push(trip("T2", "R1", "WEEK"));
+        trips[0].shape_id = None;
+        raw.shapes = Some(Ok(Vec::new()));
+
+        let report = ValidationReport::build(&raw);
+        assert_eq!(
+            kinds(&report),
+            vec![
+                (IssueKind::MissingShape, "trips.txt", 1, "T1"),
+                (IssueKind::UnknownShape, "trips.txt", 2, "T2"),
+                (IssueKind::TripWithoutStopTimes, "trips.txt", 2, "T2"),
+            ]
+        );
+    }
+
+    #[test]
+    fn issues_are_capped_per_kind() {
+        let mut raw = raw();
+        let stops = raw.stops.as_mut().unwrap();
+        for n in 0..MAX_ISSUES_PER_KIND + 20 {
+            stops.push(stop(&format!("M{}", n), None, None));
+        }
+        stops.push(stop("S1", Some(50.4), Some(4.4)));
+
+        let report = ValidationReport::build(&raw);
+        assert_eq!(report.warnings.len(), MAX_ISSUES_PER_KIND);
+        assert_eq!(
+            report.warnings.last().unwrap().entity_id.as_deref(),
+            Some("M499")
+        );
+        assert_eq!(report.warning_count(), MAX_ISSUES_PER_KIND + 20);
+        assert_eq!(
+            report.counts[&IssueKind::MissingCoordinates],
+            MAX_ISSUES_PER_KIND + 20
+        );
+        // Other kinds have a cap of their own
+        assert_eq!(
+            kinds(&report)[0],
+            (IssueKind::DuplicateId, "stops.txt", 523, "S1")
+        );
+    }
+}
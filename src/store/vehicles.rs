@@ -0,0 +1,316 @@
+use std::{
+    env,
+    time::{Duration, Instant},
+};
+
+use ahash::AHashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    logger,
+    quadtree::{Coordinate, Extent, QuadTree},
+    spatial::SpatialIndex,
+};
+
+const DEFAULT_BUS_TTL: u64 = 300;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Bus {
+    id: String,
+    line: String,
+    line_id: String,
+    /// Absent from positions pushed by the fetcher
+    #[serde(default)]
+    trip_id: Option<String>,
+    latitude: f32,
+    longitude: f32,
+    speed: f32,
+    last_update: u64,
+}
+
+impl Bus {
+    pub fn new(
+        id: String,
+        line: String,
+        line_id: String,
+        latitude: f32,
+        longitude: f32,
+        speed: f32,
+        last_update: u64,
+    ) -> Self {
+        Self {
+            id,
+            line,
+            line_id,
+            trip_id: None,
+            latitude,
+            longitude,
+            speed,
+            last_update,
+        }
+    }
+
+    pub fn with_trip_id(mut self, trip_id: Option<String>) -> Self {
+        self.trip_id = trip_id;
+        self
+    }
+
+    pub fn with_line_id(mut self, line_id: String) -> Self {
+        self.line_id = line_id;
+        self
+    }
+
+    pub fn get_id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn get_line(&self) -> &str {
+        &self.line
+    }
+
+    pub fn get_line_id(&self) -> &str {
+        &self.line_id
+    }
+
+    pub fn get_trip_id(&self) -> Option<&str> {
+        self.trip_id.as_deref()
+    }
+
+    pub fn get_last_update(&self) -> u64 {
+        self.last_update
+    }
+
+    pub fn get_coordinate(&self) -> Coordinate {
+        Coordinate::new(self.longitude as f64, self.latitude as f64)
+    }
+}
+
+/// Last known position of every bus reported by the fetcher, by id
+pub struct Vehicles {
+    buses: AHashMap<String, (Bus, Instant)>,
+    tree: QuadTree<String>,
+    /// Buses not reported for this long are dropped
+    ttl: Duration,
+}
+
+/// Change of the registry, pushed to the live subscribers
+#[derive(Debug, Clone)]
+pub enum BusDelta {
+    /// New bus or new position
+    Moved(Bus),
+    /// Expired, with its last known position
+    Removed(Bus),
+}
+
+/// Outcome of an ingested batch
+#[derive(Debug, Default, Serialize)]
+pub struct IngestSummary {
+    pub updated: usize,
+    /// Older than the position already known
+    pub outdated: usize,
+    /// Invalid coordinates
+    pub rejected: usize,
+    /// Dropped for not being reported within the TTL
+    pub expired: usize,
+}
+
+impl Vehicles {
+    /// Registry dropping buses after `GTFS_BUS_TTL` seconds without update
+    pub fn from_env() -> Self {
+        let ttl = match env::var("GTFS_BUS_TTL") {
+            Ok(raw) => match raw.trim().parse::<u64>() {
+                Ok(ttl) if ttl > 0 => ttl,
+                _ => {
+                    logger::warn(
+                        "VEHICLES",
+                        &format!(
+                            "Invalid GTFS_BUS_TTL {:?}, keeping buses {} seconds",
+                            raw, DEFAULT_BUS_TTL
+                        ),
+                    );
+                    DEFAULT_BUS_TTL
+                }
+            },
+            Err(_) => DEFAULT_BUS_TTL,
+        };
+
+        Self {
+            buses: AHashMap::new(),
+            tree: QuadTree::new(Extent::new(0.0, 0.0, 0.0, 0.0)),
+            ttl: Duration::from_secs(ttl),
+        }
+    }
+
+    /// Insert or move every bus of the batch, returning what changed. A bus
+    /// whose `last_update` is older than the known one is ignored.
+    pub fn ingest(&mut self, buses: Vec<Bus>) -> (IngestSummary, Vec<BusDelta>) {
+        let now = Instant::now();
+        let mut deltas = self.expire_at(now);
+        let mut summary = IngestSummary {
+            expired: deltas.len(),
+            ..Default::default()
+        };
+
+        for bus in buses {
+            let coord = bus.get_coordinate();
+            if !coord.is_valid() {
+                summary.rejected += 1;
+                continue;
+            }
+
+            match self.buses.get_mut(&bus.id) {
+                Some((known, _)) if known.last_update > bus.last_update => {
+                    summary.outdated += 1;
+                }
+                Some((known, received)) => {
+                    self.tree
+                        .update(&known.get_coordinate(), &coord, bus.id.clone());
+                    deltas.push(BusDelta::Moved(bus.clone()));
+                    *known = bus;
+                    *received = now;
+                    summary.updated += 1;
+                }
+                None => {
+                    self.tree.insert(&coord, bus.id.clone());
+                    deltas.push(BusDelta::Moved(bus.clone()));
+                    self.buses.insert(bus.id.clone(), (bus, now));
+                    summary.updated += 1;
+                }
+            }
+        }
+
+        (summary, deltas)
+    }
+
+    /// Drop the buses not reported within the TTL, returning their removal
+    pub fn expire(&mut self) -> Vec<BusDelta> {
+        self.expire_at(Instant::now())
+    }
+
+    fn expire_at(&mut self, now: Instant) -> Vec<BusDelta> {
+        let expired: Vec<String> = self
+            .buses
+            .iter()
+            .filter(|(_, (_, received))| now.duration_since(*received) > self.ttl)
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        let mut removed = Vec::with_capacity(expired.len());
+        for id in expired.iter() {
+            if let Some((bus, _)) = self.buses.remove(id) {
+                self.tree.remove(&bus.get_coordinate(), |v| v == id);
+                removed.push(BusDelta::Removed(bus));
+            }
+        }
+        removed
+    }
+
+    pub fn get_ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Reported within the TTL and of `line_id` when given
+    fn is_listed(&self, bus: &Bus, received: &Instant, line_id: Option<&str>) -> bool {
+        received.elapsed() <= self.ttl
+            && match line_id {
+                Some(line_id) => bus.line_id == line_id,
+                None => true,
+            }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buses.is_empty()
+    }
+
+    /// Buses reported within the TTL, of `line_id` only when given, ordered
+    /// by id
+    pub fn get_buses(&self, line_id: Option<&str>) -> Vec<Bus> {
+        let mut buses: Vec<Bus> = self
+            .buses
+            .values()
+            .filter(|(bus, received)| self.is_listed(bus, received, line_id))
+            .map(|(bus, _)| bus.clone())
+            .collect();
+        buses.sort_by(|a, b| a.id.cmp(&b.id));
+        buses
+    }
+
+    /// Buses reported within the TTL inside `extent`, ordered by id
+    pub fn find_bbox(&self, extent: &Extent, line_id: Option<&str>) -> Vec<Bus> {
+        let mut buses: Vec<Bus> = self
+            .tree
+            .find_bbox(extent)
+            .iter()
+            .filter_map(|(id, _)| self.buses.get(id))
+            .filter(|(bus, received)| self.is_listed(bus, received, line_id))
+            .map(|(bus, _)| bus.clone())
+            .collect();
+        buses.sort_by(|a, b| a.id.cmp(&b.id));
+        buses
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vehicles(ttl: Duration) -> Vehicles {
+        Vehicles {
+            buses: AHashMap::new(),
+            tree: QuadTree::new(Extent::new(0.0, 0.0, 0.0, 0.0)),
+            ttl,
+        }
+    }
+
+    fn bus(id: &str, last_update: u64) -> Bus {
+        Bus::new(
+            id.to_string(),
+            "1".to_string(),
+            "tec:R1".to_string(),
+            50.4,
+            4.4,
+            0.0,
+            last_update,
+        )
+    }
+
+    #[test]
+    fn expire_removes_stale_buses_without_a_batch() {
+        let mut vehicles = vehicles(Duration::from_millis(10));
+        vehicles.ingest(vec![bus("A", 1), bus("B", 1)]);
+        assert!(vehicles.expire().is_empty());
+
+        std::thread::sleep(Duration::from_millis(20));
+        let mut removed: Vec<String> = vehicles
+            .expire()
+            .into_iter()
+            .map(|delta| match delta {
+                BusDelta::Removed(bus) => bus.id,
+                BusDelta::Moved(bus) => panic!("{} moved", bus.id),
+            })
+            .collect();
+        removed.sort();
+        assert_eq!(removed, vec!["A", "B"]);
+        assert!(vehicles.is_empty());
+        assert!(vehicles
+            .find_bbox(&Extent::new(0.0, 0.0, 10.0, 60.0), None)
+            .is_empty());
+        assert!(vehicles.expire().is_empty());
+    }
+
+    #[test]
+    fn ingest_keeps_the_newest_position() {
+        let mut vehicles = vehicles(Duration::from_secs(60));
+        let (summary, deltas) = vehicles.ingest(vec![bus("A", 2)]);
+        assert_eq!((summary.updated, deltas.len()), (1, 1));
+
+        let (summary, deltas) = vehicles.ingest(vec![bus("A", 1)]);
+        assert_eq!((summary.outdated, deltas.len()), (1, 0));
+        assert_eq!(vehicles.get_buses(None)[0].get_last_update(), 2);
+        assert_eq!(vehicles.get_buses(Some("tec:R2")), vec![]);
+    }
+}